@@ -0,0 +1,153 @@
+//! Measures how quickly an update reaches a large number of websocket
+//! sessions, and how expensive serving the current tasks is.
+//!
+//! Run with `cargo bench --bench fanout`.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use task_hookrs::task::Task;
+use task_streamer::encoding::Encoding;
+use task_streamer::filter::TaskFilter;
+use task_streamer::schema::UrgencyLevels;
+use task_streamer::session::{
+    Connect, Evict, Message, SessionManager, TasksUpdated, View, TASKS_UPDATED,
+};
+use task_streamer::snapshot::Snapshot;
+
+const SESSIONS: usize = 10_000;
+const UPDATES: usize = 100;
+const READS: usize = 10_000;
+
+/// Stands in for a `TaskSession`, counting what it would have written.
+struct Sink {
+    delivered: Arc<AtomicUsize>,
+}
+
+impl Actor for Sink {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for Sink {
+    type Result = ();
+
+    fn handle(&mut self, _: Message, _: &mut Context<Self>) {
+        self.delivered.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Handler<Evict> for Sink {
+    type Result = ();
+
+    fn handle(&mut self, _: Evict, ctx: &mut Context<Self>) {
+        ctx.stop();
+    }
+}
+
+fn tasks(count: usize) -> Vec<Task> {
+    let task = r#"{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20201118T071926Z","description":"figure out frontend static asset storage/serving","tags":["@stream","@home"],"id":13,"modified":"20201118T071926Z","project":"twitch.task-display","urgency":2.15205}"#;
+    let task: Task = serde_json::from_str(task).unwrap();
+    vec![task; count]
+}
+
+fn report(name: &str, count: usize, unit: &str, elapsed: Duration) {
+    println!(
+        "{:<40} {:>12.0} {}/s ({:?} total)",
+        name,
+        count as f64 / elapsed.as_secs_f64(),
+        unit,
+        elapsed
+    );
+}
+
+async fn fanout(tasks: Vec<Task>) {
+    let manager = SessionManager::new().start();
+    let delivered = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..SESSIONS {
+        let addr = Sink {
+            delivered: delivered.clone(),
+        }
+        .start();
+        manager
+            .send(Connect {
+                addr: addr.clone().recipient(),
+                evict: addr.recipient(),
+                view: View::Public,
+                filter: TaskFilter::default(),
+                encoding: Encoding::Json,
+                legacy: false,
+            })
+            .await
+            .unwrap();
+    }
+
+    let start = Instant::now();
+
+    for update in 1..=UPDATES {
+        // unchanged tasks aren't sent again
+        let mut tasks = tasks.clone();
+        *tasks[0].description_mut() = format!("update {}", update);
+
+        let snapshot = Arc::new(Snapshot::new(TASKS_UPDATED, update as u64, tasks).unwrap());
+        manager.do_send(TasksUpdated {
+            private: snapshot.clone(),
+            public: snapshot,
+            urgency_levels: UrgencyLevels::default(),
+        });
+
+        // wait for every session to see this update before sending the next,
+        // so none of them lag and get evicted
+        while delivered.load(Ordering::Relaxed) < update * SESSIONS {
+            actix::clock::delay_for(Duration::from_micros(100)).await;
+        }
+    }
+
+    report(
+        &format!("fan-out, {} sessions, {} tasks", SESSIONS, tasks.len()),
+        UPDATES * SESSIONS,
+        "deliveries",
+        start.elapsed(),
+    );
+}
+
+fn reads(tasks: Vec<Task>) {
+    let start = Instant::now();
+    for _ in 0..READS {
+        let body = serde_json::to_vec(&tasks).unwrap();
+        assert!(!body.is_empty());
+    }
+    report(
+        &format!("serialize per GET, {} tasks", tasks.len()),
+        READS,
+        "requests",
+        start.elapsed(),
+    );
+
+    let snapshot = Snapshot::new(TASKS_UPDATED, 1, tasks).unwrap();
+    let start = Instant::now();
+    for _ in 0..READS {
+        let body = snapshot.body.clone();
+        assert!(!body.is_empty());
+    }
+    report(
+        &format!("cached snapshot per GET, {} tasks", snapshot.value.len()),
+        READS,
+        "requests",
+        start.elapsed(),
+    );
+}
+
+fn main() {
+    for count in &[10, 100] {
+        reads(tasks(*count));
+    }
+
+    let mut system = System::new("fanout");
+    system.block_on(async {
+        for count in &[10, 100] {
+            fanout(tasks(*count)).await;
+        }
+    });
+}
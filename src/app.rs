@@ -1,36 +1,306 @@
 use std::sync::Mutex;
-use std::time::Instant;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use actix::prelude::*;
 use actix_cors::Cors;
-use actix_web::middleware::Logger;
-use actix_web::{get, http, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::http::header::{
+    self, ETag, EntityTag, HeaderValue, HttpDate, IfModifiedSince, IfNoneMatch, LastModified,
+};
+use actix_web::dev::ServiceRequest;
+use actix_web::middleware::{Compress, Logger};
+use actix_web::{
+    get, http, post, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
+};
 use actix_web_actors::ws;
 use actix_web_httpauth::extractors::bearer::BearerAuth;
+use bytes::Bytes;
+use chrono::Utc;
 
 use crate::config::Config;
-use crate::schema::Topic;
-use crate::session::{SessionManager, TaskSession, TasksUpdated, TopicUpdated};
+use crate::encoding::{Encoded, Encoding};
+use crate::error::Result;
+use crate::filter::TaskFilter;
+use crate::frontend::{self, StaticDir};
+use crate::graphql;
+use crate::limits::{self, Limiter, Limits};
+use crate::redact::Redaction;
+use crate::relay::Relay;
+use crate::schema::{
+    DisplayTask, Envelope, Pushed, TaskDelta, TaskView, Topic, UrgencyLevels, SCHEMA_V1,
+    SCHEMA_V2,
+};
+use crate::session::{
+    SessionManager, TaskSession, TasksUpdated, TopicUpdated, View, TASKS_UPDATED, TOPIC_UPDATED,
+};
+use crate::snapshot::{Snapshot, SnapshotCell};
 
+use log::{error, info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+#[cfg(feature = "grpc")]
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
 use task_hookrs::task::Task;
 
+pub(crate) const BEARER_PROTOCOL_PREFIX: &str = "bearer.";
+/// Recorded as `updated_by` for changes made through the HTTP API.
+const UPDATED_BY_API: &str = "api";
+/// What `/api/v1` bodies were limited to when they could only be JSON.
+const V1_BODY_LIMIT: usize = 32 * 1024;
+/// `Logger::default()`'s format, with the request line from
+/// `logged_request_line`.
+const ACCESS_LOG_FORMAT: &str =
+    r#"%a "%{request_line}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
+
 #[derive(Debug)]
 pub struct AppState {
-    pub topic: Mutex<Topic>,
-    pub tasks: Mutex<Vec<Task>>,
+    pub topic: SnapshotCell<Topic>,
+    /// Everything, as last set.
+    pub tasks: SnapshotCell<Vec<Task>>,
+    /// `tasks` with the redaction applied, shown to anonymous clients.
+    pub public_tasks: SnapshotCell<Vec<Task>>,
     pub session_manager: Addr<SessionManager>,
-    pub api_key: String,
+    /// Writes, and the private view, are refused when there is no key.
+    pub api_key: Option<String>,
+    pub limiter: Limiter,
+    redaction: Redaction,
+    urgency_levels: UrgencyLevels,
+    /// Refuse websocket sessions that don't present the key.
+    private_stream: bool,
+    /// Distinguishes revisions handed out by this process from those of a
+    /// previous run, so stale `ETag`s never match after a restart.
+    epoch: u64,
+    // the last revision handed out. Readers never take this; it only keeps
+    // notifications in the same order as the snapshots they announce
+    revision: Mutex<u64>,
 }
 
 impl AppState {
     pub fn new(api_key: String) -> AppState {
         AppState {
-            topic: Mutex::new(Topic::default()),
-            tasks: Mutex::new(Vec::new()),
+            api_key: Some(api_key),
+            ..AppState::read_only()
+        }
+    }
+
+    /// State that can only be changed from inside the server, e.g. by a
+    /// `Relay`.
+    pub fn read_only() -> AppState {
+        AppState {
+            topic: SnapshotCell::new(TOPIC_UPDATED, Topic::default())
+                .expect("default topic is serializable"),
+            tasks: SnapshotCell::new(TASKS_UPDATED, Vec::new())
+                .expect("empty task list is serializable"),
+            public_tasks: SnapshotCell::new(TASKS_UPDATED, Vec::new())
+                .expect("empty task list is serializable"),
             session_manager: SessionManager::new().start(),
-            api_key: api_key,
+            api_key: None,
+            limiter: Limiter::default(),
+            redaction: Redaction::default(),
+            urgency_levels: UrgencyLevels::default(),
+            private_stream: false,
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            revision: Mutex::new(0),
+        }
+    }
+
+    pub fn set_tasks(&self, tasks: Vec<Task>) -> Result<()> {
+        self.set_tasks_by(tasks, None)?;
+        Ok(())
+    }
+
+    /// Like `set_tasks`, recording how the tasks were set, e.g. `api`.
+    pub fn set_tasks_by(&self, tasks: Vec<Task>, updated_by: Option<&str>) -> Result<Pushed> {
+        let mut revision = self.revision.lock().unwrap();
+        self.store_tasks(&mut revision, tasks, updated_by)
+    }
+
+    /// Applies `delta` to the tasks, or gives `None` if it was made against
+    /// tasks other than the current ones.
+    pub fn apply_task_delta(
+        &self,
+        delta: &TaskDelta,
+        updated_by: Option<&str>,
+    ) -> Result<Option<Pushed>> {
+        // held across the read so a concurrent `set_tasks` isn't undone
+        let mut revision = self.revision.lock().unwrap();
+        let current = self.pushed();
+
+        if delta.epoch != current.epoch || delta.base_revision != current.revision {
+            return Ok(None);
+        }
+        if delta.is_empty() {
+            return Ok(Some(current));
+        }
+
+        let tasks = delta.apply(self.tasks.load().value.clone());
+        self.store_tasks(&mut revision, tasks, updated_by).map(Some)
+    }
+
+    /// Where the tasks are at, for a client to make its next delta against.
+    pub fn pushed(&self) -> Pushed {
+        Pushed {
+            epoch: self.epoch,
+            revision: self.tasks.load().revision,
+        }
+    }
+
+    fn store_tasks(
+        &self,
+        revision: &mut u64,
+        tasks: Vec<Task>,
+        updated_by: Option<&str>,
+    ) -> Result<Pushed> {
+        let next = *revision + 1;
+        let snapshot = |tasks| -> Result<Arc<Snapshot<Vec<Task>>>> {
+            Ok(Arc::new(
+                Snapshot::new(TASKS_UPDATED, next, tasks)?.updated_by(updated_by),
+            ))
+        };
+
+        // without a redaction both views are the same, so share the snapshot
+        let (private, public) = if self.redaction.is_empty() {
+            let snapshot = snapshot(tasks)?;
+            (snapshot.clone(), snapshot)
+        } else {
+            let public = self.redaction.apply(tasks.clone());
+            (snapshot(tasks)?, snapshot(public)?)
+        };
+
+        self.tasks.replace(private.clone());
+        self.public_tasks.replace(public.clone());
+        *revision = next;
+        self.session_manager.do_send(TasksUpdated {
+            private,
+            public,
+            urgency_levels: self.urgency_levels.clone(),
+        });
+        Ok(Pushed {
+            epoch: self.epoch,
+            revision: next,
+        })
+    }
+
+    pub fn tasks_for(&self, view: View) -> Arc<Snapshot<Vec<Task>>> {
+        match view {
+            View::Private => self.tasks.load(),
+            View::Public => self.public_tasks.load(),
+        }
+    }
+
+    pub fn set_topic(&self, topic: Topic) -> Result<()> {
+        self.set_topic_by(topic, None)
+    }
+
+    /// Like `set_topic`, recording how the topic was set, e.g. `api`.
+    pub fn set_topic_by(&self, topic: Topic, updated_by: Option<&str>) -> Result<()> {
+        let mut revision = self.revision.lock().unwrap();
+        self.store_topic(&mut revision, topic, updated_by)?;
+        Ok(())
+    }
+
+    /// Marks the topic's next segment done, giving the new topic, or `None`
+    /// if there was no segment left to do.
+    pub fn complete_next_segment(
+        &self,
+        updated_by: Option<&str>,
+    ) -> Result<Option<Arc<Snapshot<Topic>>>> {
+        // held across the read so a concurrent `set_topic` isn't undone
+        let mut revision = self.revision.lock().unwrap();
+        let mut topic = self.topic.load().value.clone();
+
+        if topic.complete_next_segment().is_none() {
+            return Ok(None);
+        }
+
+        self.store_topic(&mut revision, topic, updated_by).map(Some)
+    }
+
+    fn store_topic(
+        &self,
+        revision: &mut u64,
+        topic: Topic,
+        updated_by: Option<&str>,
+    ) -> Result<Arc<Snapshot<Topic>>> {
+        let snapshot =
+            Arc::new(Snapshot::new(TOPIC_UPDATED, *revision + 1, topic)?.updated_by(updated_by));
+        self.topic.replace(snapshot.clone());
+        *revision = snapshot.revision;
+        self.session_manager
+            .do_send(TopicUpdated(snapshot.clone()));
+        Ok(snapshot)
+    }
+
+    pub fn with_limits(mut self, limits: &Limits) -> Self {
+        self.limiter = Limiter::new(limits);
+        self
+    }
+
+    /// Sets what is trimmed from the tasks in the public view. Only affects
+    /// tasks set from then on.
+    pub fn with_redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    pub fn with_urgency_levels(mut self, urgency_levels: UrgencyLevels) -> Self {
+        self.urgency_levels = urgency_levels;
+        self
+    }
+
+    pub fn with_private_stream(mut self, private_stream: bool) -> Self {
+        self.private_stream = private_stream;
+        self
+    }
+
+    pub fn authorized(&self, token: &str) -> bool {
+        self.api_key.as_deref() == Some(token)
+    }
+
+    /// The view for a reader that presented `token`, if any.
+    pub fn view_for(&self, token: Option<&str>) -> View {
+        match token {
+            Some(token) if self.authorized(token) => View::Private,
+            _ => View::Public,
         }
     }
+
+    /// The view a streaming client presenting `token` gets, or `None` if it
+    /// is refused. A wrong token is refused rather than quietly given the
+    /// public view.
+    pub fn stream_view(&self, token: Option<&str>) -> Option<View> {
+        match token {
+            Some(token) if self.authorized(token) => Some(View::Private),
+            Some(_) => None,
+            None if self.private_stream => None,
+            None => Some(View::Public),
+        }
+    }
+
+    fn etag<T>(
+        &self,
+        snapshot: &Snapshot<T>,
+        view: View,
+        encoding: Encoding,
+        version: u32,
+    ) -> EntityTag {
+        let mut tag = format!("{:x}-{}", self.epoch, snapshot.revision);
+        if version != SCHEMA_V1 {
+            tag.push_str(&format!("-v{}", version));
+        }
+        if view == View::Private {
+            tag.push_str("-private");
+        }
+        if encoding != Encoding::Json {
+            tag.push('-');
+            tag.push_str(encoding.name());
+        }
+        // weak, as `Compress` sends the same tag for the gzipped body
+        EntityTag::weak(tag)
+    }
 }
 
 pub struct Server {}
@@ -39,7 +309,41 @@ impl Server {
     pub async fn start(config: Config) -> std::io::Result<()> {
         env_logger::init();
 
-        let state = web::Data::new(AppState::new(config.server.api_key.unwrap()));
+        let state = match config.server.upstream {
+            Some(ref upstream) => {
+                if config.server.api_key.is_some() {
+                    warn!("Ignoring api key, updates only come from the upstream");
+                }
+
+                if config.server.private_stream {
+                    warn!("Without an api key, every websocket session will be refused");
+                }
+
+                let state = web::Data::new(
+                    AppState::read_only()
+                        .with_limits(&config.server.limits)
+                        .with_redaction(config.server.redaction.clone())
+                        .with_urgency_levels(config.server.urgency_levels.clone())
+                        .with_private_stream(config.server.private_stream),
+                );
+                info!("Relaying {}", upstream);
+                let relay = Relay::new(upstream, state.clone())
+                    .with_key(config.server.upstream_key.clone());
+                actix_web::rt::spawn(relay.run());
+                state
+            }
+            None => web::Data::new(
+                AppState::new(config.server.api_key.unwrap())
+                    .with_limits(&config.server.limits)
+                    .with_redaction(config.server.redaction.clone())
+                    .with_urgency_levels(config.server.urgency_levels.clone())
+                    .with_private_stream(config.server.private_stream),
+            ),
+        };
+        actix_web::rt::spawn(limits::prune_forever(state.clone()));
+        let static_dir = config.server.static_dir.map(|dir| StaticDir(dir.into()));
+        #[cfg(feature = "grpc")]
+        let grpc_state = state.clone();
 
         let mut server = HttpServer::new(move || {
 
@@ -50,17 +354,43 @@ impl Server {
                 .allowed_header(http::header::CONTENT_TYPE)
                 .max_age(3600);
 
-            App::new()
-                .wrap(Logger::default())
+            let app = App::new()
+                // leaves websocket upgrades and empty responses alone
+                .wrap(Compress::default())
+                .wrap(
+                    Logger::new(ACCESS_LOG_FORMAT)
+                        .custom_request_replace("request_line", logged_request_line),
+                )
                 .wrap(Logger::new("%a %{User-Agent}i"))
                 .wrap(cors)
                 .app_data(state.clone())
-                .configure(app_config)
+                .configure(app_config);
+
+            // only reached when no API or websocket route matched
+            match static_dir {
+                Some(ref dir) => app
+                    .data(dir.clone())
+                    .default_service(web::route().to(frontend::serve)),
+                None => app,
+            }
         });
 
         let port = config.server.port.unwrap();
+        let bind = config.server.bind.unwrap();
+
+        if let Some(grpc_port) = config.server.grpc_port {
+            #[cfg(feature = "grpc")]
+            for addr in &bind {
+                for addr in format!("{}:{}", addr, grpc_port).to_socket_addrs()? {
+                    actix_web::rt::spawn(crate::grpc::serve(addr, grpc_state.clone()));
+                }
+            }
 
-        for addr in config.server.bind.unwrap() {
+            #[cfg(not(feature = "grpc"))]
+            warn!("Built without the grpc feature, not serving gRPC on port {}", grpc_port);
+        }
+
+        for addr in bind {
             server = server.bind(&format!("{}:{}", addr, port))?;
         }
 
@@ -70,72 +400,425 @@ impl Server {
 
 // separated out to make testing easier/consistent
 pub fn app_config(cfg: &mut web::ServiceConfig) {
-    let api = web::scope("/api/v1")
+    let api = web::scope(&format!("/api/v{}", SCHEMA_V1))
+        .app_data(web::PayloadConfig::new(V1_BODY_LIMIT))
+        .wrap_fn(limits::rate_limit)
 	.service(get_tasks)
 	.service(set_tasks)
 	.service(get_topic)
-	.service(set_topic);
+	.service(set_topic)
+	.service(complete_next_segment);
+
+    // the same writes, saying what they left the tasks at and taking
+    // deltas; reads come wrapped in envelopes
+    let api_v2 = web::scope(&format!("/api/v{}", SCHEMA_V2))
+        .wrap_fn(limits::rate_limit)
+	.service(get_tasks_v2)
+	.service(get_task_views)
+	.service(set_tasks_v2)
+	.service(set_task_delta)
+	.service(get_topic_v2)
+	.service(set_topic)
+	.service(complete_next_segment_v2);
 
     let socket_service = web::resource("/ws/").to(ws_index);
 
+    // GET upgrades to a graphql-ws session, POST runs a query
+    let graphql = web::resource("/graphql")
+        .data(graphql::schema())
+        .wrap_fn(limits::read_rate_limit)
+        .route(web::get().to(graphql::subscriptions))
+        .route(web::post().to(graphql::query));
+
     cfg
         .service(api)
-        .service(socket_service);
+        .service(api_v2)
+        .service(socket_service)
+        .service(graphql);
+}
+
+/// Responds with the body of `snapshot` in the encoding the request's
+/// `Accept` prefers, or with 304 Not Modified if the request's
+/// `If-None-Match` or `If-Modified-Since` says the client already has it.
+fn snapshot_response<T: Serialize>(
+    req: &HttpRequest,
+    data: &AppState,
+    snapshot: &Snapshot<T>,
+    view: View,
+) -> HttpResponse {
+    conditional_response(req, data, snapshot, view, SCHEMA_V1, |encoding| {
+        snapshot.body_in(encoding)
+    })
+}
+
+/// Like `snapshot_response`, but with the `Envelope` of `data` made from
+/// the snapshot's value, as `/api/v2` responds.
+fn envelope_response<T, D: Serialize>(
+    req: &HttpRequest,
+    data: &AppState,
+    snapshot: &Snapshot<T>,
+    view: View,
+    make: impl FnOnce(&T) -> D,
+) -> HttpResponse {
+    conditional_response(req, data, snapshot, view, SCHEMA_V2, |encoding| {
+        Ok(encoding
+            .encode(&Envelope::new(snapshot, make(&snapshot.value)))?
+            .into())
+    })
+}
+
+fn accepted_encoding(req: &HttpRequest) -> Option<Encoding> {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    Encoding::negotiate(accept)
+}
+
+fn conditional_response<T>(
+    req: &HttpRequest,
+    data: &AppState,
+    snapshot: &Snapshot<T>,
+    view: View,
+    version: u32,
+    body: impl FnOnce(Encoding) -> Result<Bytes>,
+) -> HttpResponse {
+    let encoding = match accepted_encoding(req) {
+        Some(encoding) => encoding,
+        // v1 answered every `Accept` with JSON before it could negotiate
+        None if version == SCHEMA_V1 => Encoding::Json,
+        None => return HttpResponse::NotAcceptable().finish(),
+    };
+
+    let etag = data.etag(snapshot, view, encoding, version);
+    let updated_at = HttpDate::from(snapshot.updated_at);
+
+    // If-Modified-Since is only looked at without an ETag to go by. HTTP
+    // dates only have second precision, so it is compared against the exact
+    // time of the update: a snapshot from the same second as `since` may be
+    // newer than the client's copy.
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => match req.get_header::<IfModifiedSince>() {
+            Some(IfModifiedSince(since)) => snapshot.updated_at < SystemTime::from(since),
+            None => false,
+        },
+    };
+
+    if not_modified {
+        return HttpResponse::NotModified()
+            .set(ETag(etag))
+            .set(LastModified(updated_at))
+            .finish();
+    }
+
+    let body = match body(encoding) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Could not encode {}: {}", encoding.name(), e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type(encoding.mime())
+        .set(ETag(etag))
+        .set(LastModified(updated_at))
+        .body(body)
+}
+
+/// Anonymous readers get the public view, and those presenting the api key
+/// the private one.
+#[get("/tasks")]
+async fn get_tasks(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    auth: Option<BearerAuth>,
+) -> impl Responder {
+    let view = data.view_for(auth.as_ref().map(|auth| auth.token()));
+    let mut response = snapshot_response(&req, &data, &data.tasks_for(view), view);
+    response
+        .headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("accept, authorization"));
+    response
 }
 
 #[get("/tasks")]
-async fn get_tasks(data: web::Data<AppState>) -> impl Responder {
-    HttpResponse::Ok().json(&data.tasks)
+async fn get_tasks_v2(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    auth: Option<BearerAuth>,
+) -> impl Responder {
+    let view = data.view_for(auth.as_ref().map(|auth| auth.token()));
+    let mut response = envelope_response(&req, &data, &data.tasks_for(view), view, |tasks| {
+        tasks.iter().map(DisplayTask::from).collect::<Vec<_>>()
+    });
+    response
+        .headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("accept, authorization"));
+    response
+}
+
+/// The tasks as `TaskView`s. Their durations are as of the response, so
+/// unlike the other reads it is never answered with 304 Not Modified.
+#[get("/tasks/views")]
+async fn get_task_views(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    auth: Option<BearerAuth>,
+) -> impl Responder {
+    let encoding = match accepted_encoding(&req) {
+        Some(encoding) => encoding,
+        None => return HttpResponse::NotAcceptable().finish(),
+    };
+
+    let view = data.view_for(auth.as_ref().map(|auth| auth.token()));
+    let tasks = data.tasks_for(view);
+    let now = Utc::now();
+    let views: Vec<_> = tasks
+        .value
+        .iter()
+        .map(|task| TaskView::new(task, now, &data.urgency_levels))
+        .collect();
+
+    match encoding.encode(&Envelope::new(&tasks, views)) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(encoding.mime())
+            .header(header::VARY, "accept, authorization")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(body),
+        Err(e) => {
+            error!("Could not encode {}: {}", encoding.name(), e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
 }
 
 #[post("/tasks")]
 async fn set_tasks(
     data: web::Data<AppState>,
-    item: web::Json<Vec<Task>>,
+    item: Encoded<Vec<Task>>,
     auth: BearerAuth,
 ) -> impl Responder {
-    if auth.token() == data.api_key.as_str() {
-        let mut tasks = data.tasks.lock().unwrap();
-        *tasks = item.0;
-        let addr = &data.session_manager;
-        addr.do_send(TasksUpdated);
+    if data.authorized(auth.token()) {
+        if let Err(e) = data.set_tasks_by(item.0, Some(UPDATED_BY_API)) {
+            error!("Could not store tasks: {}", e);
+            return HttpResponse::InternalServerError();
+        }
         return HttpResponse::Ok();
     }
     HttpResponse::Unauthorized()
 }
 
+/// Responds with where the tasks are at, for the client to make its next
+/// delta against.
+#[post("/tasks")]
+async fn set_tasks_v2(
+    data: web::Data<AppState>,
+    item: Encoded<Vec<Task>>,
+    auth: BearerAuth,
+) -> impl Responder {
+    if data.authorized(auth.token()) {
+        return match data.set_tasks_by(item.0, Some(UPDATED_BY_API)) {
+            Ok(pushed) => HttpResponse::Ok().json(pushed),
+            Err(e) => {
+                error!("Could not store tasks: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        };
+    }
+    HttpResponse::Unauthorized().finish()
+}
+
+/// Applies the changes since a revision of the tasks, and responds with the
+/// new revision, or with 409 Conflict and the current one if the delta was
+/// made against any other, so the client can push everything instead.
+#[post("/tasks/delta")]
+async fn set_task_delta(
+    data: web::Data<AppState>,
+    item: Encoded<TaskDelta>,
+    auth: BearerAuth,
+) -> impl Responder {
+    if !data.authorized(auth.token()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match data.apply_task_delta(&item.0, Some(UPDATED_BY_API)) {
+        Ok(Some(pushed)) => HttpResponse::Ok().json(pushed),
+        Ok(None) => HttpResponse::Conflict().json(data.pushed()),
+        Err(e) => {
+            error!("Could not store tasks: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/topic")]
+async fn get_topic(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let mut response = snapshot_response(&req, &data, &data.topic.load(), View::Public);
+    response
+        .headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("accept"));
+    response
+}
+
 #[get("/topic")]
-async fn get_topic(data: web::Data<AppState>) -> impl Responder {
-    HttpResponse::Ok().json(&data.topic)
+async fn get_topic_v2(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let mut response =
+        envelope_response(&req, &data, &data.topic.load(), View::Public, Topic::clone);
+    response
+        .headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("accept"));
+    response
 }
 
 #[post("/topic")]
 async fn set_topic(
     data: web::Data<AppState>,
-    item: web::Json<Topic>,
+    item: Encoded<Topic>,
     auth: BearerAuth,
 ) -> impl Responder {
-    if auth.token() == data.api_key.as_str() {
-        let mut topic = data.topic.lock().unwrap();
-        *topic = item.0;
-        let addr = &data.session_manager;
-        addr.do_send(TopicUpdated);
+    if data.authorized(auth.token()) {
+        if let Err(e) = data.set_topic_by(item.0, Some(UPDATED_BY_API)) {
+            error!("Could not store topic: {}", e);
+            return HttpResponse::InternalServerError();
+        }
         return HttpResponse::Ok();
     }
     HttpResponse::Unauthorized()
 }
 
+/// Marks the next segment of the topic done, and responds with the new
+/// topic, or with 409 Conflict if every segment already was.
+#[post("/topic/segments/next")]
+async fn complete_next_segment(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    auth: BearerAuth,
+) -> impl Responder {
+    match next_segment(&data, &auth) {
+        Ok(snapshot) => snapshot_response(&req, &data, &snapshot, View::Public),
+        Err(response) => response,
+    }
+}
+
+#[post("/topic/segments/next")]
+async fn complete_next_segment_v2(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    auth: BearerAuth,
+) -> impl Responder {
+    match next_segment(&data, &auth) {
+        Ok(snapshot) => envelope_response(&req, &data, &snapshot, View::Public, Topic::clone),
+        Err(response) => response,
+    }
+}
+
+fn next_segment(
+    data: &AppState,
+    auth: &BearerAuth,
+) -> std::result::Result<Arc<Snapshot<Topic>>, HttpResponse> {
+    if !data.authorized(auth.token()) {
+        return Err(HttpResponse::Unauthorized().finish());
+    }
+
+    match data.complete_next_segment(Some(UPDATED_BY_API)) {
+        Ok(Some(snapshot)) => Ok(snapshot),
+        Ok(None) => Err(HttpResponse::Conflict().body("no segments left")),
+        Err(e) => {
+            error!("Could not store topic: {}", e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// The token a websocket client presented, either as the `token` query
+/// parameter or as a `bearer.<token>` subprotocol.
+pub(crate) fn ws_token(req: &HttpRequest, query: &HashMap<String, String>) -> Option<String> {
+    if let Some(token) = query.get("token") {
+        return Some(token.clone());
+    }
+
+    req.headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .find_map(|protocol| protocol.trim().strip_prefix(BEARER_PROTOCOL_PREFIX))
+        .map(|token| token.to_string())
+}
+
+/// The request line as the access log shows it, with the value of any
+/// `token` query parameter, which websocket clients may send, left out.
+fn logged_request_line(req: &ServiceRequest) -> String {
+    if req.query_string().is_empty() {
+        return format!("{} {} {:?}", req.method(), req.path(), req.version());
+    }
+
+    let query: Vec<_> = req
+        .query_string()
+        .split('&')
+        .map(|pair| match pair.strip_prefix("token=") {
+            Some(_) => "token=[redacted]",
+            None => pair,
+        })
+        .collect();
+    format!(
+        "{} {}?{} {:?}",
+        req.method(),
+        req.path(),
+        query.join("&"),
+        req.version()
+    )
+}
+
+// permessage-deflate is not supported, and out of scope until
+// actix-web-actors implements it: the extension is never accepted during the
+// handshake, so clients that offer it fall back to uncompressed frames.
 pub async fn ws_index(
     req: HttpRequest,
     stream: web::Payload,
     data: web::Data<AppState>,
 ) -> std::result::Result<HttpResponse, actix_web::Error> {
-    ws::start(
-        TaskSession {
-            hb: Instant::now(),
-            addr: data.session_manager.clone(),
-            id: "".to_string(),
-        },
+    let query = match web::Query::<HashMap<String, String>>::from_query(req.query_string()) {
+        Ok(query) => query.into_inner(),
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+
+    let view = match data.stream_view(ws_token(&req, &query).as_deref()) {
+        Some(view) => view,
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    // the slice of the tasks the session is sent, which it can change later
+    let filter = match TaskFilter::from_query(&query) {
+        Ok(filter) => filter,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+    };
+
+    let ip = data
+        .limiter
+        .client_ip(req.peer_addr(), &req.connection_info());
+    let permit = match data.limiter.sessions.acquire(ip) {
+        Some(permit) => permit,
+        None => return Ok(limits::session_refused()),
+    };
+
+    // Browsers can't set headers on websocket requests, so the token may
+    // come as a subprotocol. They also fail the handshake unless one of the
+    // offered protocols is accepted, so clients offer the one naming their
+    // encoding alongside it, even for JSON. Clients offering none of them
+    // predate the envelopes and get the bare `tasks-updated` notifications.
+    let protocols = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok());
+    let encoding = Encoding::from_ws_protocols(protocols);
+
+    ws::start_with_protocols(
+        TaskSession::new(data.clone(), view, filter, permit, encoding),
+        &[encoding.unwrap_or(Encoding::Json).ws_protocol()],
         &req,
         stream,
     )
@@ -152,4 +835,17 @@ mod tests {
     //     let resp = index(req).await;
     //     assert_eq!(resp.status(), http::StatusCode::OK);
     // }
+
+    #[test]
+    fn tokens_stay_out_of_the_access_log() {
+        let req = test::TestRequest::with_uri("/ws/?token=secret&events=tasks-updated")
+            .to_srv_request();
+        assert_eq!(
+            logged_request_line(&req),
+            "GET /ws/?token=[redacted]&events=tasks-updated HTTP/1.1"
+        );
+
+        let req = test::TestRequest::with_uri("/ws/").to_srv_request();
+        assert_eq!(logged_request_line(&req), "GET /ws/ HTTP/1.1");
+    }
 }
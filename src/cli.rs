@@ -1,10 +1,22 @@
+use chrono::{DateTime, Utc};
 use clap::{crate_authors, crate_description, crate_version, App, AppSettings, Arg, ArgMatches};
 use crate::app::Server;
-use crate::client::Client;
-use crate::tasks::TaskClient;
-use crate::error::UnwrapOrExit;
-use crate::schema::Topic;
+use crate::client::{Client, Payload, Sent};
+use crate::encoding::Encoding;
+use crate::filter::SortSpec;
+use crate::source::Source;
+use crate::tasks::{Exports, TaskClient};
+use crate::error::{Result, TSError, UnwrapOrExit};
+use crate::schema::{Link, Segment, TaskDiff, TaskStats, Topic};
 use crate::config;
+use crate::hook;
+use crate::outbox::{Kind, Outbox, Update};
+use crate::output;
+use crate::watch;
+use task_hookrs::task::Task;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 
 pub fn cli() -> ArgMatches<'static> {
@@ -55,6 +67,40 @@ pub fn cli() -> ArgMatches<'static> {
                         .env("TS_API_KEY")
                         .hide_env_values(true)
                         .required(false),
+                )
+                .arg(
+                    Arg::with_name("upstream")
+                        .help("Mirror the server at this url instead of accepting updates")
+                        .long("upstream")
+                        .short("u")
+                        .env("TS_UPSTREAM")
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("upstream_key")
+                        .help("Key to present to the upstream, to mirror its private view")
+                        .long("upstream-key")
+                        .env("TS_UPSTREAM_KEY")
+                        .hide_env_values(true)
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("static_dir")
+                        .help("Serve a frontend bundle from this directory at /")
+                        .long("static-dir")
+                        .env("TS_STATIC_DIR")
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("grpc_port")
+                        .help("Also serve the gRPC API on this port (needs the grpc feature)")
+                        .long("grpc-port")
+                        .env("TS_GRPC_PORT")
+                        .takes_value(true)
+                        .required(false),
                 ),
         )
         .subcommand(
@@ -78,15 +124,85 @@ pub fn cli() -> ArgMatches<'static> {
                         .takes_value(true)
                         .required(false),
                 )
+                .arg(profile_arg())
+                .arg(
+                    Arg::with_name("retries")
+                        .help("Retry a failed push this many times before queueing it in the outbox")
+                        .long("retries")
+                        .env("TS_RETRIES")
+                        .takes_value(true)
+                        .validator(|n| n.parse::<u32>().map(|_| ()).map_err(|e| e.to_string()))
+                        .required(false),
+                )
+                .subcommand(
+                    App::new("update")
+                        .about("push tasks to a server")
+                        .arg(
+                            Arg::with_name("dry_run")
+                                .help("Print what would be pushed, as JSON, instead of pushing it")
+                                .long("dry-run")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("all_profiles")
+                                .help("Push to the server of every profile, ignoring --profile, --server and --key")
+                                .long("all-profiles")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("filter")
+                                .help("The filter to use for listing tasks")
+                                .long("filter")
+                                .short("f")
+                                .default_value("status:pending")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("format")
+                                .help("Upload the tasks in this format")
+                                .long("format")
+                                .takes_value(true)
+                                .possible_values(&["json", "msgpack", "cbor"])
+                                .required(false),
+                        )
+                        .arg(source_arg())
+                        .arg(sort_arg()),
+                )
                 .subcommand(
-                    App::new("update").about("push tasks to a server").arg(
-                        Arg::with_name("filter")
-                            .help("The filter to use for listing tasks")
-                            .long("filter")
-                            .short("f")
-                            .default_value("status:pending")
-                            .required(false),
-                    ),
+                    App::new("watch")
+                        .about("push tasks to a server whenever they change")
+                        .arg(
+                            Arg::with_name("filter")
+                                .help("The filter to use for listing tasks")
+                                .long("filter")
+                                .short("f")
+                                .default_value("status:pending")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("format")
+                                .help("Upload the tasks in this format")
+                                .long("format")
+                                .takes_value(true)
+                                .possible_values(&["json", "msgpack", "cbor"])
+                                .required(false),
+                        )
+                        .arg(sort_arg())
+                        .arg(
+                            Arg::with_name("data_dir")
+                                .help("The taskwarrior data directory (defaults to TASKDATA or data.location)")
+                                .long("data-dir")
+                                .takes_value(true)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("debounce")
+                                .help("Milliseconds to let a burst of changes settle before pushing")
+                                .long("debounce")
+                                .takes_value(true)
+                                .validator(|ms| ms.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                                .required(false),
+                        ),
                 )
                 .subcommand(
                     App::new("topic")
@@ -104,6 +220,183 @@ pub fn cli() -> ArgMatches<'static> {
                                 .short("d")
                                 .takes_value(true)
                                 .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("link")
+                                .help("Add a link, as `url` or `title=url`")
+                                .long("link")
+                                .short("l")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("tag")
+                                .help("Add a tag")
+                                .long("tag")
+                                .short("t")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("goal")
+                                .help("What the stream is trying to get done")
+                                .long("goal")
+                                .short("g")
+                                .takes_value(true)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("started_at")
+                                .help("When the topic was started: `now` or an RFC 3339 time")
+                                .long("started-at")
+                                .takes_value(true)
+                                .validator(|value| parse_time(&value).map(|_| ()))
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("segment")
+                                .help("Add a segment to the agenda, in order")
+                                .long("segment")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    App::new("next-segment").about("mark the topic's next segment done"),
+                )
+                .subcommand(
+                    App::new("get")
+                        .about("show what the server has")
+                        .arg(
+                            Arg::with_name("what")
+                                .help("What to show")
+                                .index(1)
+                                .possible_values(&["tasks", "topic", "stats"])
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("json")
+                                .help("Print JSON instead of a table, as --format json does")
+                                .long("json")
+                                .conflicts_with("output")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("output")
+                                .help("Print in this format")
+                                .long("format")
+                                .value_name("format")
+                                .takes_value(true)
+                                .possible_values(&["table", "json"])
+                                .default_value("table")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("encoding")
+                                .help("Fetch in this encoding")
+                                .long("encoding")
+                                .takes_value(true)
+                                .possible_values(&["json", "msgpack", "cbor"])
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    App::new("diff")
+                        .about("compare the exported tasks with the server's, exiting with 1 if they differ")
+                        .arg(
+                            Arg::with_name("filter")
+                                .help("The filter to use for listing tasks")
+                                .long("filter")
+                                .short("f")
+                                .default_value("status:pending")
+                                .required(false),
+                        )
+                        .arg(source_arg())
+                        .arg(sort_arg())
+                        .arg(
+                            Arg::with_name("json")
+                                .help("Print JSON instead of a list, as --format json does")
+                                .long("json")
+                                .conflicts_with("output")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("output")
+                                .help("Print in this format")
+                                .long("format")
+                                .value_name("format")
+                                .takes_value(true)
+                                .possible_values(&["list", "json"])
+                                .default_value("list")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("encoding")
+                                .help("Fetch in this encoding")
+                                .long("encoding")
+                                .takes_value(true)
+                                .possible_values(&["json", "msgpack", "cbor"])
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    App::new("outbox")
+                        .about("list the updates queued to send once the server is reachable")
+                        .arg(
+                            Arg::with_name("clear")
+                                .help("Drop everything queued instead")
+                                .long("clear")
+                                .required(false),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new("hook")
+                .about("run as a taskwarrior hook")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(App::new("on-add").about("the on-add hook").arg(profile_arg()))
+                .subcommand(App::new("on-modify").about("the on-modify hook").arg(profile_arg()))
+                .subcommand(App::new("on-exit").about("the on-exit hook").arg(profile_arg()))
+                .subcommand(
+                    App::new("install")
+                        .about("install the hooks into taskwarrior")
+                        .arg(profile_arg())
+                        .arg(
+                            Arg::with_name("dir")
+                                .help("The taskwarrior hooks directory")
+                                .long("dir")
+                                .takes_value(true)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("hook")
+                                .help("Install this hook (defaults to on-exit)")
+                                .long("hook")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .possible_values(hook::HOOKS)
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    App::new("push")
+                        .about("push tasks once the change that ran a hook is written")
+                        .setting(AppSettings::Hidden)
+                        .arg(profile_arg())
+                        .arg(
+                            Arg::with_name("after")
+                                .help("Wait for this taskwarrior process to exit first")
+                                .long("after")
+                                .takes_value(true)
+                                .validator(|pid| pid.parse::<u32>().map(|_| ()).map_err(|e| e.to_string()))
+                                .required(false),
                         ),
                 ),
         );
@@ -111,6 +404,33 @@ pub fn cli() -> ArgMatches<'static> {
     app.clone().get_matches()
 }
 
+fn source_arg() -> Arg<'static, 'static> {
+    Arg::with_name("source")
+        .help("Export the tasks from taskwarrior, todotxt:PATH or json:PATH")
+        .long("source")
+        .takes_value(true)
+        .validator(|spec| Source::parse(&spec).map(drop))
+        .required(false)
+}
+
+fn sort_arg() -> Arg<'static, 'static> {
+    Arg::with_name("sort")
+        .help("Sort the tasks by these keys, e.g. 'active,urgency-,due+'")
+        .long("sort")
+        .takes_value(true)
+        .validator(|spec| SortSpec::parse(&spec).map(drop))
+        .required(false)
+}
+
+fn profile_arg() -> Arg<'static, 'static> {
+    Arg::with_name("profile")
+        .help("Use the settings of this profile in place of [client]")
+        .long("profile")
+        .env("TS_PROFILE")
+        .takes_value(true)
+        .required(false)
+}
+
 pub async fn run() -> std::io::Result<()> {
     let matches = cli();
     match matches.subcommand() {
@@ -118,7 +438,7 @@ pub async fn run() -> std::io::Result<()> {
             let config =
                 config::Config::new(&server_matches).unwrap_or_exit("Could not load config file");
 
-            if config.server.api_key.is_none() {
+            if config.server.api_key.is_none() && config.server.upstream.is_none() {
                 let err = clap::Error::with_description(
                     &"Api key must be specified either in config or via parameter",
                     clap::ErrorKind::InvalidValue,
@@ -126,61 +446,454 @@ pub async fn run() -> std::io::Result<()> {
                 err.exit()
             }
 
+            if let Some(ref dir) = config.server.static_dir {
+                if !Path::new(dir).is_dir() {
+                    let err = clap::Error::with_description(
+                        &format!("Static dir '{}' is not a directory", dir),
+                        clap::ErrorKind::InvalidValue,
+                    );
+                    err.exit()
+                }
+            }
+
             Server::start(config).await
         }
         ("client", Some(client_matches)) => {
             let mut config =
                 config::Config::new(&client_matches).unwrap_or_exit("Could not load config file");
 
+            let pushing = match client_matches.subcommand_name() {
+                Some("update") | Some("watch") => Some(Kind::Tasks),
+                Some("topic") => Some(Kind::Topic),
+                _ => None,
+            };
+            // only what pushes sends anything, not looking at the server, the
+            // outbox or what would be sent
+            let sends = match client_matches.subcommand() {
+                ("update", Some(update_matches)) => !update_matches.is_present("dry_run"),
+                ("watch", _) | ("topic", _) | ("next-segment", _) => true,
+                _ => false,
+            };
+            if sends {
+                replay_outbox(&config, pushing).await;
+            }
+
             match client_matches.subcommand() {
+                ("update", Some(update_matches)) if update_matches.is_present("all_profiles") => {
+                    let config = config::Config::load(client_matches)
+                        .unwrap_or_exit("Could not load config file");
+                    update_all_profiles(&config, update_matches).await;
+                    Ok(())
+                }
                 ("update", Some(update_matches)) => {
-                    // we have to pick up the `filter` flag
+                    // we have to pick up the `filter` and `format` flags
                     config::Config::process_client_options(&mut config, &update_matches);
-                    let task_client = TaskClient::new(&config.client.filter.clone().unwrap())
+                    let task_client = TaskClient::new(&config.client)
                         .unwrap_or_exit("Could not create task client");
 
-                    if config.client.server.is_none() {
-                        let err = clap::Error::with_description(
-                            &"Server must be specified either in config or via parameter",
-                            clap::ErrorKind::InvalidValue,
-                        );
-                        err.exit()
+                    if update_matches.is_present("dry_run") {
+                        dry_run(&config, task_client.tasks);
+                        return Ok(());
                     }
 
-                    if config.client.api_key.is_none() {
+                    check_push_config(&config);
+
+                    let client = Client::new(config);
+                    report(client.send(Update::Tasks(task_client.tasks)).await, "post tasks");
+                    Ok(())
+                }
+                ("watch", Some(watch_matches)) => {
+                    config::Config::process_client_options(&mut config, watch_matches);
+                    check_push_config(&config);
+                    if config.client.source != Source::Taskwarrior {
                         let err = clap::Error::with_description(
-                            &"Api key must be specified either in config or via parameter",
+                            &format!("Only taskwarrior can be watched, not {}", config.client.source),
                             clap::ErrorKind::InvalidValue,
                         );
                         err.exit()
                     }
 
+                    let data_dir: PathBuf = match watch_matches.value_of("data_dir") {
+                        Some(dir) => dir.into(),
+                        None => config.client.taskwarrior.data_location()
+                            .unwrap_or_exit("Could not find the taskwarrior data"),
+                    };
+                    let debounce = match watch_matches.value_of("debounce") {
+                        Some(ms) => Duration::from_millis(ms.parse().unwrap()),
+                        None => watch::DEBOUNCE,
+                    };
+                    let mut task_client = TaskClient::new(&config.client)
+                        .unwrap_or_exit("Could not create task client");
+
                     let client = Client::new(config);
-                    client
-                        .push_tasks(&task_client)
+                    watch::watch(&client, &mut task_client, &data_dir, debounce)
                         .await
-                        .unwrap_or_exit("Could not post tasks");
+                        .unwrap_or_exit("Could not watch the taskwarrior data");
                     Ok(())
                 }
                 ("topic", Some(topic_matches)) => {
                     let client = Client::new(config);
-                    let topic = Topic::new(
-                        topic_matches.value_of("title").unwrap().to_string(),
+                    let values = |name| -> Vec<String> {
                         topic_matches
-                            .value_of("description")
+                            .values_of(name)
+                            .map(|values| values.map(str::to_string).collect())
                             .unwrap_or_default()
-                            .to_string(),
-                    );
+                    };
+                    let topic = Topic {
+                        links: values("link").iter().map(|link| Link::parse(link)).collect(),
+                        tags: values("tag"),
+                        started_at: topic_matches
+                            .value_of("started_at")
+                            .map(|time| parse_time(time).unwrap()),
+                        goal: topic_matches.value_of("goal").map(str::to_string),
+                        segments: values("segment").into_iter().map(Segment::new).collect(),
+                        ..Topic::new(
+                            topic_matches.value_of("title").unwrap().to_string(),
+                            topic_matches
+                                .value_of("description")
+                                .unwrap_or_default()
+                                .to_string(),
+                        )
+                    };
 
-                    client
-                        .set_topic(topic)
+                    report(client.send(Update::Topic(topic)).await, "set topic");
+                    Ok(())
+                }
+                ("next-segment", Some(_)) => {
+                    let client = Client::new(config);
+                    let topic = client
+                        .complete_next_segment()
                         .await
-                        .unwrap_or_exit("Could not set topic");
+                        .unwrap_or_exit("Could not mark the next segment done");
+
+                    match topic.segments.iter().find(|segment| !segment.done) {
+                        Some(next) => println!("Next up: {}", next.title),
+                        None => println!("All segments done"),
+                    }
+                    Ok(())
+                }
+                ("get", Some(get_matches)) => {
+                    config::Config::process_client_options(&mut config, get_matches);
+                    check_read_config(&config);
+                    let json = prints_json(get_matches);
+                    let client = Client::new(config);
+
+                    match get_matches.value_of("what").unwrap() {
+                        "tasks" => {
+                            let tasks = client.get_tasks().await.unwrap_or_exit("Could not get tasks");
+                            print(json, &tasks, |tasks| output::tasks(tasks));
+                        }
+                        "topic" => {
+                            let topic = client.get_topic().await.unwrap_or_exit("Could not get topic");
+                            print(json, &topic, output::topic);
+                        }
+                        "stats" => {
+                            let tasks = client.get_tasks().await.unwrap_or_exit("Could not get tasks");
+                            print(json, &TaskStats::new(&tasks, Utc::now()), output::stats);
+                        }
+                        _ => unreachable!(),
+                    }
+                    Ok(())
+                }
+                ("diff", Some(diff_matches)) => {
+                    config::Config::process_client_options(&mut config, diff_matches);
+                    check_read_config(&config);
+                    let task_client = TaskClient::new(&config.client)
+                        .unwrap_or_exit("Could not create task client");
+
+                    let client = Client::new(config);
+                    let server = client.get_tasks().await.unwrap_or_exit("Could not get tasks");
+                    // as pushing would have left them
+                    let local = client.redact(task_client.tasks);
+                    let diff = TaskDiff::between(&server, &local);
+                    print(prints_json(diff_matches), &diff, output::diff);
+
+                    if !diff.is_empty() {
+                        std::process::exit(1);
+                    }
+                    Ok(())
+                }
+                ("outbox", Some(outbox_matches)) => {
+                    let path = Outbox::default_path()
+                        .unwrap_or_exit("Could not find the data directory");
+
+                    // without reading it first, as it may not be readable
+                    if outbox_matches.is_present("clear") {
+                        Outbox::clear(&path).unwrap_or_exit("Could not clear the outbox");
+                        println!("Cleared the outbox");
+                        return Ok(());
+                    }
+
+                    let outbox = Outbox::load(&path).unwrap_or_exit("Could not read the outbox");
+                    if outbox.is_empty() {
+                        println!("Nothing queued");
+                    } else {
+                        for entry in outbox.entries() {
+                            println!("{}  {}  {}", entry.queued_at.to_rfc3339(), entry.server, entry.update);
+                        }
+                    }
                     Ok(())
                 }
                 _ => unreachable!(),
             }
         }
+        ("hook", Some(hook_matches)) => {
+            match hook_matches.subcommand() {
+                ("install", Some(install_matches)) => {
+                    // the hooks run from wherever taskwarrior was run
+                    let config_path = install_matches.value_of("config").map(|path| {
+                        std::fs::canonicalize(path)
+                            .map(|path| path.to_string_lossy().to_string())
+                            .unwrap_or_else(|_| path.to_string())
+                    });
+
+                    let dir = match install_matches.value_of("dir") {
+                        Some(dir) => PathBuf::from(dir),
+                        None => dirs::home_dir()
+                            .unwrap_or_exit("Could not find the home directory")
+                            .join(".task")
+                            .join("hooks"),
+                    };
+                    let hooks: Vec<&str> = match install_matches.values_of("hook") {
+                        Some(hooks) => hooks.collect(),
+                        None => hook::DEFAULT_HOOKS.to_vec(),
+                    };
+
+                    let options = hook::Options {
+                        config: config_path.as_deref(),
+                        profile: install_matches.value_of("profile"),
+                    };
+                    for path in hook::install(&dir, &hooks, options)
+                        .unwrap_or_exit("Could not install hooks")
+                    {
+                        println!("Installed {}", path.display());
+                    }
+                    Ok(())
+                }
+                ("push", Some(push_matches)) => {
+                    if let Some(pid) = push_matches.value_of("after") {
+                        hook::wait_for_exit(pid.parse().unwrap()).await;
+                    }
+
+                    let config = config::Config::new(push_matches)
+                        .unwrap_or_exit("Could not load config file");
+                    check_push_config(&config);
+                    replay_outbox(&config, Some(Kind::Tasks)).await;
+                    let task_client = TaskClient::new(&config.client)
+                        .unwrap_or_exit("Could not create task client");
+
+                    let client = Client::new(config);
+                    report(client.send(Update::Tasks(task_client.tasks)).await, "post tasks");
+                    Ok(())
+                }
+                (name, hook_matches) => {
+                    let options = hook::Options {
+                        config: hook_matches.and_then(|matches| matches.value_of("config")),
+                        profile: hook_matches.and_then(|matches| matches.value_of("profile")),
+                    };
+                    let stdin = std::io::stdin();
+                    let stdout = std::io::stdout();
+
+                    // taskwarrior has to get its task back whatever happens,
+                    // so only the push may fail, and quietly
+                    match hook::run(name, stdin.lock(), stdout.lock()) {
+                        // stdout is taskwarrior's, so complaints go to stderr
+                        Ok(true) => {
+                            if let Err(e) = hook::spawn_push(name, options) {
+                                eprintln!("task-streamer: could not push: {}", e);
+                            }
+                        }
+                        Ok(false) => (),
+                        Err(e) => eprintln!("task-streamer: {}", e),
+                    }
+                    Ok(())
+                }
+            }
+        }
         _ => unreachable!(),
     }
 }
+
+/// Exits unless there is enough configured to push tasks.
+fn check_push_config(config: &config::Config) {
+    if let Some(problem) = push_config_problem(config) {
+        clap::Error::with_description(&problem, clap::ErrorKind::InvalidValue).exit()
+    }
+}
+
+/// Exits unless there is enough configured to read from the server.
+fn check_read_config(config: &config::Config) {
+    if let Some(problem) = read_config_problem(config) {
+        clap::Error::with_description(&problem, clap::ErrorKind::InvalidValue).exit()
+    }
+}
+
+/// What keeps `config` from pushing tasks, if anything.
+fn push_config_problem(config: &config::Config) -> Option<String> {
+    read_config_problem(config).or_else(|| {
+        if config.client.api_key.is_none() {
+            Some("Api key must be specified either in config or via parameter".to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// What keeps `config` from reading from the server, if anything.
+fn read_config_problem(config: &config::Config) -> Option<String> {
+    if config.client.server.is_none() {
+        return Some("Server must be specified either in config or via parameter".to_string());
+    }
+
+    match config.client.format {
+        Some(ref format) if Encoding::from_name(format).is_none() => {
+            Some(format!("Unknown format '{}'", format))
+        }
+        _ => None,
+    }
+}
+
+/// Pushes one export to the server of every profile, exporting once for
+/// each source, taskwarrior and filter the profiles use, but with each
+/// profile's own privacy and sort. Exits if any refused the tasks or
+/// could not push for how it is configured, after trying all the others.
+async fn update_all_profiles(config: &config::Config, update_matches: &ArgMatches<'_>) {
+    if config.profiles.is_empty() {
+        let err = clap::Error::with_description(
+            "No profiles are configured",
+            clap::ErrorKind::InvalidValue,
+        );
+        err.exit()
+    }
+
+    let mut exports = Exports::default();
+    let mut refused = false;
+
+    for name in config.profiles.keys() {
+        let mut config = config.profile(name).unwrap();
+        config::Config::process_client_options(&mut config, update_matches);
+
+        let label = config.client.stream.clone().unwrap_or_else(|| name.clone());
+        let tasks = match exports.tasks(&config.client) {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                println!("{}: could not export tasks: {}", label, e);
+                refused = true;
+                continue;
+            }
+        };
+
+        if update_matches.is_present("dry_run") {
+            eprintln!("{}:", label);
+            dry_run(&config, tasks);
+            continue;
+        }
+        if let Some(problem) = push_config_problem(&config) {
+            println!("{}: could not push: {}", label, problem);
+            refused = true;
+            continue;
+        }
+
+        replay_outbox(&config, Some(Kind::Tasks)).await;
+        match Client::new(config).send(Update::Tasks(tasks)).await {
+            Ok(Sent::Delivered) => println!("{}: pushed", label),
+            Ok(Sent::Queued(e)) => println!("{}: could not push yet, queued it to send later: {}", label, e),
+            Err(e) => {
+                println!("{}: could not push: {}", label, e);
+                refused = true;
+            }
+        }
+    }
+
+    if refused {
+        std::process::exit(1);
+    }
+}
+
+/// Sends whatever earlier commands left in the outbox, except the `pushing`
+/// about to be sent anyway, if there is a server to send it to.
+async fn replay_outbox(config: &config::Config, pushing: Option<Kind>) {
+    if config.client.server.is_none() || config.client.api_key.is_none() {
+        return;
+    }
+
+    match Client::new(config.clone()).replay_outbox(pushing).await {
+        Ok(0) => (),
+        Ok(sent) => eprintln!("Sent {} queued updates", sent),
+        Err(e) => eprintln!("Could not read the outbox: {}", e),
+    }
+}
+
+/// Whether `--json` or `--format json` was given.
+fn prints_json(matches: &ArgMatches) -> bool {
+    matches.is_present("json") || matches.value_of("output") == Some("json")
+}
+
+/// Prints `value` as JSON, or as `human` puts it.
+fn print<T: Serialize>(json: bool, value: &T, human: impl Fn(&T) -> String) {
+    if json {
+        print_json(value);
+    } else {
+        println!("{}", human(value));
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    let json = serde_json::to_string_pretty(value)
+        .map_err(TSError::from)
+        .unwrap_or_exit("Could not encode JSON");
+    println!("{}", json);
+}
+
+/// Prints what pushing with `config` would send first: the changes since the
+/// last push to the server if there was one, and all the tasks otherwise.
+/// It is printed as JSON, with where it would go and in which encoding on
+/// stderr.
+fn dry_run(config: &config::Config, tasks: Vec<Task>) {
+    let server = match config.client.server {
+        Some(ref server) => server.clone(),
+        None => {
+            let tasks = config.client.redaction.apply(tasks);
+            eprintln!("Would push {} tasks, once there is a server to push them to", tasks.len());
+            return print_json(&tasks);
+        }
+    };
+
+    let client = Client::new(config.clone());
+    let encoding = client.encoding().name();
+    match client.payload(&tasks) {
+        Payload::All(tasks) => {
+            eprintln!("Would push {} tasks to {} as {}", tasks.len(), server, encoding);
+            print_json(&tasks);
+        }
+        Payload::Delta(delta) => {
+            eprintln!(
+                "Would push {} changed and {} removed tasks to {} as {}, or all the tasks if it can't apply the changes",
+                delta.upserted.len(),
+                delta.removed.len(),
+                server,
+                encoding
+            );
+            print_json(&delta);
+        }
+    }
+}
+
+/// Exits if the update was refused, and says so if it had to be queued.
+fn report(sent: Result<Sent>, what: &str) {
+    match sent.unwrap_or_exit(&format!("Could not {}", what)) {
+        Sent::Delivered => (),
+        Sent::Queued(e) => println!("Could not {} yet, queued it to send later: {}", what, e),
+    }
+}
+
+/// Reads `now` or an RFC 3339 time.
+fn parse_time(value: &str) -> std::result::Result<DateTime<Utc>, String> {
+    if value == "now" {
+        return Ok(Utc::now());
+    }
+
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| format!("invalid time '{}': {}", value, e))
+}
@@ -1,55 +1,436 @@
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use actix_web::rt::time::delay_for;
+use log::{debug, warn};
 use reqwest;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use task_hookrs::task::Task;
 
 use crate::config::Config;
-use crate::error::Result;
-use crate::schema::Topic;
-use crate::tasks::TaskClient;
+use crate::encoding::Encoding;
+use crate::error::{Result, TSError};
+use crate::outbox::{Kind, Outbox, Update};
+use crate::redact::Redaction;
+use crate::schema::{Pushed, TaskDelta, Topic, SCHEMA_V1, SCHEMA_V2};
 
 #[derive(Debug)]
 enum Verb {
+    GET,
     POST,
 }
 
+/// How hard to try before an update is queued in the outbox instead.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Retry {
+    /// Tries after the first.
+    pub attempts: u32,
+    /// Before the first retry, doubling for each after.
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// How long each request may take.
+    pub timeout_secs: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 3,
+            initial_delay_ms: 500,
+            max_delay_ms: 10_000,
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl Retry {
+    /// The wait before retry number `retry`, counting from 0. `jitter`, from
+    /// 0 to 1, takes up to half of it off, so clients that failed together
+    /// don't all retry together.
+    pub fn delay(&self, retry: u32, jitter: f64) -> Duration {
+        let factor = 1u64.checked_shl(retry).unwrap_or(u64::MAX);
+        let delay = self
+            .initial_delay_ms
+            .saturating_mul(factor)
+            .min(self.max_delay_ms) as f64;
+        Duration::from_millis((delay * (1.0 - jitter / 2.0)) as u64)
+    }
+}
+
+/// What became of an update.
+#[derive(Debug)]
+pub enum Sent {
+    Delivered,
+    /// The server couldn't be reached, for this reason, so the update was
+    /// queued to be sent later.
+    Queued(TSError),
+}
+
+/// What pushing tasks sends first.
+#[derive(Debug)]
+pub enum Payload {
+    /// Everything, when there was no push to make a delta against.
+    All(Vec<Task>),
+    /// What changed since the last push. Should the server refuse it,
+    /// everything is sent after all.
+    Delta(TaskDelta),
+}
+
 #[derive(Debug)]
 pub struct Client {
     base_url: String,
-    api_key: String,
+    /// Only needed for writes, and to see the private tasks.
+    api_key: Option<String>,
+    encoding: Encoding,
+    retry: Retry,
+    redaction: Redaction,
+    /// Where the last push is kept, to send only what changed since.
+    cache: Option<PathBuf>,
+    outbox: Option<PathBuf>,
+}
+
+/// The tasks as last pushed, and the revision the server gave them.
+#[derive(Debug, Deserialize, Serialize)]
+struct LastPush {
+    server: String,
+    pushed: Pushed,
+    tasks: Vec<Task>,
+}
+
+impl LastPush {
+    /// The last push to `server`, if there is one that can be read.
+    fn load(path: &Path, server: &str) -> Option<Self> {
+        let last: LastPush = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| warn!("Ignoring unreadable {}: {}", path.display(), e))
+                .ok()?,
+            Err(_) => return None,
+        };
+
+        if last.server == server {
+            Some(last)
+        } else {
+            None
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
 }
 
 impl Client {
     pub fn new(config: Config) -> Self {
+        let base_url = config.client.server.unwrap();
+        // one per server, so pushing to several doesn't throw each away
+        let cache_name = format!("last-push-{}.json", file_name(&base_url));
+
         Client {
-            base_url: config.client.server.unwrap(),
-            api_key: config.client.api_key.unwrap(),
+            cache: dirs::cache_dir().map(|dir| dir.join("task-streamer").join(cache_name)),
+            base_url,
+            api_key: config.client.api_key,
+            encoding: config
+                .client
+                .format
+                .as_deref()
+                .and_then(Encoding::from_name)
+                .unwrap_or(Encoding::Json),
+            retry: config.client.retry,
+            redaction: config.client.redaction,
+            outbox: Outbox::default_path(),
         }
     }
 
+    /// The tasks as they would be pushed.
+    pub fn redact(&self, tasks: Vec<Task>) -> Vec<Task> {
+        self.redaction.apply(tasks)
+    }
+
+    /// What pushing `tasks` would send first, without sending anything.
+    pub fn payload(&self, tasks: &[Task]) -> Payload {
+        let tasks = self.redact(tasks.to_vec());
+        match self.last_push() {
+            Some(last) => Payload::Delta(TaskDelta::between(last.pushed, &last.tasks, &tasks)),
+            None => Payload::All(tasks),
+        }
+    }
+
+    /// The encoding pushes are sent in.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    fn last_push(&self) -> Option<LastPush> {
+        self.cache
+            .as_deref()
+            .and_then(|path| LastPush::load(path, &self.base_url))
+    }
+
     fn base_request(&self, verb: Verb, path: &str) -> reqwest::RequestBuilder {
-        let full_path = format!("{}/{}", self.base_url, path);
+        self.request(verb, &format!("{}/{}", self.base_url, path))
+    }
+
+    /// Like `base_request`, but on `/api/v2` when the server is given as
+    /// `/api/v1`, which neither takes deltas nor says what a push left the
+    /// tasks at.
+    fn v2_request(&self, verb: Verb, path: &str) -> reqwest::RequestBuilder {
+        let base_url = self.base_url.trim_end_matches('/');
+        let base_url = match base_url.strip_suffix(&format!("/api/v{}", SCHEMA_V1)) {
+            Some(root) => format!("{}/api/v{}", root, SCHEMA_V2),
+            None => base_url.to_string(),
+        };
+        self.request(verb, &format!("{}/{}", base_url, path))
+    }
+
+    fn request(&self, verb: Verb, full_path: &str) -> reqwest::RequestBuilder {
         let client = reqwest::Client::new();
 
         let builder = match verb {
-            Verb::POST => client.post(&full_path),
+            Verb::GET => client.get(full_path),
+            Verb::POST => client.post(full_path),
+        }
+        .timeout(Duration::from_secs(self.retry.timeout_secs));
+
+        match self.api_key {
+            Some(ref key) => builder.header("Authorization", format!("Bearer {}", key)),
+            None => builder,
+        }
+    }
+
+    /// Sends `update`, retrying as configured, and queues it in the outbox
+    /// if the server still can't be reached. Errors from the server itself,
+    /// like a wrong key, are given back rather than queued.
+    pub async fn send(&self, update: Update) -> Result<Sent> {
+        let kind = update.kind();
+        let result = match update {
+            Update::Tasks(ref tasks) => self.push_tasks(tasks).await,
+            Update::Topic(ref topic) => self.set_topic(topic).await,
+        };
+
+        match result {
+            Ok(()) => {
+                // whatever was queued before is out of date now
+                let removed = self.change_outbox(|outbox| outbox.remove(&self.base_url, kind));
+                if let Err(e) = removed {
+                    warn!("Could not update the outbox: {}", e);
+                }
+                Ok(Sent::Delivered)
+            }
+            Err(e) if self.outbox.is_some() && is_transient(&e) => {
+                match self.change_outbox(|outbox| {
+                    outbox.queue(&self.base_url, update);
+                    true
+                }) {
+                    Ok(()) => Ok(Sent::Queued(e)),
+                    Err(queue_error) => {
+                        warn!("Could not queue the {}: {}", kind, queue_error);
+                        Err(e)
+                    }
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Tries what is queued for this server once more, leaving what still
+    /// fails queued. `pushing` is skipped, as the caller is about to send a
+    /// newer one of it anyway. Gives how many were sent.
+    pub async fn replay_outbox(&self, pushing: Option<Kind>) -> Result<usize> {
+        let path = match self.outbox {
+            Some(ref path) => path,
+            None => return Ok(0),
+        };
+
+        let queued: Vec<_> = Outbox::load(path)?
+            .entries()
+            .iter()
+            .filter(|entry| entry.server == self.base_url && Some(entry.update.kind()) != pushing)
+            .cloned()
+            .collect();
+
+        let mut sent = Vec::new();
+        for entry in queued {
+            let result = match entry.update {
+                Update::Tasks(ref tasks) => self.push_tasks_once(tasks).await,
+                Update::Topic(ref topic) => self.set_topic_once(topic).await,
+            };
+            match result {
+                Ok(()) => sent.push(entry),
+                Err(e) => debug!("Could not send the queued {}: {}", entry.update, e),
+            }
+        }
+
+        if !sent.is_empty() {
+            // reread, as another client may have queued something since
+            Outbox::modify(path, |outbox| {
+                for entry in &sent {
+                    outbox.remove_sent(entry);
+                }
+                true
+            })?;
+        }
+        Ok(sent.len())
+    }
+
+    fn change_outbox(&self, change: impl FnOnce(&mut Outbox) -> bool) -> Result<()> {
+        match self.outbox {
+            Some(ref path) => Outbox::modify(path, change),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `attempt` until it works, fails for good, or runs out of
+    /// retries.
+    async fn retrying<T, F, Fut>(&self, mut attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retries = 0;
+        loop {
+            match attempt().await {
+                Err(e) if retries < self.retry.attempts && is_transient(&e) => {
+                    let delay = self.retry.delay(retries, rand::random());
+                    debug!("Retrying in {:?}: {}", delay, e);
+                    delay_for(delay).await;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The tasks as the server shows them to this client.
+    pub async fn get_tasks(&self) -> Result<Vec<Task>> {
+        self.retrying(|| self.get("tasks")).await
+    }
+
+    pub async fn get_topic(&self) -> Result<Topic> {
+        self.retrying(|| self.get("topic")).await
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let response = self
+            .base_request(Verb::GET, path)
+            .header(ACCEPT, self.encoding.mime())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        self.encoding.decode(&response.bytes().await?)
+    }
+
+    pub async fn push_tasks(&self, tasks: &[Task]) -> Result<()> {
+        self.retrying(|| self.push_tasks_once(tasks)).await
+    }
+
+    /// Pushes the tasks, sending only what changed since the last push when
+    /// the server still has that, and everything otherwise.
+    async fn push_tasks_once(&self, tasks: &[Task]) -> Result<()> {
+        let tasks = self.redact(tasks.to_vec());
+
+        let pushed = match self.last_push() {
+            Some(last) => {
+                let delta = TaskDelta::between(last.pushed, &last.tasks, &tasks);
+                match self.push_delta(&delta).await? {
+                    Some(pushed) => Some(pushed),
+                    None => self.push_all(&tasks).await?,
+                }
+            }
+            None => self.push_all(&tasks).await?,
         };
 
-        builder.header("Authorization", format!("Bearer {}", self.api_key))
+        if let Some(ref path) = self.cache {
+            let saved = match pushed {
+                Some(pushed) => LastPush {
+                    server: self.base_url.clone(),
+                    pushed,
+                    tasks,
+                }
+                .save(path),
+                // nothing to make the next delta against
+                None => fs::remove_file(path).or(Ok(())),
+            };
+            if let Err(e) = saved {
+                warn!("Could not update {}: {}", path.display(), e);
+            }
+        }
+        Ok(())
     }
 
-    pub async fn push_tasks(&self, task_client: &TaskClient) -> Result<()> {
+    /// Gives the revision the tasks are at now, if the server said.
+    async fn push_all(&self, tasks: &[Task]) -> Result<Option<Pushed>> {
+        let body = self.encoding.encode(&tasks)?;
         let response = self
-            .base_request(Verb::POST, "tasks")
-            .json(&task_client.tasks)
+            .v2_request(Verb::POST, "tasks")
+            .header(CONTENT_TYPE, self.encoding.mime())
+            .body(body.clone())
             .send()
             .await?;
 
-        response.error_for_status()?;
-        Ok(())
+        match response.status() {
+            // older servers only have v1, which doesn't say
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => {
+                debug!("No v2 push, got {}, pushing to v1", response.status());
+                self.base_request(Verb::POST, "tasks")
+                    .header(CONTENT_TYPE, self.encoding.mime())
+                    .body(body)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(None)
+            }
+            _ => Ok(response.error_for_status()?.json().await.ok()),
+        }
+    }
+
+    /// Gives `None` if the server can't apply `delta`, because the tasks
+    /// changed since or it doesn't take deltas at all.
+    async fn push_delta(&self, delta: &TaskDelta) -> Result<Option<Pushed>> {
+        let response = self
+            .v2_request(Verb::POST, "tasks/delta")
+            .header(CONTENT_TYPE, self.encoding.mime())
+            .body(self.encoding.encode(delta)?)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::CONFLICT | StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => {
+                debug!("Delta refused with {}, pushing everything", response.status());
+                Ok(None)
+            }
+            _ => Ok(Some(response.error_for_status()?.json().await?)),
+        }
     }
 
-    pub async fn set_topic(&self, topic: Topic) -> Result<()> {
+    /// Gives the topic as it is after the segment was marked done.
+    pub async fn complete_next_segment(&self) -> Result<Topic> {
+        let response = self
+            .base_request(Verb::POST, "topic/segments/next")
+            .send()
+            .await?;
+
+        Ok(response.error_for_status()?.json().await?)
+    }
+
+    pub async fn set_topic(&self, topic: &Topic) -> Result<()> {
+        self.retrying(|| self.set_topic_once(topic)).await
+    }
+
+    async fn set_topic_once(&self, topic: &Topic) -> Result<()> {
         let response = self
             .base_request(Verb::POST, "topic")
-            .json(&topic)
+            .json(topic)
             .send()
             .await?;
 
@@ -57,3 +438,109 @@ impl Client {
         Ok(())
     }
 }
+
+/// `url` with everything but letters and digits replaced, to name a file
+/// after.
+fn file_name(url: &str) -> String {
+    url.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Whether trying again might work: the server couldn't be reached, took too
+/// long or was having trouble, rather than refusing the update.
+fn is_transient(error: &TSError) -> bool {
+    match error {
+        TSError::RequestError(e) => {
+            e.is_connect()
+                || e.is_timeout()
+                || matches!(e.status(), Some(status)
+                    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_push_is_per_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache").join("last-push.json");
+        assert!(LastPush::load(&path, "http://localhost:8000/api/v1").is_none());
+
+        LastPush {
+            server: "http://localhost:8000/api/v1".to_string(),
+            pushed: Pushed {
+                epoch: 1,
+                revision: 2,
+            },
+            tasks: Vec::new(),
+        }
+        .save(&path)
+        .unwrap();
+
+        let last = LastPush::load(&path, "http://localhost:8000/api/v1").unwrap();
+        assert_eq!(last.pushed.revision, 2);
+        assert!(LastPush::load(&path, "http://example.com/api/v1").is_none());
+
+        fs::write(&path, "not json").unwrap();
+        assert!(LastPush::load(&path, "http://localhost:8000/api/v1").is_none());
+    }
+
+    #[test]
+    fn payloads_are_deltas_after_a_push() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.client.server = Some("http://localhost:8000/api/v1".to_string());
+        let client = Client {
+            cache: Some(dir.path().join("last-push.json")),
+            ..Client::new(config)
+        };
+        let tasks: Vec<Task> =
+            serde_json::from_str(r#"[{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20201118T071926Z","description":"write the overlay"}]"#)
+                .unwrap();
+
+        assert!(matches!(client.payload(&tasks), Payload::All(all) if all == tasks));
+
+        LastPush {
+            server: "http://localhost:8000/api/v1".to_string(),
+            pushed: Pushed {
+                epoch: 1,
+                revision: 2,
+            },
+            tasks: tasks.clone(),
+        }
+        .save(client.cache.as_deref().unwrap())
+        .unwrap();
+
+        match client.payload(&tasks) {
+            Payload::Delta(delta) => {
+                assert_eq!(delta.base_revision, 2);
+                assert!(delta.upserted.is_empty() && delta.removed.is_empty());
+            }
+            payload => panic!("expected a delta, got {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn naming_files_after_servers() {
+        assert_eq!(
+            file_name("http://127.0.0.1:8128/api/v1"),
+            "http___127_0_0_1_8128_api_v1"
+        );
+    }
+
+    #[test]
+    fn backing_off() {
+        let retry = Retry::default();
+        let delays: Vec<_> = (0..6).map(|n| retry.delay(n, 0.0).as_millis()).collect();
+        assert_eq!(delays, vec![500, 1000, 2000, 4000, 8000, 10_000]);
+
+        // at most half comes off
+        assert_eq!(retry.delay(1, 1.0), Duration::from_millis(500));
+        assert_eq!(retry.delay(200, 0.0), Duration::from_millis(10_000));
+    }
+}
@@ -1,11 +1,19 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsStr;
 use std::path::Path;
 
 use clap::ArgMatches;
 use serde::Deserialize;
 
-use crate::error::Result;
+use crate::client::Retry;
+use crate::error::{Result, TSError};
+use crate::filter::SortSpec;
+use crate::limits::Limits;
+use crate::privacy::Privacy;
+use crate::redact::Redaction;
+use crate::schema::UrgencyLevels;
+use crate::source::Source;
+use crate::taskwarrior::Taskwarrior;
 
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct Config {
@@ -13,6 +21,9 @@ pub struct Config {
     pub server: Server,
     #[serde(default)]
     pub client: Client,
+    /// Servers the client can push to instead, picked with `--profile`.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
@@ -20,6 +31,26 @@ pub struct Server {
     pub port: Option<String>,
     pub bind: Option<Vec<String>>,
     pub api_key: Option<String>,
+    pub static_dir: Option<String>,
+    /// Base url of a task-streamer to mirror instead of accepting writes.
+    pub upstream: Option<String>,
+    /// The upstream's api key, to mirror its private view, e.g. when it has
+    /// `private_stream` set.
+    pub upstream_key: Option<String>,
+    /// What anonymous clients don't get to see.
+    #[serde(default)]
+    pub redaction: Redaction,
+    #[serde(default)]
+    pub limits: Limits,
+    /// Refuse websocket sessions that don't present the api key.
+    #[serde(default)]
+    pub private_stream: bool,
+    /// Also serve the gRPC API on this port, if built with the `grpc`
+    /// feature.
+    pub grpc_port: Option<String>,
+    /// Where task views go from `low` to `medium` to `high` urgency.
+    #[serde(default)]
+    pub urgency_levels: UrgencyLevels,
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
@@ -27,10 +58,67 @@ pub struct Client {
     pub server: Option<String>,
     pub filter: Option<String>,
     pub api_key: Option<String>,
+    /// What `update` uploads tasks as: `json`, `msgpack` or `cbor`.
+    pub format: Option<String>,
+    /// How pushes are retried before being queued in the outbox.
+    #[serde(default)]
+    pub retry: Retry,
+    /// Trimmed from the tasks before they are pushed.
+    #[serde(default)]
+    pub redaction: Redaction,
+    /// Taken out of the tasks as soon as they are exported.
+    #[serde(default)]
+    pub privacy: Privacy,
+    /// What the exported tasks are sorted by, e.g. `active,urgency-,due+`.
+    pub sort: Option<SortSpec>,
+    /// Where the tasks are exported from: `taskwarrior`, `todotxt:PATH` or
+    /// `json:PATH`.
+    #[serde(default)]
+    pub source: Source,
+    /// How `task` is run.
+    #[serde(default)]
+    pub taskwarrior: Taskwarrior,
+    /// What the stream the server shows is called, for messages.
+    pub stream: Option<String>,
+}
+
+/// Stands in for the `[client]` settings it has.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Profile {
+    pub server: Option<String>,
+    pub api_key: Option<String>,
+    pub filter: Option<String>,
+    pub format: Option<String>,
+    pub redaction: Option<Redaction>,
+    pub privacy: Option<Privacy>,
+    pub sort: Option<SortSpec>,
+    pub stream: Option<String>,
+    pub source: Option<Source>,
+    pub taskwarrior: Option<Taskwarrior>,
 }
 
 impl Config {
     pub fn new(matches: &ArgMatches) -> Result<Self> {
+        let mut config = Config::load(matches)?;
+
+        if let Some(name) = matches.value_of("profile") {
+            config = config.profile(name)?;
+        }
+
+        if matches.is_present("api_key") {
+            let key = Some(matches.value_of("api_key").unwrap().to_string());
+            config.client.api_key = key.clone();
+            config.server.api_key = key;
+        }
+
+        Config::process_server_options(&mut config, matches);
+        Config::process_client_options(&mut config, matches);
+
+        Ok(config)
+    }
+
+    /// Only what the config files say, without any flags applied.
+    pub fn load(matches: &ArgMatches) -> Result<Self> {
         let mut config = ::config::Config::new();
 
         if matches.is_present("config") {
@@ -54,16 +142,44 @@ impl Config {
             }
         }
 
-        let mut config: Self = config.try_into()?;
+        Ok(config.try_into()?)
+    }
 
-        if matches.is_present("api_key") {
-            let key = Some(matches.value_of("api_key").unwrap().to_string());
-            config.client.api_key = key.clone();
-            config.server.api_key = key;
-        }
+    /// This config with the client settings of the profile `name` in place
+    /// of those in `[client]`.
+    pub fn profile(&self, name: &str) -> Result<Self> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| TSError::Error(format!("no profile named '{}'", name)))?;
 
-        Config::process_server_options(&mut config, matches);
-        Config::process_client_options(&mut config, matches);
+        let mut config = self.clone();
+        let client = &mut config.client;
+        let overlay = |setting: &mut Option<String>, value: &Option<String>| {
+            if value.is_some() {
+                *setting = value.clone();
+            }
+        };
+        overlay(&mut client.server, &profile.server);
+        overlay(&mut client.api_key, &profile.api_key);
+        overlay(&mut client.filter, &profile.filter);
+        overlay(&mut client.format, &profile.format);
+        overlay(&mut client.stream, &profile.stream);
+        if let Some(ref redaction) = profile.redaction {
+            client.redaction = redaction.clone();
+        }
+        if let Some(ref privacy) = profile.privacy {
+            client.privacy = privacy.clone();
+        }
+        if profile.sort.is_some() {
+            client.sort = profile.sort.clone();
+        }
+        if let Some(ref source) = profile.source {
+            client.source = source.clone();
+        }
+        if let Some(ref taskwarrior) = profile.taskwarrior {
+            client.taskwarrior = taskwarrior.clone();
+        }
 
         Ok(config)
     }
@@ -85,6 +201,21 @@ impl Config {
             config.server.bind = Some(interfaces.into_iter().map(|i| i.to_string()).collect());
         }
 
+        if matches.is_present("upstream") {
+            config.server.upstream = Some(matches.value_of("upstream").unwrap().to_string());
+        }
+
+        if matches.is_present("upstream_key") {
+            config.server.upstream_key = Some(matches.value_of("upstream_key").unwrap().to_string());
+        }
+
+        if matches.is_present("static_dir") {
+            config.server.static_dir = Some(matches.value_of("static_dir").unwrap().to_string());
+        }
+
+        if matches.is_present("grpc_port") {
+            config.server.grpc_port = Some(matches.value_of("grpc_port").unwrap().to_string());
+        }
     }
 
     pub fn process_client_options(config: &mut Config, matches: &ArgMatches) {
@@ -96,5 +227,91 @@ impl Config {
         if matches.is_present("server") {
             config.client.server = Some(matches.value_of("server").unwrap().to_string());
         }
+
+        if matches.is_present("format") {
+            config.client.format = Some(matches.value_of("format").unwrap().to_string());
+        }
+
+        if matches.is_present("encoding") {
+            config.client.format = Some(matches.value_of("encoding").unwrap().to_string());
+        }
+
+        if let Some(source) = matches.value_of("source") {
+            config.client.source = Source::parse(source).unwrap();
+        }
+
+        if let Some(sort) = matches.value_of("sort") {
+            config.client.sort = Some(SortSpec::parse(sort).unwrap());
+        }
+
+        if let Some(retries) = matches.value_of("retries") {
+            config.client.retry.attempts = retries.parse().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::config::{File, FileFormat};
+
+    fn parse(toml: &str) -> Config {
+        let mut config = ::config::Config::new();
+        config.merge(File::from_str(toml, FileFormat::Toml)).unwrap();
+        config.try_into().unwrap()
+    }
+
+    #[test]
+    fn profiles_stand_in_for_client_settings() {
+        let config = parse(
+            r#"
+            [client]
+            server = "http://localhost:8128/api/v1"
+            api_key = "dev"
+            filter = "status:pending"
+
+            [profiles.production]
+            server = "https://tasks.example.com/api/v1"
+            api_key = "secret"
+            stream = "Main stream"
+
+            [profiles.production.redaction]
+            exclude_tags = ["private"]
+
+            [profiles.production.privacy]
+            strip_annotations = true
+
+            [profiles.todo]
+            source = "todotxt:/home/me/todo.txt"
+            sort = "due+"
+
+            [profiles.work.taskwarrior]
+            data_dir = "/home/me/.task-work"
+            "#,
+        );
+
+        let production = config.profile("production").unwrap();
+        assert_eq!(
+            production.client.server.as_deref(),
+            Some("https://tasks.example.com/api/v1")
+        );
+        assert_eq!(production.client.api_key.as_deref(), Some("secret"));
+        assert_eq!(production.client.stream.as_deref(), Some("Main stream"));
+        assert_eq!(production.client.redaction.exclude_tags, vec!["private"]);
+        assert!(production.client.privacy.redaction.strip_annotations);
+        // what the profile leaves out comes from `[client]`
+        assert_eq!(production.client.filter.as_deref(), Some("status:pending"));
+
+        assert_eq!(production.client.source, Source::Taskwarrior);
+        assert!(production.client.sort.is_none());
+        let todo = config.profile("todo").unwrap();
+        assert_eq!(todo.client.source, Source::TodoTxt("/home/me/todo.txt".into()));
+        assert!(todo.client.sort.is_some());
+        assert_eq!(
+            config.profile("work").unwrap().client.taskwarrior.data_dir,
+            Some("/home/me/.task-work".into())
+        );
+
+        assert!(config.profile("staging").is_err());
     }
 }
@@ -0,0 +1,224 @@
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorBadRequest, ErrorUnsupportedMediaType};
+use actix_web::http::header;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::Result;
+
+/// The wire formats tasks and topics can be sent in. JSON is the default
+/// everywhere; the binary ones are for small devices that would rather not
+/// parse it.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    pub const ALL: &'static [Encoding] = &[Encoding::Json, Encoding::MessagePack, Encoding::Cbor];
+
+    /// The name used by the client's `--format` and in `ETag`s.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Encoding::ALL.iter().copied().find(|e| e.name() == name)
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::MessagePack => "application/msgpack",
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+
+    /// Matches a media type, ignoring any parameters, including the
+    /// unofficial names MessagePack goes by.
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        let mime = mime.split(';').next().unwrap_or_default().trim();
+
+        match mime.to_ascii_lowercase().as_str() {
+            "application/json" => Some(Encoding::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Encoding::MessagePack)
+            }
+            "application/cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The encoding a request body is in. Bodies without a `Content-Type`
+    /// are taken to be JSON.
+    pub fn from_content_type(content_type: Option<&str>) -> Option<Self> {
+        match content_type {
+            Some(content_type) => Encoding::from_mime(content_type),
+            None => Some(Encoding::Json),
+        }
+    }
+
+    /// Picks the encoding an `Accept` header prefers, or `None` if it
+    /// accepts none of them. Wildcards get JSON.
+    pub fn negotiate(accept: Option<&str>) -> Option<Self> {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Some(Encoding::Json),
+        };
+
+        let mut ranges: Vec<(f32, &str)> = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let mime = parts.next()?.trim();
+                let quality = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse().ok())
+                    .unwrap_or(1.0);
+                Some((quality, mime))
+            })
+            .filter(|(quality, _)| *quality > 0.0)
+            .collect();
+
+        // stable, so equally preferred ranges keep the client's order
+        ranges.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        ranges.into_iter().find_map(|(_, mime)| match mime {
+            "*/*" | "application/*" => Some(Encoding::Json),
+            mime => Encoding::from_mime(mime),
+        })
+    }
+
+    /// The websocket subprotocol asking for frames in this encoding. JSON
+    /// goes out as text frames and the others as binary frames.
+    pub fn ws_protocol(self) -> &'static str {
+        match self {
+            Encoding::Json => "task-streamer",
+            Encoding::MessagePack => "task-streamer.msgpack",
+            Encoding::Cbor => "task-streamer.cbor",
+        }
+    }
+
+    /// The encoding of the first of a client's `Sec-WebSocket-Protocol`s
+    /// that names one, if any do.
+    pub fn from_ws_protocols(protocols: Option<&str>) -> Option<Self> {
+        protocols
+            .unwrap_or_default()
+            .split(',')
+            .find_map(|protocol| {
+                Encoding::ALL
+                    .iter()
+                    .copied()
+                    .find(|e| e.ws_protocol() == protocol.trim())
+            })
+    }
+
+    pub fn is_binary(self) -> bool {
+        self != Encoding::Json
+    }
+
+    // The binary formats aren't "human readable" to serde, so left to
+    // themselves uuids and the like come out as byte strings. Going through
+    // a JSON value keeps every encoding carrying the same structures.
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Json => serde_json::to_vec(value)?,
+            // as maps rather than arrays, so fields are found by name
+            Encoding::MessagePack => rmp_serde::to_vec_named(&serde_json::to_value(value)?)?,
+            Encoding::Cbor => serde_cbor::to_vec(&serde_json::to_value(value)?)?,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, data: &[u8]) -> Result<T> {
+        let value: Value = match self {
+            Encoding::Json => return Ok(serde_json::from_slice(data)?),
+            Encoding::MessagePack => rmp_serde::from_slice(data)?,
+            Encoding::Cbor => serde_cbor::from_slice(data)?,
+        };
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+/// Extracts a request body in whichever encoding its `Content-Type` names.
+pub struct Encoded<T>(pub T);
+
+impl<T: DeserializeOwned + 'static> FromRequest for Encoded<T> {
+    type Config = ();
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, std::result::Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        let encoding = Encoding::from_content_type(content_type);
+        let body = web::Bytes::from_request(req, payload);
+
+        async move {
+            let encoding =
+                encoding.ok_or_else(|| ErrorUnsupportedMediaType("unsupported content type"))?;
+            let body = body.await?;
+
+            encoding.decode(&body).map(Encoded).map_err(ErrorBadRequest)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Topic;
+
+    #[test]
+    fn negotiating() {
+        assert_eq!(Encoding::negotiate(None), Some(Encoding::Json));
+        assert_eq!(
+            Encoding::negotiate(Some("application/msgpack")),
+            Some(Encoding::MessagePack)
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("application/json;q=0.5, application/cbor")),
+            Some(Encoding::Cbor)
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("text/html, */*;q=0.8")),
+            Some(Encoding::Json)
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("application/cbor;q=0, text/plain")),
+            None
+        );
+    }
+
+    #[test]
+    fn websocket_protocols() {
+        assert_eq!(Encoding::from_ws_protocols(None), None);
+        assert_eq!(Encoding::from_ws_protocols(Some("bearer.abc")), None);
+        assert_eq!(
+            Encoding::from_ws_protocols(Some("bearer.abc, task-streamer.cbor, task-streamer")),
+            Some(Encoding::Cbor)
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let topic = Topic::new("title".to_string(), "description".to_string());
+
+        for encoding in Encoding::ALL {
+            let encoded = encoding.encode(&topic).unwrap();
+            assert_eq!(encoding.decode::<Topic>(&encoded).unwrap(), topic);
+        }
+    }
+}
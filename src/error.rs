@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use actix_web_actors::ws::ProtocolError;
 use reqwest;
 
@@ -6,27 +8,63 @@ pub type Result<T> = std::result::Result<T, TSError>;
 /// TSError enumerates all possible errors returned by this library
 #[derive(Debug)]
 pub enum TSError {
+    /// Represents all other cases of serde_cbor::Error
+    CborError(serde_cbor::Error),
+
     ConfigError(::config::ConfigError),
     Error(String),
 
     /// Represents all other cases of IO Error
     IOError(std::io::Error),
 
+    /// Represents all other cases of serde_json::Error
+    JsonError(serde_json::Error),
+
+    /// Represents all other cases of rmp_serde::decode::Error
+    MessagePackDecodeError(rmp_serde::decode::Error),
+
+    /// Represents all other cases of rmp_serde::encode::Error
+    MessagePackEncodeError(rmp_serde::encode::Error),
+
+    /// Represents all other cases of notify::Error
+    NotifyError(notify::Error),
+
     /// Represents all other cases of websocket ProtocolError
     ProtocolError(ProtocolError),
 
     /// Represents all other cases of reqwest::Error
     RequestError(reqwest::Error),
+
+    /// A taskwarrior command that failed, with its exit code, if it wasn't
+    /// killed, and what it printed to stderr
+    TaskError {
+        command: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+
+    /// A taskwarrior command that was killed for running past its timeout
+    TaskTimeout {
+        command: String,
+        timeout: Duration,
+    },
 }
 
 impl std::error::Error for TSError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
+            TSError::CborError(ref err) => Some(err),
             TSError::ConfigError(ref err) => Some(err),
             TSError::Error(_) => None,
             TSError::IOError(ref err) => Some(err),
+            TSError::JsonError(ref err) => Some(err),
+            TSError::MessagePackDecodeError(ref err) => Some(err),
+            TSError::MessagePackEncodeError(ref err) => Some(err),
+            TSError::NotifyError(ref err) => Some(err),
             TSError::ProtocolError(ref err) => Some(err),
             TSError::RequestError(ref err) => Some(err),
+            TSError::TaskError { .. } => None,
+            TSError::TaskTimeout { .. } => None,
         }
     }
 }
@@ -34,15 +72,44 @@ impl std::error::Error for TSError {
 impl std::fmt::Display for TSError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match *self {
+            TSError::CborError(ref err) => err.fmt(f),
             TSError::ConfigError(ref err) => err.fmt(f),
             TSError::Error(ref msg) => write!(f, "{}", msg),
             TSError::IOError(ref err) => err.fmt(f),
+            TSError::JsonError(ref err) => err.fmt(f),
+            TSError::MessagePackDecodeError(ref err) => err.fmt(f),
+            TSError::MessagePackEncodeError(ref err) => err.fmt(f),
+            TSError::NotifyError(ref err) => err.fmt(f),
             TSError::ProtocolError(ref err) => err.fmt(f),
             TSError::RequestError(ref err) => err.fmt(f),
+            TSError::TaskError {
+                ref command,
+                code,
+                ref stderr,
+            } => {
+                match code {
+                    Some(code) => write!(f, "`{}` exited with {}", command, code)?,
+                    None => write!(f, "`{}` was killed", command)?,
+                }
+                if !stderr.is_empty() {
+                    write!(f, ": {}", stderr)?;
+                }
+                Ok(())
+            }
+            TSError::TaskTimeout {
+                ref command,
+                timeout,
+            } => write!(f, "`{}` took longer than {}s", command, timeout.as_secs()),
         }
     }
 }
 
+impl From<serde_cbor::Error> for TSError {
+    fn from(err: serde_cbor::Error) -> TSError {
+        TSError::CborError(err)
+    }
+}
+
 impl From<::config::ConfigError> for TSError {
     fn from(err: ::config::ConfigError) -> TSError {
         TSError::ConfigError(err)
@@ -55,6 +122,30 @@ impl From<std::io::Error> for TSError {
     }
 }
 
+impl From<serde_json::Error> for TSError {
+    fn from(err: serde_json::Error) -> TSError {
+        TSError::JsonError(err)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for TSError {
+    fn from(err: rmp_serde::decode::Error) -> TSError {
+        TSError::MessagePackDecodeError(err)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for TSError {
+    fn from(err: rmp_serde::encode::Error) -> TSError {
+        TSError::MessagePackEncodeError(err)
+    }
+}
+
+impl From<notify::Error> for TSError {
+    fn from(err: notify::Error) -> TSError {
+        TSError::NotifyError(err)
+    }
+}
+
 impl From<ProtocolError> for TSError {
     fn from(err: ProtocolError) -> TSError {
         TSError::ProtocolError(err)
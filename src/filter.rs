@@ -0,0 +1,458 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+use task_hookrs::task::Task;
+use task_hookrs::uda::UDAValue;
+
+use crate::redact::{has_any_tag, in_projects};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortBy {
+    /// Started tasks first.
+    Active,
+    /// Most urgent first.
+    Urgency,
+    /// Oldest first.
+    Entry,
+    /// Soonest first, tasks without a due date last.
+    Due,
+    /// By name, tasks without a project last.
+    Project,
+    Description,
+}
+
+impl SortBy {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "active" => Some(SortBy::Active),
+            "urgency" => Some(SortBy::Urgency),
+            "entry" => Some(SortBy::Entry),
+            "due" => Some(SortBy::Due),
+            "project" => Some(SortBy::Project),
+            "description" => Some(SortBy::Description),
+            _ => None,
+        }
+    }
+
+    fn compare(self, a: &Task, b: &Task) -> Ordering {
+        SortKey::from(self).compare(a, b)
+    }
+
+    /// The way it sorts unless told otherwise, as documented on each.
+    fn natural(self) -> Direction {
+        match self {
+            SortBy::Active | SortBy::Urgency => Direction::Descending,
+            SortBy::Entry | SortBy::Due | SortBy::Project | SortBy::Description => {
+                Direction::Ascending
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+impl Direction {
+    fn apply(self, ordering: Ordering) -> Ordering {
+        match self {
+            Direction::Ascending => ordering,
+            Direction::Descending => ordering.reverse(),
+        }
+    }
+}
+
+/// One key of a `SortSpec`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SortKey {
+    pub by: SortBy,
+    pub direction: Direction,
+}
+
+impl From<SortBy> for SortKey {
+    fn from(by: SortBy) -> Self {
+        SortKey {
+            by,
+            direction: by.natural(),
+        }
+    }
+}
+
+impl SortKey {
+    /// Reads `field`, sorted its natural way, or `field+`/`field-` for
+    /// ascending/descending.
+    fn parse(key: &str) -> Option<Self> {
+        let (name, direction) = match key.strip_suffix('+') {
+            Some(name) => (name, Some(Direction::Ascending)),
+            None => match key.strip_suffix('-') {
+                Some(name) => (name, Some(Direction::Descending)),
+                None => (key, None),
+            },
+        };
+
+        let by = SortBy::parse(name)?;
+        Some(SortKey {
+            by,
+            direction: direction.unwrap_or_else(|| by.natural()),
+        })
+    }
+
+    /// Tasks missing what is sorted by go last whichever way it sorts.
+    fn compare(self, a: &Task, b: &Task) -> Ordering {
+        fn missing_last<T: Ord>(a: Option<T>, b: Option<T>, direction: Direction) -> Ordering {
+            match (a, b) {
+                (Some(a), Some(b)) => direction.apply(a.cmp(&b)),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            }
+        }
+
+        let ascending = match self.by {
+            SortBy::Active => a.start().is_some().cmp(&b.start().is_some()),
+            SortBy::Urgency => urgency(a)
+                .partial_cmp(&urgency(b))
+                .unwrap_or(Ordering::Equal),
+            SortBy::Entry => a.entry().cmp(b.entry()),
+            SortBy::Due => {
+                return missing_last(
+                    a.due().map(|due| **due),
+                    b.due().map(|due| **due),
+                    self.direction,
+                )
+            }
+            SortBy::Project => return missing_last(a.project(), b.project(), self.direction),
+            SortBy::Description => a.description().cmp(b.description()),
+        };
+        self.direction.apply(ascending)
+    }
+}
+
+/// Keys to sort by, each breaking the ties of those before it. Tasks tied on
+/// all of them keep the order they were in.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SortSpec(pub Vec<SortKey>);
+
+impl Default for SortSpec {
+    /// Started tasks first, and otherwise as exported.
+    fn default() -> Self {
+        SortSpec(vec![SortBy::Active.into()])
+    }
+}
+
+impl SortSpec {
+    /// Reads comma separated keys, as in taskwarrior's report sorts:
+    /// `active,urgency-,due+`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        spec.split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| SortKey::parse(key).ok_or_else(|| format!("unknown sort key {}", key)))
+            .collect::<Result<_, _>>()
+            .map(SortSpec)
+    }
+
+    pub fn compare(&self, a: &Task, b: &Task) -> Ordering {
+        self.0
+            .iter()
+            .map(|key| key.compare(a, b))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+
+    pub fn sort(&self, tasks: &mut [Task]) {
+        tasks.sort_by(|a, b| self.compare(a, b));
+    }
+}
+
+impl TryFrom<String> for SortSpec {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, Self::Error> {
+        SortSpec::parse(&spec)
+    }
+}
+
+/// The slice of the tasks a websocket session wants. The default matches
+/// everything and leaves the order alone.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TaskFilter {
+    /// Only tasks in these projects or their sub-projects.
+    pub projects: Vec<String>,
+    /// Only tasks with any of these tags.
+    pub tags: Vec<String>,
+    /// Only tasks with any of these statuses, e.g. `pending`.
+    pub status: Vec<String>,
+    pub sort: Option<SortBy>,
+    /// At most this many tasks, after sorting.
+    pub limit: Option<usize>,
+}
+
+impl TaskFilter {
+    /// Reads a filter from websocket query parameters, where lists are
+    /// comma separated: `?projects=a,b&status=pending&sort=urgency&limit=5`.
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self, String> {
+        let list = |key: &str| -> Vec<String> {
+            query
+                .get(key)
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|v| !v.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        let sort = match query.get("sort") {
+            Some(sort) => {
+                Some(SortBy::parse(sort).ok_or_else(|| format!("unknown sort {}", sort))?)
+            }
+            None => None,
+        };
+
+        let limit = match query.get("limit") {
+            Some(limit) => Some(
+                limit
+                    .parse()
+                    .map_err(|_| format!("invalid limit {}", limit))?,
+            ),
+            None => None,
+        };
+
+        Ok(TaskFilter {
+            projects: list("projects"),
+            tags: list("tags"),
+            status: list("status"),
+            sort,
+            limit,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == TaskFilter::default()
+    }
+
+    pub fn matches(&self, task: &Task) -> bool {
+        in_projects(task, &self.projects)
+            && (self.tags.is_empty() || has_any_tag(task, &self.tags))
+            && (self.status.is_empty() || self.status.iter().any(|s| has_status(task, s)))
+    }
+
+    pub fn apply(&self, tasks: &[Task]) -> Vec<Task> {
+        let mut slice: Vec<Task> = tasks.iter().filter(|t| self.matches(t)).cloned().collect();
+
+        if let Some(sort) = self.sort {
+            // stable, so ties keep the order they were pushed in
+            slice.sort_by(|a, b| sort.compare(a, b));
+        }
+
+        if let Some(limit) = self.limit {
+            slice.truncate(limit);
+        }
+
+        slice
+    }
+}
+
+/// The status as taskwarrior writes it, e.g. `pending`.
+pub(crate) fn status_name(task: &Task) -> Option<String> {
+    // `TaskStatus` displays capitalized, but serializes as taskwarrior does
+    match serde_json::to_value(task.status()) {
+        Ok(serde_json::Value::String(s)) => Some(s),
+        _ => None,
+    }
+}
+
+pub(crate) fn has_status(task: &Task, status: &str) -> bool {
+    matches!(status_name(task), Some(s) if s.eq_ignore_ascii_case(status))
+}
+
+pub(crate) fn urgency(task: &Task) -> f64 {
+    match task.uda().get("urgency") {
+        Some(UDAValue::F64(urgency)) => *urgency,
+        Some(UDAValue::U64(urgency)) => *urgency as f64,
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tasks() -> Vec<Task> {
+        let task_json = r#"
+        [{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20201118T071926Z","description":"figure out frontend static asset storage/serving","tags":["@stream","@home"],"project":"twitch.task-display","urgency":2.15},
+         {"status":"completed","uuid":"8699cf59-59d4-4f42-812d-0d2de0cad191","entry":"20201120T064735Z","description":"add tests","tags":["@stream"],"project":"twitch.task-display","urgency":0.5},
+         {"status":"pending","uuid":"6c2b9f0f-10a2-4e36-8f13-c160e7dbc3cb","entry":"20201125T062735Z","description":"call the bank","tags":["@home"],"project":"personal","urgency":4},
+         {"status":"pending","uuid":"02cb9bfc-fa96-4293-a71e-b833ca3e8795","entry":"20201127T085138Z","description":"display controls on hover","tags":["@stream","next"],"project":"twitch.task-display","urgency":17}]
+        "#;
+        serde_json::from_str(task_json).unwrap()
+    }
+
+    fn descriptions(tasks: &[Task]) -> Vec<&str> {
+        tasks.iter().map(|t| t.description().as_str()).collect()
+    }
+
+    #[test]
+    fn empty_filter_keeps_everything() {
+        assert!(TaskFilter::default().is_empty());
+        assert_eq!(TaskFilter::default().apply(&tasks()), tasks());
+    }
+
+    #[test]
+    fn filtering_sorting_and_limiting() {
+        let filter = TaskFilter {
+            projects: vec!["twitch".to_string()],
+            status: vec!["pending".to_string()],
+            sort: Some(SortBy::Urgency),
+            limit: Some(1),
+            ..TaskFilter::default()
+        };
+        assert_eq!(
+            descriptions(&filter.apply(&tasks())),
+            vec!["display controls on hover"]
+        );
+
+        let filter = TaskFilter {
+            tags: vec!["@home".to_string()],
+            sort: Some(SortBy::Description),
+            ..TaskFilter::default()
+        };
+        assert_eq!(
+            descriptions(&filter.apply(&tasks())),
+            vec![
+                "call the bank",
+                "figure out frontend static asset storage/serving"
+            ]
+        );
+    }
+
+    fn sorting_tasks() -> Vec<Task> {
+        let task_json = r#"
+        [{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20201118T071926Z","description":"figure out frontend static asset storage/serving","project":"twitch.task-display","urgency":2.15},
+         {"status":"pending","uuid":"8699cf59-59d4-4f42-812d-0d2de0cad191","entry":"20201120T064735Z","description":"add tests","project":"twitch.task-display","urgency":4,"due":"20201201T000000Z"},
+         {"status":"pending","uuid":"6c2b9f0f-10a2-4e36-8f13-c160e7dbc3cb","entry":"20201125T062735Z","description":"call the bank","project":"personal","urgency":4,"due":"20201130T000000Z"},
+         {"status":"pending","uuid":"02cb9bfc-fa96-4293-a71e-b833ca3e8795","entry":"20201127T085138Z","description":"display controls on hover","urgency":1,"start":"20201128T085138Z"},
+         {"status":"pending","uuid":"d1b4a190-d4fc-4d8f-aa88-a53398a8b17c","entry":"20201230T074345Z","description":"fix styling everywhere","project":"twitch.task-display","urgency":4}]
+        "#;
+        serde_json::from_str(task_json).unwrap()
+    }
+
+    fn sorted(spec: &str) -> Vec<String> {
+        let mut tasks = sorting_tasks();
+        SortSpec::parse(spec).unwrap().sort(&mut tasks);
+        tasks.iter().map(|t| t.description().clone()).collect()
+    }
+
+    #[test]
+    fn sorting_by_several_keys() {
+        // ties on urgency go to the soonest due, then to export order
+        assert_eq!(
+            sorted("active,urgency-,due+"),
+            vec![
+                "display controls on hover",
+                "call the bank",
+                "add tests",
+                "fix styling everywhere",
+                "figure out frontend static asset storage/serving"
+            ]
+        );
+
+        // grouped by project, tasks without one last, most urgent first
+        assert_eq!(
+            sorted("project,urgency"),
+            vec![
+                "call the bank",
+                "add tests",
+                "fix styling everywhere",
+                "figure out frontend static asset storage/serving",
+                "display controls on hover"
+            ]
+        );
+    }
+
+    #[test]
+    fn sorting_directions() {
+        assert_eq!(sorted("urgency+")[0], "display controls on hover");
+        assert_eq!(sorted("urgency-")[0], "add tests");
+
+        // without a due date is last both ways
+        let descending = sorted("due-");
+        assert_eq!(&descending[..2], &["add tests", "call the bank"]);
+        assert_eq!(&sorted("due+")[..2], &["call the bank", "add tests"]);
+        assert_eq!(descending[4], "fix styling everywhere");
+    }
+
+    #[test]
+    fn default_sort_puts_started_tasks_first() {
+        let mut tasks = sorting_tasks();
+        SortSpec::default().sort(&mut tasks);
+        assert_eq!(tasks[0].description(), "display controls on hover");
+        assert_eq!(&tasks[1..4], &sorting_tasks()[..3]);
+        assert_eq!(tasks[4], sorting_tasks()[4]);
+    }
+
+    #[test]
+    fn parsing_sort_specs() {
+        assert_eq!(
+            SortSpec::parse("active, urgency-,due+").unwrap(),
+            SortSpec(vec![
+                SortKey {
+                    by: SortBy::Active,
+                    direction: Direction::Descending
+                },
+                SortKey {
+                    by: SortBy::Urgency,
+                    direction: Direction::Descending
+                },
+                SortKey {
+                    by: SortBy::Due,
+                    direction: Direction::Ascending
+                },
+            ])
+        );
+        assert!(SortSpec::parse("urgency,vibes").is_err());
+
+        let spec: SortSpec = serde_json::from_str(r#""project,entry-""#).unwrap();
+        assert_eq!(spec.0.len(), 2);
+        assert!(serde_json::from_str::<SortSpec>(r#""vibes""#).is_err());
+    }
+
+    #[test]
+    fn parsing_query_parameters() {
+        let query: HashMap<String, String> = vec![
+            ("projects", "twitch, personal"),
+            ("status", "pending"),
+            ("sort", "due"),
+            ("limit", "3"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        assert_eq!(
+            TaskFilter::from_query(&query).unwrap(),
+            TaskFilter {
+                projects: vec!["twitch".to_string(), "personal".to_string()],
+                status: vec!["pending".to_string()],
+                sort: Some(SortBy::Due),
+                limit: Some(3),
+                ..TaskFilter::default()
+            }
+        );
+
+        let mut bad = query.clone();
+        bad.insert("sort".to_string(), "vibes".to_string());
+        assert!(TaskFilter::from_query(&bad).is_err());
+    }
+}
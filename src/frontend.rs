@@ -0,0 +1,142 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use actix_web::http::header::{
+    CacheControl, CacheDirective, ContentType, HttpDate, IfModifiedSince, LastModified,
+};
+use actix_web::http::Method;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use log::error;
+use percent_encoding::percent_decode_str;
+
+const INDEX: &str = "index.html";
+
+/// How long browsers may cache anything other than `index.html`, in seconds.
+const ASSET_MAX_AGE: u32 = 3600;
+
+/// Unmatched paths under these prefixes are 404s rather than frontend routes.
+const RESERVED_PREFIXES: &[&str] = &["/api/", "/ws/"];
+
+/// Directory holding the frontend bundle, mounted at `/`.
+#[derive(Clone, Debug)]
+pub struct StaticDir(pub PathBuf);
+
+struct Asset {
+    path: PathBuf,
+    contents: Vec<u8>,
+    modified: Option<SystemTime>,
+}
+
+/// Serves files from the `StaticDir` in the app data.
+///
+/// Meant to be the app's default service, so it only sees requests no API
+/// or websocket route matched. Extension-less paths that don't exist fall
+/// back to `index.html` so the frontend can do its own routing.
+pub async fn serve(req: HttpRequest, dir: web::Data<StaticDir>) -> HttpResponse {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return HttpResponse::MethodNotAllowed().finish();
+    }
+
+    let path = req.path().to_string();
+
+    if RESERVED_PREFIXES.iter().any(|p| path.starts_with(p)) {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let root = dir.0.clone();
+    let asset = match web::block(move || load(&root, &path)).await {
+        Ok(Some(asset)) => asset,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Could not read static file: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let cache = if asset.path.ends_with(INDEX) {
+        // always revalidate, so a new deploy is picked up straight away
+        CacheControl(vec![CacheDirective::NoCache])
+    } else {
+        CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(ASSET_MAX_AGE),
+        ])
+    };
+
+    let mut response = HttpResponse::Ok();
+    response.set(cache);
+
+    if let Some(modified) = asset.modified {
+        let modified = HttpDate::from(modified);
+
+        if let Some(IfModifiedSince(since)) = req.get_header::<IfModifiedSince>() {
+            if modified <= since {
+                return HttpResponse::NotModified()
+                    .set(LastModified(modified))
+                    .finish();
+            }
+        }
+
+        response.set(LastModified(modified));
+    }
+
+    response
+        .set(ContentType(
+            mime_guess::from_path(&asset.path).first_or_octet_stream(),
+        ))
+        .body(asset.contents)
+}
+
+fn load(root: &Path, request_path: &str) -> io::Result<Option<Asset>> {
+    let path = match resolve(root, request_path) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let path = if path.is_file() {
+        path
+    } else if path.join(INDEX).is_file() {
+        path.join(INDEX)
+    } else if path.extension().is_none() {
+        root.join(INDEX)
+    } else {
+        return Ok(None);
+    };
+
+    if !path.is_file() || !within(root, &path)? {
+        return Ok(None);
+    }
+
+    let modified = fs::metadata(&path)?.modified().ok();
+    let contents = fs::read(&path)?;
+
+    Ok(Some(Asset {
+        path,
+        contents,
+        modified,
+    }))
+}
+
+/// Whether `path` is still under `root` once symlinks are followed.
+fn within(root: &Path, path: &Path) -> io::Result<bool> {
+    Ok(path.canonicalize()?.starts_with(root.canonicalize()?))
+}
+
+/// Maps a request path onto `root`, refusing anything that could escape it
+/// or that names a hidden file. Symlinks are left to `within`.
+fn resolve(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(request_path).decode_utf8().ok()?;
+    let mut path = root.to_path_buf();
+
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) if !part.to_string_lossy().starts_with('.') => path.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    Some(path)
+}
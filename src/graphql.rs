@@ -0,0 +1,482 @@
+//! A GraphQL schema over the topic and tasks, for frontends that would rather
+//! pick the fields they render. Queries are POSTed to `/graphql`, and
+//! subscriptions are served on the same path over either websocket protocol:
+//! `graphql-transport-ws`, or the older `graphql-ws`.
+//!
+//! Subscriptions are fed by the same notifications as the websocket
+//! sessions, through a `Watch` channel on the `SessionManager`.
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use async_graphql::http::{WebSocket, WebSocketProtocols, WsMessage};
+use async_graphql::{
+    Context, Data, EmptyMutation, Enum, Error, InputObject, Object, Result, Schema, Subscription,
+};
+use futures::channel::mpsc;
+use futures::future::ready;
+use futures::{Stream, StreamExt};
+use log::info;
+use serde_json::Value;
+use std::sync::Arc;
+use task_hookrs::task::Task;
+
+use crate::app::{ws_token, AppState};
+use crate::filter::{status_name, urgency, SortBy, TaskFilter};
+use crate::limits::{self, SessionPermit};
+use crate::schema::{format_date, is_active, Link, Segment, Topic};
+use crate::session::{
+    TopicUpdated, Update, View, Watch, CLIENT_TIMEOUT, HEARTBEAT_INTERVAL, WATCH_CAPACITY,
+};
+use crate::snapshot::Snapshot;
+
+pub type TaskSchema = Schema<Query, EmptyMutation, Subscription>;
+
+pub fn schema() -> TaskSchema {
+    Schema::new(Query, EmptyMutation, Subscription)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Enum)]
+enum TaskSort {
+    /// Started tasks first.
+    Active,
+    /// Most urgent first.
+    Urgency,
+    /// Oldest first.
+    Entry,
+    /// Soonest first, tasks without a due date last.
+    Due,
+    /// By name, tasks without a project last.
+    Project,
+    Description,
+}
+
+impl From<TaskSort> for SortBy {
+    fn from(sort: TaskSort) -> Self {
+        match sort {
+            TaskSort::Active => SortBy::Active,
+            TaskSort::Urgency => SortBy::Urgency,
+            TaskSort::Entry => SortBy::Entry,
+            TaskSort::Due => SortBy::Due,
+            TaskSort::Project => SortBy::Project,
+            TaskSort::Description => SortBy::Description,
+        }
+    }
+}
+
+/// Which tasks, and in what order. Each list matches tasks matching any of
+/// its entries.
+#[derive(Default, InputObject)]
+#[graphql(name = "TaskFilter")]
+struct FilterInput {
+    /// Projects, including their sub-projects.
+    #[graphql(default)]
+    projects: Vec<String>,
+    #[graphql(default)]
+    tags: Vec<String>,
+    /// Statuses as taskwarrior writes them, e.g. `pending`.
+    #[graphql(default)]
+    status: Vec<String>,
+    sort: Option<TaskSort>,
+    /// At most this many tasks, after sorting.
+    limit: Option<usize>,
+}
+
+impl From<FilterInput> for TaskFilter {
+    fn from(filter: FilterInput) -> Self {
+        TaskFilter {
+            projects: filter.projects,
+            tags: filter.tags,
+            status: filter.status,
+            sort: filter.sort.map(SortBy::from),
+            limit: filter.limit,
+        }
+    }
+}
+
+struct TaskObject(Task);
+
+#[Object(name = "Task")]
+impl TaskObject {
+    async fn uuid(&self) -> String {
+        self.0.uuid().to_string()
+    }
+
+    async fn description(&self) -> &str {
+        self.0.description()
+    }
+
+    async fn status(&self) -> Option<String> {
+        status_name(&self.0)
+    }
+
+    async fn project(&self) -> Option<&str> {
+        self.0.project().map(String::as_str)
+    }
+
+    async fn tags(&self) -> Vec<&str> {
+        self.0
+            .tags()
+            .map(|tags| tags.iter().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    async fn urgency(&self) -> f64 {
+        urgency(&self.0)
+    }
+
+    async fn entry(&self) -> String {
+        format_date(self.0.entry())
+    }
+
+    async fn start(&self) -> Option<String> {
+        self.0.start().map(format_date)
+    }
+
+    async fn due(&self) -> Option<String> {
+        self.0.due().map(format_date)
+    }
+
+    /// Started, and not yet done.
+    async fn is_active(&self) -> bool {
+        is_active(&self.0)
+    }
+
+    /// Seconds since the task was entered.
+    async fn age(&self) -> i64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as i64)
+            .unwrap_or_default();
+
+        (now - self.0.entry().timestamp()).max(0)
+    }
+}
+
+struct TopicObject(Arc<Snapshot<Topic>>);
+
+#[Object(name = "Topic")]
+impl TopicObject {
+    async fn title(&self) -> &str {
+        &self.0.value.title
+    }
+
+    async fn description(&self) -> &str {
+        &self.0.value.description
+    }
+
+    async fn links(&self) -> &[Link] {
+        &self.0.value.links
+    }
+
+    async fn tags(&self) -> &[String] {
+        &self.0.value.tags
+    }
+
+    async fn started_at(&self) -> Option<String> {
+        self.0.value.started_at.map(|time| time.to_rfc3339())
+    }
+
+    async fn goal(&self) -> Option<&str> {
+        self.0.value.goal.as_deref()
+    }
+
+    async fn segments(&self) -> &[Segment] {
+        &self.0.value.segments
+    }
+
+    async fn revision(&self) -> u64 {
+        self.0.revision
+    }
+}
+
+#[Object]
+impl Link {
+    async fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+}
+
+#[Object]
+impl Segment {
+    async fn title(&self) -> &str {
+        &self.title
+    }
+
+    async fn done(&self) -> bool {
+        self.done
+    }
+}
+
+fn state<'a>(ctx: &Context<'a>) -> Result<&'a web::Data<AppState>> {
+    ctx.data::<web::Data<AppState>>()
+}
+
+/// Readers that didn't present the api key get the public view.
+fn view(ctx: &Context<'_>) -> View {
+    ctx.data_opt::<View>().copied().unwrap_or(View::Public)
+}
+
+fn watch(ctx: &Context<'_>) -> Result<mpsc::Receiver<Update>> {
+    let (sender, receiver) = mpsc::channel(WATCH_CAPACITY);
+    state(ctx)?.session_manager.do_send(Watch(sender));
+    Ok(receiver)
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    async fn topic(&self, ctx: &Context<'_>) -> Result<TopicObject> {
+        Ok(TopicObject(state(ctx)?.topic.load()))
+    }
+
+    async fn tasks(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: FilterInput,
+    ) -> Result<Vec<TaskObject>> {
+        let tasks = state(ctx)?.tasks_for(view(ctx));
+
+        Ok(TaskFilter::from(filter)
+            .apply(&tasks.value)
+            .into_iter()
+            .map(TaskObject)
+            .collect())
+    }
+}
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    /// The filtered tasks, each time they change.
+    async fn tasks_updated(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: FilterInput,
+    ) -> Result<impl Stream<Item = Vec<TaskObject>>> {
+        let view = view(ctx);
+        let filter = TaskFilter::from(filter);
+        let mut last: Option<Vec<Task>> = None;
+
+        Ok(watch(ctx)?.filter_map(move |update| {
+            let slice = match update {
+                Update::Tasks(tasks) => filter.apply(&tasks.view(view).value),
+                Update::Topic(_) => return ready(None),
+            };
+
+            // like the websocket sessions, only sent when the slice changed
+            if last.as_ref() == Some(&slice) {
+                return ready(None);
+            }
+            last = Some(slice.clone());
+
+            ready(Some(slice.into_iter().map(TaskObject).collect()))
+        }))
+    }
+
+    async fn topic_updated(&self, ctx: &Context<'_>) -> Result<impl Stream<Item = TopicObject>> {
+        Ok(watch(ctx)?.filter_map(|update| {
+            ready(match update {
+                Update::Topic(TopicUpdated(topic)) => Some(TopicObject(topic)),
+                Update::Tasks(_) => None,
+            })
+        }))
+    }
+}
+
+/// Runs a query. Clients presenting the api key see the private view.
+pub async fn query(
+    schema: web::Data<TaskSchema>,
+    data: web::Data<AppState>,
+    auth: Option<BearerAuth>,
+    request: web::Json<async_graphql::Request>,
+) -> HttpResponse {
+    let view = data.view_for(auth.as_ref().map(|auth| auth.token()));
+    let request = request.into_inner().data(data.clone()).data(view);
+
+    HttpResponse::Ok().json(schema.execute(request).await)
+}
+
+/// Clients that can't set headers put the token in their `connection_init`
+/// payload, either as `token` or as an `Authorization` value.
+fn init_token(payload: &Value) -> Option<String> {
+    if let Some(token) = payload.get("token").and_then(Value::as_str) {
+        return Some(token.to_string());
+    }
+
+    ["Authorization", "authorization"]
+        .iter()
+        .find_map(|key| payload.get(key)?.as_str()?.strip_prefix("Bearer "))
+        .map(str::to_string)
+}
+
+/// Starts a subscription session, under the same rules and limits as the
+/// task websocket.
+pub async fn subscriptions(
+    req: HttpRequest,
+    stream: web::Payload,
+    schema: web::Data<TaskSchema>,
+    data: web::Data<AppState>,
+) -> std::result::Result<HttpResponse, actix_web::Error> {
+    let query = match web::Query::<HashMap<String, String>>::from_query(req.query_string()) {
+        Ok(query) => query.into_inner(),
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+
+    // the token may still come in `connection_init`, so only a wrong one
+    // is refused here
+    let token = ws_token(&req, &query);
+    if token.is_some() && data.stream_view(token.as_deref()).is_none() {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let protocol = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .find_map(|protocol| WebSocketProtocols::from_str(protocol.trim()).ok())
+        .unwrap_or(WebSocketProtocols::GraphQLWS);
+
+    let ip = data
+        .limiter
+        .client_ip(req.peer_addr(), &req.connection_info());
+    let permit = match data.limiter.sessions.acquire(ip) {
+        Some(permit) => permit,
+        None => return Ok(limits::session_refused()),
+    };
+
+    let session = GraphQLSession {
+        hb: Instant::now(),
+        schema: schema.get_ref().clone(),
+        state: data.clone(),
+        protocol,
+        token,
+        frames: None,
+        _permit: permit,
+    };
+
+    ws::start_with_protocols(session, &[protocol.sec_websocket_protocol()], &req, stream)
+}
+
+/// Hands a websocket's frames to async-graphql, which answers with a stream
+/// of frames of its own.
+struct GraphQLSession {
+    hb: Instant,
+    schema: TaskSchema,
+    state: web::Data<AppState>,
+    protocol: WebSocketProtocols,
+    /// The token presented during the handshake, if any.
+    token: Option<String>,
+    frames: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    _permit: SessionPermit,
+}
+
+impl GraphQLSession {
+    fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+                info!("GraphQL client heartbeat failed, disconnecting!");
+                ctx.stop();
+                return;
+            }
+
+            ctx.ping(b"");
+        });
+    }
+
+    fn forward(&self, frame: Vec<u8>, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(ref frames) = self.frames {
+            if frames.unbounded_send(frame).is_err() {
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl Actor for GraphQLSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.hb(ctx);
+
+        let (sender, receiver) = mpsc::unbounded();
+        self.frames = Some(sender);
+
+        let state = self.state.clone();
+        let token = self.token.take();
+        let socket = WebSocket::new(self.schema.clone(), receiver, self.protocol)
+            .on_connection_init(move |payload| async move {
+                let token = init_token(&payload).or(token);
+                let view = state
+                    .stream_view(token.as_deref())
+                    .ok_or_else(|| Error::new("unauthorized"))?;
+
+                let mut data = Data::default();
+                data.insert(state);
+                data.insert(view);
+                Ok(data)
+            });
+
+        ctx.add_stream(socket);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for GraphQLSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let msg = match msg {
+            Err(_) => {
+                ctx.stop();
+                return;
+            }
+            Ok(msg) => msg,
+        };
+
+        match msg {
+            ws::Message::Ping(msg) => {
+                self.hb = Instant::now();
+                ctx.pong(&msg);
+            }
+            ws::Message::Pong(_) => {
+                self.hb = Instant::now();
+            }
+            ws::Message::Text(text) => self.forward(text.as_bytes().to_vec(), ctx),
+            ws::Message::Binary(bytes) => self.forward(bytes.to_vec(), ctx),
+            ws::Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            ws::Message::Continuation(_) => {
+                ctx.stop();
+            }
+            ws::Message::Nop => (),
+        }
+    }
+}
+
+impl StreamHandler<WsMessage> for GraphQLSession {
+    fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) {
+        match msg {
+            WsMessage::Text(text) => ctx.text(text),
+            WsMessage::Close(code, reason) => {
+                ctx.close(Some(ws::CloseReason {
+                    code: code.into(),
+                    description: Some(reason),
+                }));
+                ctx.stop();
+            }
+        }
+    }
+}
@@ -0,0 +1,471 @@
+//! A gRPC view of the same state as the HTTP API, for tooling that only
+//! speaks gRPC. Served on its own port when the `grpc` feature is enabled.
+//!
+//! Callers are identified by their peer address only: unlike the HTTP API,
+//! forwarding headers are never trusted here.
+// tonic's handlers return its (large) `Status`, so the helpers do too
+#![allow(clippy::result_large_err)]
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+
+use actix_web::http::Method;
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc;
+use futures::future::ready;
+use futures::{Stream, StreamExt};
+use log::{error, info};
+use task_hookrs::task::Task;
+use tonic::{Request, Response, Status};
+
+use crate::app::AppState;
+use crate::filter::{status_name, urgency, SortBy, TaskFilter};
+use crate::protocol;
+use crate::schema::{self, format_date};
+use crate::session::{TopicUpdated, Update, Watch, TASKS_UPDATED, TOPIC_UPDATED, WATCH_CAPACITY};
+use crate::snapshot::Snapshot;
+
+pub mod proto {
+    tonic::include_proto!("task_streamer");
+}
+
+use proto::task_streamer_server::{TaskStreamer, TaskStreamerServer};
+
+/// Recorded as `updated_by` for changes made over gRPC.
+const UPDATED_BY: &str = "grpc";
+/// The events a watch can send. Task views have no message of their own.
+const WATCH_EVENTS: &[&str] = &[TASKS_UPDATED, TOPIC_UPDATED];
+
+/// Serves gRPC on `addr` until the server stops.
+pub async fn serve(addr: SocketAddr, state: web::Data<AppState>) {
+    info!("Serving gRPC on {}", addr);
+
+    let result = tonic::transport::Server::builder()
+        .add_service(TaskStreamerServer::new(TaskService { state }))
+        .serve(addr)
+        .await;
+
+    if let Err(e) = result {
+        error!("gRPC server on {} failed: {}", addr, e);
+    }
+}
+
+pub struct TaskService {
+    state: web::Data<AppState>,
+}
+
+impl TaskService {
+    pub fn new(state: web::Data<AppState>) -> Self {
+        TaskService { state }
+    }
+
+    /// Applies the same rate limits as the HTTP API, reads or writes
+    /// depending on `method`.
+    fn check_limit<T>(&self, method: &Method, request: &Request<T>) -> Result<(), Status> {
+        self.state
+            .limiter
+            .check(method, client_ip(request))
+            .map_err(|retry_after| {
+                Status::resource_exhausted(format!(
+                    "rate limited, retry in {}s",
+                    retry_after.as_secs() + 1
+                ))
+            })
+    }
+
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        match token(request) {
+            Some(token) if self.state.authorized(token) => Ok(()),
+            _ => Err(Status::unauthenticated("a valid api key is required")),
+        }
+    }
+}
+
+fn token<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get("authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+fn client_ip<T>(request: &Request<T>) -> IpAddr {
+    request
+        .remote_addr()
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+fn internal(e: impl std::fmt::Display) -> Status {
+    error!("gRPC request failed: {}", e);
+    Status::internal("internal error")
+}
+
+impl From<proto::TaskFilter> for TaskFilter {
+    fn from(filter: proto::TaskFilter) -> Self {
+        let sort = match proto::Sort::from_i32(filter.sort) {
+            Some(proto::Sort::Urgency) => Some(SortBy::Urgency),
+            Some(proto::Sort::Entry) => Some(SortBy::Entry),
+            Some(proto::Sort::Due) => Some(SortBy::Due),
+            Some(proto::Sort::Description) => Some(SortBy::Description),
+            Some(proto::Sort::Active) => Some(SortBy::Active),
+            Some(proto::Sort::Project) => Some(SortBy::Project),
+            Some(proto::Sort::None) | None => None,
+        };
+
+        TaskFilter {
+            projects: filter.projects,
+            tags: filter.tags,
+            status: filter.status,
+            sort,
+            limit: match filter.limit {
+                0 => None,
+                limit => Some(limit as usize),
+            },
+        }
+    }
+}
+
+fn to_task(task: &Task) -> Result<proto::Task, Status> {
+    Ok(proto::Task {
+        json: serde_json::to_string(task).map_err(internal)?,
+        uuid: task.uuid().to_string(),
+        description: task.description().clone(),
+        status: status_name(task).unwrap_or_default(),
+        project: task.project().cloned().unwrap_or_default(),
+        tags: task.tags().cloned().unwrap_or_default(),
+        urgency: urgency(task),
+        entry: format_date(task.entry()),
+        start: task.start().map(format_date).unwrap_or_default(),
+        due: task.due().map(format_date).unwrap_or_default(),
+    })
+}
+
+fn to_tasks(snapshot: &Snapshot<Vec<Task>>, filter: &TaskFilter) -> Result<proto::Tasks, Status> {
+    Ok(proto::Tasks {
+        revision: snapshot.revision,
+        tasks: filter
+            .apply(&snapshot.value)
+            .iter()
+            .map(to_task)
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+fn to_topic(snapshot: &Snapshot<schema::Topic>) -> proto::Topic {
+    let topic = &snapshot.value;
+    proto::Topic {
+        revision: snapshot.revision,
+        title: topic.title.clone(),
+        description: topic.description.clone(),
+        links: topic
+            .links
+            .iter()
+            .map(|link| proto::Link {
+                url: link.url.clone(),
+                title: link.title.clone().unwrap_or_default(),
+            })
+            .collect(),
+        tags: topic.tags.clone(),
+        started_at: topic
+            .started_at
+            .map(|time| time.to_rfc3339())
+            .unwrap_or_default(),
+        goal: topic.goal.clone().unwrap_or_default(),
+        segments: topic
+            .segments
+            .iter()
+            .map(|segment| proto::Segment {
+                title: segment.title.clone(),
+                done: segment.done,
+            })
+            .collect(),
+    }
+}
+
+fn from_topic(topic: proto::Topic) -> Result<schema::Topic, Status> {
+    let non_empty = |value: String| Some(value).filter(|value| !value.is_empty());
+    let started_at = match non_empty(topic.started_at) {
+        Some(time) => Some(
+            DateTime::parse_from_rfc3339(&time)
+                .map_err(|e| Status::invalid_argument(format!("invalid started_at: {}", e)))?
+                .with_timezone(&Utc),
+        ),
+        None => None,
+    };
+
+    Ok(schema::Topic {
+        title: topic.title,
+        description: topic.description,
+        links: topic
+            .links
+            .into_iter()
+            .map(|link| schema::Link {
+                url: link.url,
+                title: non_empty(link.title),
+            })
+            .collect(),
+        tags: topic.tags,
+        started_at,
+        goal: non_empty(topic.goal),
+        segments: topic
+            .segments
+            .into_iter()
+            .map(|segment| schema::Segment {
+                title: segment.title,
+                done: segment.done,
+            })
+            .collect(),
+    })
+}
+
+type WatchStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send + Sync>>;
+
+#[tonic::async_trait]
+impl TaskStreamer for TaskService {
+    async fn get_tasks(
+        &self,
+        request: Request<proto::GetTasksRequest>,
+    ) -> Result<Response<proto::Tasks>, Status> {
+        self.check_limit(&Method::GET, &request)?;
+
+        let view = self.state.view_for(token(&request));
+        let filter = request.into_inner().filter.unwrap_or_default().into();
+
+        Ok(Response::new(to_tasks(
+            &self.state.tasks_for(view),
+            &filter,
+        )?))
+    }
+
+    async fn get_topic(
+        &self,
+        request: Request<proto::GetTopicRequest>,
+    ) -> Result<Response<proto::Topic>, Status> {
+        self.check_limit(&Method::GET, &request)?;
+
+        Ok(Response::new(to_topic(&self.state.topic.load())))
+    }
+
+    async fn set_tasks(
+        &self,
+        request: Request<proto::Tasks>,
+    ) -> Result<Response<proto::SetResponse>, Status> {
+        self.check_limit(&Method::POST, &request)?;
+        self.authorize(&request)?;
+
+        let tasks = request
+            .into_inner()
+            .tasks
+            .iter()
+            .map(|task| serde_json::from_str(&task.json))
+            .collect::<Result<Vec<Task>, _>>()
+            .map_err(|e| Status::invalid_argument(format!("invalid task: {}", e)))?;
+
+        self.state
+            .set_tasks_by(tasks, Some(UPDATED_BY))
+            .map_err(internal)?;
+        Ok(Response::new(proto::SetResponse {}))
+    }
+
+    async fn set_topic(
+        &self,
+        request: Request<proto::Topic>,
+    ) -> Result<Response<proto::SetResponse>, Status> {
+        self.check_limit(&Method::POST, &request)?;
+        self.authorize(&request)?;
+
+        let topic = from_topic(request.into_inner())?;
+        self.state
+            .set_topic_by(topic, Some(UPDATED_BY))
+            .map_err(internal)?;
+        Ok(Response::new(proto::SetResponse {}))
+    }
+
+    type WatchStream = WatchStream;
+
+    /// Like a websocket session: the same view rules, and it counts against
+    /// the session limits for as long as it is open.
+    async fn watch(
+        &self,
+        request: Request<proto::WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let view = self
+            .state
+            .stream_view(token(&request))
+            .ok_or_else(|| Status::unauthenticated("a valid api key is required"))?;
+        let permit = self
+            .state
+            .limiter
+            .sessions
+            .acquire(client_ip(&request))
+            .ok_or_else(|| Status::resource_exhausted("too many sessions"))?;
+
+        let request = request.into_inner();
+        let events = match protocol::events(&request.events).map_err(Status::invalid_argument)? {
+            events if events.is_empty() => protocol::DEFAULT_EVENTS.to_vec(),
+            events => events,
+        };
+        if let Some(event) = events.iter().find(|event| !WATCH_EVENTS.contains(event)) {
+            return Err(Status::invalid_argument(format!(
+                "{} can't be watched over gRPC",
+                event
+            )));
+        }
+        let filter: TaskFilter = request.filter.unwrap_or_default().into();
+
+        let (sender, receiver) = mpsc::channel(WATCH_CAPACITY);
+        self.state.session_manager.do_send(Watch(sender));
+
+        let mut last: Option<Vec<Task>> = None;
+        let stream = receiver.filter_map(move |update| {
+            // released when the caller goes away and the stream is dropped
+            let _ = &permit;
+
+            let event = match update {
+                Update::Tasks(tasks) if events.contains(&TASKS_UPDATED) => {
+                    let snapshot = tasks.view(view);
+                    let slice = filter.apply(&snapshot.value);
+
+                    // like the websocket sessions, only sent when the slice changed
+                    if last.as_ref() == Some(&slice) {
+                        return ready(None);
+                    }
+
+                    let tasks = slice
+                        .iter()
+                        .map(to_task)
+                        .collect::<Result<_, _>>()
+                        .map(|tasks| proto::Event {
+                            event: TASKS_UPDATED.to_string(),
+                            revision: snapshot.revision,
+                            data: Some(proto::event::Data::Tasks(proto::Tasks {
+                                revision: snapshot.revision,
+                                tasks,
+                            })),
+                        });
+                    last = Some(slice);
+                    tasks
+                }
+                Update::Topic(TopicUpdated(topic)) if events.contains(&TOPIC_UPDATED) => {
+                    Ok(proto::Event {
+                        event: TOPIC_UPDATED.to_string(),
+                        revision: topic.revision,
+                        data: Some(proto::event::Data::Topic(to_topic(&topic))),
+                    })
+                }
+                _ => return ready(None),
+            };
+
+            ready(Some(event))
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::TASK_VIEWS_UPDATED;
+    use actix_web::rt as actix_rt;
+    use std::time::Duration;
+
+    fn tasks() -> Vec<Task> {
+        let task_json = r#"
+        [{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20201118T071926Z","start":"20201119T071926Z","description":"write the overlay","project":"twitch","tags":["@stream"]},
+         {"status":"pending","uuid":"8699cf59-59d4-4f42-812d-0d2de0cad191","entry":"20201120T064735Z","description":"call the bank","project":"personal"}]
+        "#;
+        serde_json::from_str(task_json).unwrap()
+    }
+
+    fn authorized<T>(message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        request
+    }
+
+    #[actix_rt::test]
+    async fn reading_and_writing() {
+        let service = TaskService::new(web::Data::new(AppState::new("secret".to_string())));
+
+        let request = proto::Tasks {
+            revision: 0,
+            tasks: tasks().iter().map(|t| to_task(t).unwrap()).collect(),
+        };
+        let refused = service.set_tasks(Request::new(request.clone())).await;
+        assert_eq!(refused.unwrap_err().code(), tonic::Code::Unauthenticated);
+
+        service.set_tasks(authorized(request)).await.unwrap();
+        assert_eq!(service.state.tasks.load().value, tasks());
+
+        let filter = proto::TaskFilter {
+            projects: vec!["twitch".to_string()],
+            ..proto::TaskFilter::default()
+        };
+        let response = service
+            .get_tasks(Request::new(proto::GetTasksRequest {
+                filter: Some(filter),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.tasks.len(), 1);
+        assert_eq!(response.tasks[0].description, "write the overlay");
+        assert_eq!(response.tasks[0].start, "2020-11-19T07:19:26Z");
+        assert_eq!(response.tasks[0].tags, vec!["@stream"]);
+    }
+
+    #[actix_rt::test]
+    async fn watching() {
+        let service = TaskService::new(web::Data::new(AppState::new("secret".to_string())));
+
+        let mut stream = service
+            .watch(Request::new(proto::WatchRequest {
+                events: vec![TOPIC_UPDATED.to_string()],
+                filter: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // the watch registers with the manager asynchronously
+        actix_rt::time::delay_for(Duration::from_millis(50)).await;
+        service.state.set_tasks(tasks()).unwrap();
+        service
+            .state
+            .set_topic(schema::Topic::new("grpc".to_string(), "".to_string()))
+            .unwrap();
+
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.event, TOPIC_UPDATED);
+        match event.data {
+            Some(proto::event::Data::Topic(topic)) => assert_eq!(topic.title, "grpc"),
+            data => panic!("unexpected {:?}", data),
+        }
+        assert_eq!(service.state.limiter.sessions.active(), 1);
+
+        drop(stream);
+        assert_eq!(service.state.limiter.sessions.active(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn watching_what_is_never_sent() {
+        let service = TaskService::new(web::Data::new(AppState::new("secret".to_string())));
+
+        let status = service
+            .watch(Request::new(proto::WatchRequest {
+                events: vec![TASK_VIEWS_UPDATED.to_string()],
+                filter: None,
+            }))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(service.state.limiter.sessions.active(), 0);
+    }
+}
@@ -0,0 +1,295 @@
+//! Taskwarrior hooks, so tasks are pushed as they change instead of when
+//! someone remembers to run `client update`.
+//!
+//! Taskwarrior waits on its hooks and trusts what they print, so the hooks
+//! themselves only echo the task back, exactly as they were given it, and
+//! start a detached `hook push` to do the pushing. Whatever goes wrong,
+//! they exit successfully.
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use actix_web::rt::time::delay_for;
+
+use crate::error::Result;
+
+pub const HOOKS: &[&str] = &["on-add", "on-modify", "on-exit"];
+
+/// What `hook install` installs unless told otherwise. `on-exit` runs once
+/// per command, after taskwarrior has written its changes, where `on-add`
+/// and `on-modify` run for every task, before it has.
+pub const DEFAULT_HOOKS: &[&str] = &["on-exit"];
+
+/// How often a deferred `hook push` checks whether taskwarrior has exited.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a deferred `hook push` waits for taskwarrior to exit before
+/// pushing anyway.
+const EXIT_WAIT_LIMIT: Duration = Duration::from_secs(60);
+
+/// Runs the named hook over taskwarrior's `input`, writing what taskwarrior
+/// expects back to `output`. Gives whether any task changed.
+pub fn run(hook: &str, input: impl BufRead, mut output: impl Write) -> io::Result<bool> {
+    let mut lines = input.lines();
+
+    match hook {
+        // the new task, which has to come back unchanged
+        "on-add" => match lines.next().transpose()? {
+            Some(task) => {
+                writeln!(output, "{}", task)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        },
+        // the task before and after, and the latter has to come back
+        "on-modify" => {
+            let _original = lines.next().transpose()?;
+            match lines.next().transpose()? {
+                Some(modified) => {
+                    writeln!(output, "{}", modified)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+        // every task the command added or changed, and nothing comes back
+        "on-exit" => {
+            let mut changed = false;
+            for line in lines {
+                changed |= !line?.trim().is_empty();
+            }
+            Ok(changed)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// The flags a hook hands on to what it runs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options<'a> {
+    pub config: Option<&'a str>,
+    pub profile: Option<&'a str>,
+}
+
+impl<'a> Options<'a> {
+    fn args(&self) -> Vec<(&'static str, &'a str)> {
+        let mut args = Vec::new();
+        if let Some(config) = self.config {
+            args.push(("--config", config));
+        }
+        if let Some(profile) = self.profile {
+            args.push(("--profile", profile));
+        }
+        args
+    }
+}
+
+/// Starts `hook push` in the background, leaving it running after the hook
+/// exits.
+///
+/// `on-add` and `on-modify` run before taskwarrior has written the task, and
+/// once for every task a command changes, so their push waits for
+/// taskwarrior to exit, and only the first of them in a command starts one.
+pub fn spawn_push(hook: &str, options: Options) -> Result<()> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg("hook").arg("push");
+    for (flag, value) in options.args() {
+        command.arg(flag).arg(value);
+    }
+
+    if hook != "on-exit" {
+        if let Some(pid) = taskwarrior_pid() {
+            if !claim_push(pid)? {
+                return Ok(());
+            }
+            command.arg("--after").arg(pid.to_string());
+        }
+    }
+
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(())
+}
+
+/// The taskwarrior process running the hook, which the scripts `exec` into.
+#[cfg(unix)]
+fn taskwarrior_pid() -> Option<u32> {
+    Some(std::os::unix::process::parent_id())
+}
+
+#[cfg(not(unix))]
+fn taskwarrior_pid() -> Option<u32> {
+    None
+}
+
+/// Marks a push as pending for the taskwarrior process `pid`, unless one
+/// already is. Gives whether it wasn't.
+fn claim_push(pid: u32) -> Result<bool> {
+    let claimed = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(pending_marker(pid));
+
+    match claimed {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn pending_marker(pid: u32) -> PathBuf {
+    std::env::temp_dir().join(format!("task-streamer-push-{}", pid))
+}
+
+/// Waits until the taskwarrior process `pid` has exited, and with it
+/// written every change, then lets the next of its hooks start a push.
+pub async fn wait_for_exit(pid: u32) {
+    let start = Instant::now();
+
+    while is_running(pid) && start.elapsed() < EXIT_WAIT_LIMIT {
+        delay_for(EXIT_POLL_INTERVAL).await;
+    }
+
+    let _ = fs::remove_file(pending_marker(pid));
+}
+
+fn is_running(pid: u32) -> bool {
+    Command::new("kill")
+        .arg("-0")
+        .arg(pid.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// The script taskwarrior runs for `hook`.
+pub fn script(exe: &Path, hook: &str, options: Options) -> String {
+    let mut command = format!("exec {} hook {}", shell_quote(&exe.to_string_lossy()), hook);
+    for (flag, value) in options.args() {
+        command.push_str(&format!(" {} {}", flag, shell_quote(value)));
+    }
+
+    format!(
+        "#!/bin/sh\n# installed by task-streamer hook install\n{}\n",
+        command
+    )
+}
+
+/// Writes a script for each of `hooks` into `dir`, replacing any this
+/// installed before, and gives their paths.
+pub fn install(dir: &Path, hooks: &[&str], options: Options) -> Result<Vec<PathBuf>> {
+    let exe = std::env::current_exe()?;
+    fs::create_dir_all(dir)?;
+
+    hooks
+        .iter()
+        .map(|hook| {
+            let path = dir.join(format!("{}-task-streamer", hook));
+            fs::write(&path, script(&exe, hook, options))?;
+            #[cfg(unix)]
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+            Ok(path)
+        })
+        .collect()
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt as actix_rt;
+
+    const ORIGINAL: &str = r#"{"uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","description":"hook","status":"pending","entry":"20201118T071926Z"}"#;
+    const MODIFIED: &str = r#"{"uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","description":"hook","status":"completed","entry":"20201118T071926Z"}"#;
+
+    fn hook(name: &str, input: &str) -> (bool, String) {
+        let mut output = Vec::new();
+        let changed = run(name, input.as_bytes(), &mut output).unwrap();
+        (changed, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn tasks_are_echoed_back_untouched() {
+        assert_eq!(
+            hook("on-add", &format!("{}\n", ORIGINAL)),
+            (true, format!("{}\n", ORIGINAL))
+        );
+        assert_eq!(
+            hook("on-modify", &format!("{}\n{}\n", ORIGINAL, MODIFIED)),
+            (true, format!("{}\n", MODIFIED))
+        );
+        // whatever taskwarrior sends comes back, even if it isn't a task
+        assert_eq!(
+            hook("on-add", "not json\n"),
+            (true, "not json\n".to_string())
+        );
+    }
+
+    #[test]
+    fn on_exit_only_pushes_changes() {
+        assert_eq!(hook("on-exit", ""), (false, String::new()));
+        assert_eq!(
+            hook("on-exit", &format!("{}\n{}\n", ORIGINAL, MODIFIED)),
+            (true, String::new())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn one_push_per_command() {
+        // far above any real pid
+        let pid = u32::MAX - 42;
+
+        assert!(claim_push(pid).unwrap());
+        assert!(!claim_push(pid).unwrap());
+
+        wait_for_exit(pid).await;
+        assert!(claim_push(pid).unwrap());
+        fs::remove_file(pending_marker(pid)).unwrap();
+    }
+
+    #[test]
+    fn installing_scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let hooks = dir.path().join("hooks");
+
+        let options = Options {
+            config: Some("/it's/config.toml"),
+            profile: Some("staging"),
+        };
+        let installed = install(&hooks, &["on-add", "on-modify"], options).unwrap();
+        assert_eq!(
+            installed,
+            vec![
+                hooks.join("on-add-task-streamer"),
+                hooks.join("on-modify-task-streamer")
+            ]
+        );
+
+        let script = fs::read_to_string(&installed[1]).unwrap();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script
+            .ends_with(" hook on-modify --config '/it'\\''s/config.toml' --profile 'staging'\n"));
+
+        let installed = install(&hooks, DEFAULT_HOOKS, Options::default()).unwrap();
+        assert_eq!(installed, vec![hooks.join("on-exit-task-streamer")]);
+
+        #[cfg(unix)]
+        assert_eq!(
+            fs::metadata(&installed[0]).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+    }
+}
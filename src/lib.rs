@@ -1,9 +1,27 @@
 pub mod cli;
 pub mod app;
+pub mod encoding;
+pub mod filter;
+pub mod frontend;
+pub mod graphql;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod limits;
+pub mod protocol;
+pub mod redact;
 pub mod schema;
+pub mod session;
+pub mod snapshot;
 
 mod client;
 mod config;
 mod error;
-mod session;
+mod hook;
+mod outbox;
+mod output;
+mod privacy;
+mod relay;
+mod source;
 mod tasks;
+mod taskwarrior;
+mod watch;
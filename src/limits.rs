@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{ConnectionInfo, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::rt::time::delay_for;
+use actix_web::{web, Error, HttpResponse};
+use futures::future::{ok, Either, Ready};
+use serde::Deserialize;
+
+use crate::app::AppState;
+
+/// Once this many clients are tracked, requests prune the buckets too, not
+/// only `prune_forever`.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// How often the buckets are pruned, and at most how often requests do, so
+/// a busy server doesn't walk them all on every request.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What a client refused a websocket session is told to wait, in seconds.
+const SESSION_RETRY_AFTER: u64 = 10;
+
+/// A token bucket: `burst` requests at once, refilled at `per_second`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "RawRateLimit")]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+#[derive(Deserialize)]
+struct RawRateLimit {
+    burst: u32,
+    per_second: f64,
+}
+
+impl TryFrom<RawRateLimit> for RateLimit {
+    type Error = String;
+
+    fn try_from(raw: RawRateLimit) -> std::result::Result<Self, Self::Error> {
+        if raw.burst == 0 {
+            return Err("rate limit burst must be at least 1".to_string());
+        }
+        if raw.per_second.is_nan() || raw.per_second <= 0.0 {
+            return Err(format!(
+                "rate limit per_second must be above 0, not {}",
+                raw.per_second
+            ));
+        }
+
+        Ok(RateLimit {
+            burst: raw.burst,
+            per_second: raw.per_second,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Limits {
+    /// Websocket sessions across all clients.
+    pub max_sessions: Option<usize>,
+    /// Websocket sessions per client address.
+    pub max_sessions_per_ip: Option<usize>,
+    /// Applies to each client's POSTs.
+    pub writes: Option<RateLimit>,
+    /// Applies to each client's GETs.
+    pub reads: Option<RateLimit>,
+    /// Identify clients by `X-Forwarded-For`/`Forwarded` rather than the
+    /// peer address. Only safe when a proxy always sets them.
+    #[serde(default)]
+    pub behind_proxy: bool,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    by_ip: HashMap<IpAddr, Bucket>,
+    pruned: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(Buckets {
+                by_ip: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token for `ip`, or says how long until one is available.
+    pub fn check(&self, ip: IpAddr) -> std::result::Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.by_ip.len() >= PRUNE_THRESHOLD
+            && now.duration_since(buckets.pruned) >= PRUNE_INTERVAL
+        {
+            self.prune_buckets(&mut buckets, now);
+        }
+
+        let bucket = buckets.by_ip.entry(ip).or_insert(Bucket {
+            tokens: self.limit.burst as f64,
+            updated: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.limit.per_second,
+            ))
+        }
+    }
+
+    /// Forgets clients with full buckets, as they are indistinguishable from
+    /// new ones.
+    pub fn prune(&self) {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.prune_buckets(&mut buckets, Instant::now());
+    }
+
+    fn prune_buckets(&self, buckets: &mut Buckets, now: Instant) {
+        let burst = self.limit.burst as f64;
+        buckets
+            .by_ip
+            .retain(|_, bucket| self.refill(bucket, now) < burst);
+        buckets.pruned = now;
+    }
+
+    /// The tokens `bucket` has at `now`.
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64)
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.buckets.lock().unwrap().by_ip.len()
+    }
+}
+
+#[derive(Debug, Default)]
+struct SessionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct SessionLimiter {
+    max: Option<usize>,
+    max_per_ip: Option<usize>,
+    counts: Mutex<SessionCounts>,
+}
+
+impl SessionLimiter {
+    /// Reserves a session for `ip`, if that stays within the limits. The
+    /// reservation lasts until the returned permit is dropped.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<SessionPermit> {
+        let mut counts = self.counts.lock().unwrap();
+        let for_ip = counts.per_ip.get(&ip).copied().unwrap_or_default();
+
+        if matches!(self.max, Some(max) if counts.total >= max)
+            || matches!(self.max_per_ip, Some(max) if for_ip >= max)
+        {
+            return None;
+        }
+
+        counts.total += 1;
+        counts.per_ip.insert(ip, for_ip + 1);
+
+        Some(SessionPermit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    pub fn active(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+}
+
+/// Held by a `TaskSession` for as long as it is connected.
+#[derive(Debug)]
+pub struct SessionPermit {
+    limiter: Arc<SessionLimiter>,
+    ip: IpAddr,
+}
+
+impl SessionPermit {
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        counts.total -= 1;
+
+        if let Some(count) = counts.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// The runtime side of `Limits`.
+#[derive(Debug, Default)]
+pub struct Limiter {
+    behind_proxy: bool,
+    reads: Option<RateLimiter>,
+    writes: Option<RateLimiter>,
+    pub sessions: Arc<SessionLimiter>,
+}
+
+impl Limiter {
+    pub fn new(limits: &Limits) -> Self {
+        Limiter {
+            behind_proxy: limits.behind_proxy,
+            reads: limits.reads.clone().map(RateLimiter::new),
+            writes: limits.writes.clone().map(RateLimiter::new),
+            sessions: Arc::new(SessionLimiter {
+                max: limits.max_sessions,
+                max_per_ip: limits.max_sessions_per_ip,
+                counts: Mutex::new(SessionCounts::default()),
+            }),
+        }
+    }
+
+    pub fn client_ip(&self, peer_addr: Option<SocketAddr>, info: &ConnectionInfo) -> IpAddr {
+        let forwarded = if self.behind_proxy {
+            info.realip_remote_addr().and_then(parse_ip)
+        } else {
+            None
+        };
+
+        forwarded
+            .or_else(|| peer_addr.map(|addr| addr.ip()))
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+
+    pub fn prune(&self) {
+        for limiter in self.reads.iter().chain(self.writes.iter()) {
+            limiter.prune();
+        }
+    }
+
+    /// Checks the read or write limit, depending on the request method.
+    pub fn check(&self, method: &Method, ip: IpAddr) -> std::result::Result<(), Duration> {
+        let limiter = match *method {
+            Method::GET | Method::HEAD => &self.reads,
+            _ => &self.writes,
+        };
+
+        match limiter {
+            Some(limiter) => limiter.check(ip),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `realip_remote_addr` may or may not carry a port.
+fn parse_ip(addr: &str) -> Option<IpAddr> {
+    addr.parse::<IpAddr>()
+        .ok()
+        .or_else(|| addr.parse::<SocketAddr>().ok().map(|a| a.ip()))
+}
+
+pub fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    // round up, so a client that waits as told is let through
+    let secs = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+
+    HttpResponse::TooManyRequests()
+        .header(header::RETRY_AFTER, secs.max(1).to_string())
+        .finish()
+}
+
+pub fn session_refused() -> HttpResponse {
+    too_many_requests(Duration::from_secs(SESSION_RETRY_AFTER))
+}
+
+/// Prunes the `AppState`'s rate limits every `PRUNE_INTERVAL`, so clients
+/// that went away are forgotten however few requests come in.
+pub async fn prune_forever(state: web::Data<AppState>) {
+    loop {
+        delay_for(PRUNE_INTERVAL).await;
+        state.limiter.prune();
+    }
+}
+
+/// Middleware applying the `AppState`'s rate limits, for use with `wrap_fn`.
+pub fn rate_limit<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> Either<S::Future, Ready<std::result::Result<ServiceResponse, Error>>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    let method = req.method().clone();
+    limit(req, srv, &method)
+}
+
+/// Like `rate_limit`, but every request counts as a read. The GraphQL schema
+/// has no mutations, yet every query is a POST.
+pub fn read_rate_limit<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> Either<S::Future, Ready<std::result::Result<ServiceResponse, Error>>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    limit(req, srv, &Method::GET)
+}
+
+fn limit<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+    method: &Method,
+) -> Either<S::Future, Ready<std::result::Result<ServiceResponse, Error>>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    let limited = req.app_data::<web::Data<AppState>>().map(|data| {
+        let ip = data
+            .limiter
+            .client_ip(req.peer_addr(), &req.connection_info());
+        data.limiter.check(method, ip)
+    });
+
+    match limited {
+        Some(Err(retry_after)) => {
+            Either::Right(ok(req.into_response(too_many_requests(retry_after))))
+        }
+        _ => Either::Left(srv.call(req)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn buckets_are_per_client() {
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 2,
+            per_second: 1.0,
+        });
+
+        assert!(limiter.check(ip(1)).is_ok());
+        assert!(limiter.check(ip(1)).is_ok());
+
+        let retry_after = limiter.check(ip(1)).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(1));
+
+        assert!(limiter.check(ip(2)).is_ok());
+    }
+
+    #[test]
+    fn buckets_refill() {
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 1,
+            per_second: 100.0,
+        });
+
+        assert!(limiter.check(ip(1)).is_ok());
+        assert!(limiter.check(ip(1)).is_err());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.check(ip(1)).is_ok());
+    }
+
+    #[test]
+    fn idle_clients_are_forgotten() {
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 1,
+            per_second: 100.0,
+        });
+
+        limiter.check(ip(1)).unwrap();
+        limiter.prune();
+        assert_eq!(limiter.tracked(), 1);
+
+        std::thread::sleep(Duration::from_millis(20));
+        limiter.prune();
+        assert_eq!(limiter.tracked(), 0);
+    }
+
+    #[test]
+    fn rate_limits_must_let_requests_through() {
+        let parse = |json: &str| serde_json::from_str::<RateLimit>(json);
+
+        assert!(parse(r#"{"burst": 5, "per_second": 0.5}"#).is_ok());
+        assert!(parse(r#"{"burst": 0, "per_second": 1.0}"#).is_err());
+        assert!(parse(r#"{"burst": 5, "per_second": 0.0}"#).is_err());
+        assert!(parse(r#"{"burst": 5, "per_second": -1.0}"#).is_err());
+    }
+
+    #[test]
+    fn session_permits() {
+        let limiter = Limiter::new(&Limits {
+            max_sessions: Some(3),
+            max_sessions_per_ip: Some(2),
+            ..Limits::default()
+        });
+        let sessions = &limiter.sessions;
+
+        let first = sessions.acquire(ip(1)).unwrap();
+        let _second = sessions.acquire(ip(1)).unwrap();
+        assert!(sessions.acquire(ip(1)).is_none());
+
+        let _third = sessions.acquire(ip(2)).unwrap();
+        assert!(sessions.acquire(ip(3)).is_none());
+        assert_eq!(sessions.active(), 3);
+
+        drop(first);
+        assert_eq!(sessions.active(), 2);
+        assert!(sessions.acquire(ip(1)).is_some());
+    }
+}
@@ -0,0 +1,263 @@
+//! Updates the server couldn't be reached for, kept on disk until it can.
+//!
+//! Only the latest update of each kind is kept for each server: sending an
+//! older list of tasks after a newer one would only undo it.
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use task_hookrs::task::Task;
+use tempfile::NamedTempFile;
+
+use crate::error::Result;
+use crate::schema::Topic;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    Tasks,
+    Topic,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Tasks => write!(f, "tasks"),
+            Kind::Topic => write!(f, "topic"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", content = "data", rename_all = "lowercase")]
+pub enum Update {
+    Tasks(Vec<Task>),
+    Topic(Topic),
+}
+
+impl Update {
+    pub fn kind(&self) -> Kind {
+        match self {
+            Update::Tasks(_) => Kind::Tasks,
+            Update::Topic(_) => Kind::Topic,
+        }
+    }
+}
+
+impl fmt::Display for Update {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Update::Tasks(tasks) => write!(f, "{} tasks", tasks.len()),
+            Update::Topic(topic) => write!(f, "topic '{}'", topic.title),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Entry {
+    pub server: String,
+    pub queued_at: DateTime<Utc>,
+    pub update: Update,
+}
+
+#[derive(Debug)]
+pub struct Outbox {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+impl Outbox {
+    /// Where the outbox is kept unless told otherwise.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("task-streamer").join("outbox.json"))
+    }
+
+    /// The outbox at `path`, which is empty if there is nothing there yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let entries = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Outbox {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Queues `update` for `server`, replacing any of the same kind.
+    pub fn queue(&mut self, server: &str, update: Update) {
+        self.remove(server, update.kind());
+        self.entries.push(Entry {
+            server: server.to_string(),
+            queued_at: Utc::now(),
+            update,
+        });
+    }
+
+    /// Gives whether there was anything to remove.
+    pub fn remove(&mut self, server: &str, kind: Kind) -> bool {
+        let before = self.entries.len();
+        self.entries
+            .retain(|entry| entry.server != server || entry.update.kind() != kind);
+        self.entries.len() != before
+    }
+
+    /// Removes `sent`, unless something newer has been queued in its place
+    /// since it was read.
+    pub fn remove_sent(&mut self, sent: &Entry) {
+        self.entries.retain(|entry| entry != sent);
+    }
+
+    /// Loads the outbox at `path`, and saves it again if `change` says it
+    /// changed it. Pushes may run side by side, as hooks' do, so the outbox
+    /// is locked throughout, and none of them undoes what another queued.
+    pub fn modify(path: &Path, change: impl FnOnce(&mut Outbox) -> bool) -> Result<()> {
+        let _lock = lock(path)?;
+        let mut outbox = Outbox::load(path)?;
+        if change(&mut outbox) {
+            outbox.save()?;
+        }
+        Ok(())
+    }
+
+    /// Drops everything queued at `path` without reading it, so an outbox
+    /// that can't be read can still be cleared.
+    pub fn clear(path: &Path) -> Result<()> {
+        let _lock = lock(path)?;
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) => dir,
+            None => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+
+        // written aside and moved into place, so a reader never sees half
+        let mut partial = NamedTempFile::new_in(dir)?;
+        partial.write_all(&serde_json::to_vec(&self.entries)?)?;
+        partial.persist(&self.path).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+/// Holds a lock on the outbox at `path` until the file it gives is dropped.
+fn lock(path: &Path) -> Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.with_extension("json.lock"))?;
+    file.lock()?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = "http://localhost:8128/api/v1";
+
+    #[test]
+    fn latest_update_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.json");
+
+        let mut outbox = Outbox::load(&path).unwrap();
+        assert!(outbox.is_empty());
+
+        outbox.queue(SERVER, Update::Topic(Topic::new("old".into(), "".into())));
+        outbox.queue(SERVER, Update::Tasks(Vec::new()));
+        outbox.queue(SERVER, Update::Topic(Topic::new("new".into(), "".into())));
+        outbox.queue(
+            "http://example.com/api/v1",
+            Update::Topic(Topic::new("elsewhere".into(), "".into())),
+        );
+        outbox.save().unwrap();
+
+        let outbox = Outbox::load(&path).unwrap();
+        let queued: Vec<_> = outbox
+            .entries()
+            .iter()
+            .map(|entry| entry.update.to_string())
+            .collect();
+        assert_eq!(queued, vec!["0 tasks", "topic 'new'", "topic 'elsewhere'"]);
+    }
+
+    #[test]
+    fn removing_what_was_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::load(&dir.path().join("outbox.json")).unwrap();
+
+        outbox.queue(SERVER, Update::Tasks(Vec::new()));
+        let sent = outbox.entries()[0].clone();
+
+        // queued again while the first was being sent
+        let mut newer = sent.clone();
+        newer.queued_at = sent.queued_at + chrono::Duration::seconds(1);
+        outbox.entries = vec![newer];
+        outbox.remove_sent(&sent);
+        assert_eq!(outbox.entries().len(), 1);
+
+        assert!(outbox.remove(SERVER, Kind::Tasks));
+        assert!(outbox.is_empty());
+        assert!(!outbox.remove(SERVER, Kind::Tasks));
+    }
+
+    #[test]
+    fn concurrent_changes_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.json");
+
+        let threads: Vec<_> = (0..8)
+            .map(|n| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    Outbox::modify(&path, |outbox| {
+                        outbox.queue(&format!("http://{}", n), Update::Tasks(Vec::new()));
+                        true
+                    })
+                    .unwrap()
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(Outbox::load(&path).unwrap().entries().len(), 8);
+    }
+
+    #[test]
+    fn clearing_what_cant_be_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.json");
+
+        fs::write(&path, "not json").unwrap();
+        assert!(Outbox::load(&path).is_err());
+
+        Outbox::clear(&path).unwrap();
+        assert!(Outbox::load(&path).unwrap().is_empty());
+        Outbox::clear(&path).unwrap();
+    }
+}
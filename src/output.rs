@@ -0,0 +1,183 @@
+//! How the client's read commands print what the server has, when not asked
+//! for JSON.
+use task_hookrs::task::Task;
+
+use crate::filter::urgency;
+use crate::schema::{TaskDiff, TaskStats, Topic};
+
+/// Lines up `rows` under `header`.
+pub fn table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut all = vec![header.iter().map(|title| title.to_string()).collect()];
+    all.extend_from_slice(rows);
+    align(&all)
+}
+
+/// Lines up the columns of `rows`. The last column isn't padded, so long
+/// descriptions don't drag trailing spaces along.
+pub fn align(rows: &[Vec<String>]) -> String {
+    let mut widths = Vec::new();
+    for row in rows {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let lines: Vec<_> = rows
+        .iter()
+        .map(|row| {
+            let last = row.len().saturating_sub(1);
+            let cells: Vec<_> = row
+                .iter()
+                .enumerate()
+                .map(|(i, cell)| {
+                    if i == last {
+                        cell.clone()
+                    } else {
+                        format!("{:width$}", cell, width = widths[i])
+                    }
+                })
+                .collect();
+            cells.join("  ")
+        })
+        .collect();
+    lines.join("\n")
+}
+
+pub fn tasks(tasks: &[Task]) -> String {
+    if tasks.is_empty() {
+        return "No tasks".to_string();
+    }
+
+    let rows: Vec<_> = tasks
+        .iter()
+        .map(|task| {
+            vec![
+                task.id().map(|id| id.to_string()).unwrap_or_default(),
+                task.project().cloned().unwrap_or_default(),
+                task.tags().map(|tags| tags.join(" ")).unwrap_or_default(),
+                format!("{:.2}", urgency(task)),
+                task.description().clone(),
+            ]
+        })
+        .collect();
+
+    table(&["ID", "Project", "Tags", "Urg", "Description"], &rows)
+}
+
+pub fn topic(topic: &Topic) -> String {
+    let mut rows = vec![("Title", topic.title.clone())];
+    if !topic.description.is_empty() {
+        rows.push(("Description", topic.description.clone()));
+    }
+    if let Some(ref goal) = topic.goal {
+        rows.push(("Goal", goal.clone()));
+    }
+    if let Some(started_at) = topic.started_at {
+        rows.push(("Started", started_at.to_rfc3339()));
+    }
+    if !topic.tags.is_empty() {
+        rows.push(("Tags", topic.tags.join(" ")));
+    }
+    for link in &topic.links {
+        let link = match link.title {
+            Some(ref title) => format!("{} <{}>", title, link.url),
+            None => link.url.clone(),
+        };
+        rows.push(("Link", link));
+    }
+    for segment in &topic.segments {
+        let done = if segment.done { "x" } else { " " };
+        rows.push(("Segment", format!("[{}] {}", done, segment.title)));
+    }
+
+    let rows: Vec<_> = rows
+        .into_iter()
+        .map(|(name, value)| vec![name.to_string(), value])
+        .collect();
+    align(&rows)
+}
+
+pub fn stats(stats: &TaskStats) -> String {
+    let mut rows = vec![
+        vec!["total".to_string(), stats.total.to_string()],
+        vec!["active".to_string(), stats.active.to_string()],
+        vec!["overdue".to_string(), stats.overdue.to_string()],
+    ];
+
+    let counts = [
+        ("status", &stats.by_status),
+        ("project", &stats.by_project),
+        ("tag", &stats.by_tag),
+    ];
+    for (kind, counts) in counts.iter() {
+        for (name, count) in counts.iter() {
+            rows.push(vec![format!("{}:{}", kind, name), count.to_string()]);
+        }
+    }
+
+    align(&rows)
+}
+
+/// One line per difference: `+` for tasks only exported locally, `-` for
+/// those only on the server and `~` for those that differ.
+pub fn diff(diff: &TaskDiff) -> String {
+    if diff.is_empty() {
+        return "The server has the exported tasks".to_string();
+    }
+
+    let mut lines = Vec::new();
+    let mut push = |sign: &str, tasks: &[Task]| {
+        for task in tasks {
+            lines.push(format!("{} {} {}", sign, task.uuid(), task.description()));
+        }
+    };
+    push("+", &diff.added);
+    push("-", &diff.removed);
+    push("~", &diff.changed);
+
+    if diff.reordered {
+        lines.push("The tasks are in a different order".to_string());
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Segment;
+
+    #[test]
+    fn tables_line_up() {
+        let rows = vec![
+            vec!["1".to_string(), "stream".to_string(), "first".to_string()],
+            vec!["12".to_string(), "".to_string(), "second".to_string()],
+        ];
+        assert_eq!(
+            table(&["ID", "Project", "Description"], &rows),
+            "ID  Project  Description\n1   stream   first\n12           second"
+        );
+    }
+
+    #[test]
+    fn topics() {
+        let topic = Topic {
+            segments: vec![
+                Segment {
+                    title: "intro".to_string(),
+                    done: true,
+                },
+                Segment::new("outro".to_string()),
+            ],
+            ..Topic::new("title".to_string(), "description".to_string())
+        };
+
+        assert_eq!(
+            super::topic(&topic),
+            "Title        title\n\
+             Description  description\n\
+             Segment      [x] intro\n\
+             Segment      [ ] outro"
+        );
+    }
+}
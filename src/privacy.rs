@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+
+use regex::{NoExpand, Regex};
+use serde::Deserialize;
+use task_hookrs::task::Task;
+
+use crate::error::{Result, TSError};
+use crate::redact::Redaction;
+
+const EMAIL_PATTERN: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}";
+const URL_PATTERN: &str = r"\b(?:[A-Za-z][A-Za-z0-9+.-]*://|www\.)[^\s]+";
+
+/// What the client takes out of the tasks as soon as they are exported, so
+/// it never leaves the machine, whatever the server would show.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Privacy {
+    /// Applied here rather than on the server, with its placeholder also
+    /// standing in for scrubbed text.
+    #[serde(flatten)]
+    pub redaction: Redaction,
+    /// UDAs to drop, by name.
+    pub drop_udas: Vec<String>,
+    /// Projects to rename, with their sub-projects: `work.clientX = "work"`
+    /// puts `work.clientX.api` in `work.api`. The longest match wins.
+    pub project_aliases: BTreeMap<String, String>,
+    /// Scrub email addresses from descriptions and annotations.
+    pub scrub_emails: bool,
+    /// Scrub URLs from descriptions and annotations.
+    pub scrub_urls: bool,
+    /// Further regexes to scrub from descriptions and annotations.
+    pub scrub_patterns: Vec<String>,
+}
+
+impl Privacy {
+    pub fn apply(&self, tasks: Vec<Task>) -> Result<Vec<Task>> {
+        let placeholder = self.redaction.placeholder();
+        let scrubbers = self.scrubbers()?;
+        let scrub = |text: &mut String| {
+            for scrubber in &scrubbers {
+                if scrubber.is_match(text) {
+                    *text = scrubber
+                        .replace_all(text, NoExpand(placeholder))
+                        .into_owned();
+                }
+            }
+        };
+
+        Ok(self
+            .redaction
+            .apply(tasks)
+            .into_iter()
+            .map(|mut task| {
+                scrub(task.description_mut());
+
+                if let Some(annotations) = task.annotations_mut() {
+                    for annotation in annotations {
+                        scrub(annotation.description_mut());
+                    }
+                }
+
+                for uda in &self.drop_udas {
+                    task.uda_mut().remove(uda);
+                }
+
+                if let Some(project) = task.project_mut() {
+                    if let Some(alias) = self.alias(project) {
+                        *project = alias;
+                    }
+                }
+
+                task
+            })
+            .collect())
+    }
+
+    fn scrubbers(&self) -> Result<Vec<Regex>> {
+        let mut patterns: Vec<&str> = Vec::new();
+        if self.scrub_emails {
+            patterns.push(EMAIL_PATTERN);
+        }
+        if self.scrub_urls {
+            patterns.push(URL_PATTERN);
+        }
+        patterns.extend(self.scrub_patterns.iter().map(String::as_str));
+
+        patterns
+            .into_iter()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|e| {
+                    TSError::Error(format!("invalid scrub pattern '{}': {}", pattern, e))
+                })
+            })
+            .collect()
+    }
+
+    /// What `project` is renamed to, if it is aliased.
+    fn alias(&self, project: &str) -> Option<String> {
+        self.project_aliases
+            .iter()
+            .filter_map(|(from, to)| {
+                if project == from {
+                    Some((from.len(), to.clone()))
+                } else {
+                    project
+                        .strip_prefix(from.as_str())
+                        .and_then(|rest| rest.strip_prefix('.'))
+                        .map(|rest| (from.len(), format!("{}.{}", to, rest)))
+                }
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, alias)| alias)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tasks() -> Vec<Task> {
+        let task_json = r#"
+        [{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20201118T071926Z","description":"mail jane@example.com about https://example.com/spec","project":"work.clientX.api","billing":"ACME-42","annotations":[{"entry":"20201118T071926Z","description":"see www.example.com/notes"}]},
+         {"status":"pending","uuid":"8699cf59-59d4-4f42-812d-0d2de0cad191","entry":"20201120T064735Z","description":"call the bank","tags":["private"],"project":"work.clientXY"}]
+        "#;
+        serde_json::from_str(task_json).unwrap()
+    }
+
+    #[test]
+    fn empty_privacy_keeps_everything() {
+        assert_eq!(Privacy::default().apply(tasks()).unwrap(), tasks());
+    }
+
+    #[test]
+    fn scrubbing_and_redacting() {
+        let privacy = Privacy {
+            redaction: Redaction {
+                redact_tags: vec!["private".to_string()],
+                ..Redaction::default()
+            },
+            scrub_emails: true,
+            scrub_urls: true,
+            drop_udas: vec!["billing".to_string()],
+            ..Privacy::default()
+        };
+
+        let tasks = privacy.apply(tasks()).unwrap();
+        assert_eq!(tasks[0].description(), "mail [redacted] about [redacted]");
+        assert_eq!(
+            tasks[0].annotations().unwrap()[0].description(),
+            "see [redacted]"
+        );
+        assert!(tasks[0].uda().get("billing").is_none());
+        assert_eq!(tasks[1].description(), "[redacted]");
+    }
+
+    #[test]
+    fn placeholders_are_taken_literally() {
+        let privacy = Privacy {
+            redaction: Redaction {
+                placeholder: Some("$0 ${1}".to_string()),
+                ..Redaction::default()
+            },
+            scrub_emails: true,
+            ..Privacy::default()
+        };
+
+        let tasks = privacy.apply(tasks()).unwrap();
+        assert_eq!(
+            tasks[0].description(),
+            "mail $0 ${1} about https://example.com/spec"
+        );
+    }
+
+    #[test]
+    fn aliasing_projects() {
+        let mut project_aliases = BTreeMap::new();
+        project_aliases.insert("work.clientX".to_string(), "work".to_string());
+        project_aliases.insert("work".to_string(), "job".to_string());
+        let privacy = Privacy {
+            project_aliases,
+            ..Privacy::default()
+        };
+
+        let projects: Vec<_> = privacy
+            .apply(tasks())
+            .unwrap()
+            .iter()
+            .map(|task| task.project().cloned().unwrap())
+            .collect();
+        // `work.clientXY` isn't under `work.clientX`
+        assert_eq!(projects, vec!["work.api", "job.clientXY"]);
+    }
+
+    #[test]
+    fn invalid_patterns_are_errors() {
+        let privacy = Privacy {
+            scrub_patterns: vec!["(unclosed".to_string()],
+            ..Privacy::default()
+        };
+        assert!(privacy.apply(tasks()).is_err());
+    }
+}
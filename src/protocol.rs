@@ -0,0 +1,224 @@
+//! The commands websocket clients may send, and the frames answering them.
+//!
+//! A command is a JSON object naming the command, with an optional `id` that
+//! is echoed back:
+//!
+//! ```json
+//! {"id": 1, "command": "subscribe", "events": ["topic_updated"]}
+//! ```
+//!
+//! and is answered with either `{"event":"reply","id":..,"command":..,"data":..}`
+//! or `{"event":"error","id":..,"error":..}`. Sessions using a binary
+//! encoding send and receive the same structures in that encoding.
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use task_hookrs::task::Task;
+
+use crate::encoding::Encoding;
+use crate::error::Result;
+use crate::filter::TaskFilter;
+use crate::schema::Topic;
+use crate::session::{TASKS_UPDATED, TASK_VIEWS_UPDATED, TOPIC_UPDATED};
+use crate::snapshot::Snapshot;
+
+pub const REPLY: &str = "reply";
+pub const ERROR: &str = "error";
+
+/// Events a session can subscribe to.
+pub const EVENTS: &[&str] = &[TASKS_UPDATED, TOPIC_UPDATED, TASK_VIEWS_UPDATED];
+/// The events new sessions get. Task views are opt-in, since they carry
+/// the same tasks again.
+pub const DEFAULT_EVENTS: &[&str] = &[TASKS_UPDATED, TOPIC_UPDATED];
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Command {
+    /// Introduces the client, for the server's logs.
+    Hello {
+        client: String,
+        version: Option<String>,
+    },
+    Ping,
+    /// Asks for the current tasks and topic.
+    Snapshot,
+    /// Adds to the events subscribed to, and replaces the filter if given.
+    Subscribe {
+        #[serde(default)]
+        events: Vec<String>,
+        filter: Option<TaskFilter>,
+    },
+    Unsubscribe {
+        events: Vec<String>,
+    },
+    SetTasks {
+        data: Vec<Task>,
+    },
+    SetTopic {
+        data: Topic,
+    },
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Hello { .. } => "hello",
+            Command::Ping => "ping",
+            Command::Snapshot => "snapshot",
+            Command::Subscribe { .. } => "subscribe",
+            Command::Unsubscribe { .. } => "unsubscribe",
+            Command::SetTasks { .. } => "set-tasks",
+            Command::SetTopic { .. } => "set-topic",
+        }
+    }
+
+    /// Writes are only accepted from authenticated sessions.
+    pub fn is_write(&self) -> bool {
+        matches!(self, Command::SetTasks { .. } | Command::SetTopic { .. })
+    }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    /// Echoed back in the answer, `null` if the client didn't send one.
+    pub id: Value,
+    pub command: Command,
+}
+
+/// Parses a frame from a client, or gives the error answering it.
+pub fn parse(frame: &[u8], encoding: Encoding) -> std::result::Result<Request, Value> {
+    let value: Value = encoding
+        .decode(frame)
+        .map_err(|e| error(&Value::Null, &format!("invalid command: {}", e)))?;
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+
+    match serde_json::from_value(value) {
+        Ok(command) => Ok(Request { id, command }),
+        Err(e) => Err(error(&id, &format!("invalid command: {}", e))),
+    }
+}
+
+/// Maps the event names a client sent onto `EVENTS`.
+pub fn events(names: &[String]) -> std::result::Result<Vec<&'static str>, String> {
+    names
+        .iter()
+        .map(|name| {
+            EVENTS
+                .iter()
+                .find(|event| *event == name)
+                .copied()
+                .ok_or_else(|| format!("unknown event {}", name))
+        })
+        .collect()
+}
+
+pub fn reply(id: &Value, command: &str, data: Value) -> Value {
+    json!({"event": REPLY, "id": id, "command": command, "data": data})
+}
+
+pub fn error(id: &Value, message: &str) -> Value {
+    json!({"event": ERROR, "id": id, "error": message})
+}
+
+/// The reply to `snapshot`. In JSON it is put together from the snapshots'
+/// serialized bodies rather than serializing the values again.
+pub fn snapshot_reply(
+    id: &Value,
+    tasks: &Snapshot<Vec<Task>>,
+    topic: &Snapshot<Topic>,
+    encoding: Encoding,
+) -> Result<Bytes> {
+    let revision = std::cmp::max(tasks.revision, topic.revision);
+
+    if encoding.is_binary() {
+        let reply = json!({
+            "event": REPLY,
+            "id": id,
+            "command": "snapshot",
+            "revision": revision,
+            "data": {"tasks": tasks.value, "topic": topic.value},
+        });
+        return Ok(encoding.encode(&reply)?.into());
+    }
+
+    let prefix = format!(
+        r#"{{"event":{},"id":{},"command":"snapshot","revision":{},"data":{{"tasks":"#,
+        serde_json::to_string(REPLY)?,
+        serde_json::to_string(id)?,
+        revision
+    );
+    let separator = br#","topic":"#;
+
+    let mut buf = BytesMut::with_capacity(
+        prefix.len() + tasks.body.len() + separator.len() + topic.body.len() + 2,
+    );
+    buf.put_slice(prefix.as_bytes());
+    buf.put_slice(&tasks.body);
+    buf.put_slice(separator);
+    buf.put_slice(&topic.body);
+    buf.put_slice(b"}}");
+
+    Ok(buf.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_commands() {
+        let request = parse(
+            br#"{"id":"a","command":"hello","client":"overlay"}"#,
+            Encoding::Json,
+        )
+        .unwrap();
+        assert_eq!(request.id, json!("a"));
+        assert_eq!(
+            request.command,
+            Command::Hello {
+                client: "overlay".to_string(),
+                version: None
+            }
+        );
+
+        let request = Encoding::Cbor
+            .encode(&json!({"command": "subscribe", "events": ["topic_updated"]}))
+            .unwrap();
+        let request = parse(&request, Encoding::Cbor).unwrap();
+        assert_eq!(request.id, Value::Null);
+        assert_eq!(
+            events(&["topic_updated".to_string()]),
+            Ok(vec![TOPIC_UPDATED])
+        );
+        assert!(events(&["nope".to_string()]).is_err());
+    }
+
+    #[test]
+    fn unknown_commands_are_errors() {
+        let error = parse(br#"{"id":7,"command":"nope"}"#, Encoding::Json).unwrap_err();
+        assert_eq!(error["event"], ERROR);
+        assert_eq!(error["id"], 7);
+
+        let error = parse(b"not json", Encoding::Json).unwrap_err();
+        assert_eq!(error["id"], Value::Null);
+    }
+
+    #[test]
+    fn snapshot_replies() {
+        let tasks = Snapshot::new(TASKS_UPDATED, 3, Vec::<Task>::new()).unwrap();
+        let topic = Snapshot::new(TOPIC_UPDATED, 4, Topic::new("a".into(), "b".into())).unwrap();
+
+        let expected = json!({
+            "event": "reply",
+            "id": 1,
+            "command": "snapshot",
+            "revision": 4,
+            "data": {"tasks": [], "topic": {"title": "a", "description": "b"}},
+        });
+
+        for encoding in Encoding::ALL {
+            let reply = snapshot_reply(&json!(1), &tasks, &topic, *encoding).unwrap();
+            assert_eq!(encoding.decode::<Value>(&reply).unwrap(), expected);
+        }
+    }
+}
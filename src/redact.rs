@@ -0,0 +1,145 @@
+use serde::Deserialize;
+use task_hookrs::annotation::Annotation;
+use task_hookrs::task::Task;
+
+const DEFAULT_PLACEHOLDER: &str = "[redacted]";
+
+/// Rules for trimming a task list down to what may be shown publicly.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Redaction {
+    /// Only keep tasks in these projects or their sub-projects.
+    #[serde(default)]
+    pub projects: Vec<String>,
+    /// Drop tasks with any of these tags.
+    #[serde(default)]
+    pub exclude_tags: Vec<String>,
+    /// Replace the description of tasks with any of these tags.
+    #[serde(default)]
+    pub redact_tags: Vec<String>,
+    /// What redacted descriptions are replaced with.
+    pub placeholder: Option<String>,
+    #[serde(default)]
+    pub strip_annotations: bool,
+}
+
+impl Redaction {
+    pub fn is_empty(&self) -> bool {
+        self.projects.is_empty()
+            && self.exclude_tags.is_empty()
+            && self.redact_tags.is_empty()
+            && !self.strip_annotations
+    }
+
+    /// What redacted descriptions are replaced with.
+    pub fn placeholder(&self) -> &str {
+        self.placeholder.as_deref().unwrap_or(DEFAULT_PLACEHOLDER)
+    }
+
+    pub fn apply(&self, tasks: Vec<Task>) -> Vec<Task> {
+        if self.is_empty() {
+            return tasks;
+        }
+
+        tasks
+            .into_iter()
+            .filter(|task| {
+                in_projects(task, &self.projects) && !has_any_tag(task, &self.exclude_tags)
+            })
+            .map(|mut task| {
+                if has_any_tag(&task, &self.redact_tags) {
+                    *task.description_mut() = self.placeholder().to_string();
+                }
+
+                if self.strip_annotations {
+                    task.set_annotations::<Vec<Annotation>, Annotation>(None);
+                }
+
+                task
+            })
+            .collect()
+    }
+}
+
+/// Whether `task` is in any of `projects` or their sub-projects. Everything
+/// is when there are no projects.
+pub(crate) fn in_projects(task: &Task, projects: &[String]) -> bool {
+    if projects.is_empty() {
+        return true;
+    }
+
+    match task.project() {
+        Some(project) => projects
+            .iter()
+            .any(|p| project == p || project.starts_with(&format!("{}.", p))),
+        None => false,
+    }
+}
+
+pub(crate) fn has_any_tag(task: &Task, tags: &[String]) -> bool {
+    match task.tags() {
+        Some(task_tags) => task_tags.iter().any(|t| tags.contains(t)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tasks() -> Vec<Task> {
+        let task_json = r#"
+        [{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20201118T071926Z","description":"figure out frontend static asset storage/serving","tags":["@stream","@home"],"project":"twitch.task-display","annotations":[{"entry":"20201118T071926Z","description":"see the notes"}]},
+         {"status":"pending","uuid":"8699cf59-59d4-4f42-812d-0d2de0cad191","entry":"20201120T064735Z","description":"call the bank","tags":["@home","private"],"project":"personal"},
+         {"status":"pending","uuid":"6c2b9f0f-10a2-4e36-8f13-c160e7dbc3cb","entry":"20201125T062735Z","description":"secret feature","tags":["@stream","nda"],"project":"twitch"},
+         {"status":"pending","uuid":"02cb9bfc-fa96-4293-a71e-b833ca3e8795","entry":"20201127T085138Z","description":"no project"}]
+        "#;
+        serde_json::from_str(task_json).unwrap()
+    }
+
+    fn descriptions(tasks: &[Task]) -> Vec<&str> {
+        tasks.iter().map(|t| t.description().as_str()).collect()
+    }
+
+    #[test]
+    fn empty_redaction_keeps_everything() {
+        assert_eq!(Redaction::default().apply(tasks()), tasks());
+    }
+
+    #[test]
+    fn filtering_by_project() {
+        let redaction = Redaction {
+            projects: vec!["twitch".to_string()],
+            ..Redaction::default()
+        };
+
+        assert_eq!(
+            descriptions(&redaction.apply(tasks())),
+            vec![
+                "figure out frontend static asset storage/serving",
+                "secret feature"
+            ]
+        );
+    }
+
+    #[test]
+    fn excluding_and_redacting_by_tag() {
+        let redaction = Redaction {
+            exclude_tags: vec!["private".to_string()],
+            redact_tags: vec!["nda".to_string()],
+            strip_annotations: true,
+            ..Redaction::default()
+        };
+
+        let redacted = redaction.apply(tasks());
+
+        assert_eq!(
+            descriptions(&redacted),
+            vec![
+                "figure out frontend static asset storage/serving",
+                "[redacted]",
+                "no project"
+            ]
+        );
+        assert!(redacted.iter().all(|t| t.annotations().is_none()));
+    }
+}
@@ -0,0 +1,282 @@
+use log::{debug, info, warn};
+use std::time::Duration;
+
+use actix_http::ws::Item;
+use actix_web::rt::time::{delay_for, timeout};
+use actix_web::web;
+use actix_web_actors::ws::{Frame, Message};
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use task_hookrs::task::Task;
+
+use crate::app::{AppState, BEARER_PROTOCOL_PREFIX};
+use crate::error::{Result, TSError};
+use crate::schema::Topic;
+use crate::session::{RESYNC_REQUIRED, TASKS_UPDATED, TOPIC_UPDATED};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The upstream pings every few seconds, so this much silence means the
+/// connection is dead.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The upstream response body limit for tasks, in bytes.
+const MAX_TASKS_SIZE: usize = 8 * 1024 * 1024;
+/// Recorded as `updated_by` for what the relay stores.
+const UPDATED_BY: &str = "relay";
+
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    event: String,
+    data: Option<serde_json::Value>,
+}
+
+/// Mirrors the tasks and topic of an upstream task-streamer into `AppState`.
+///
+/// The upstream's public view is mirrored, or its private one when the relay
+/// has its api key, and the `AppState`'s own redaction is applied on top of
+/// that.
+pub struct Relay {
+    upstream: String,
+    key: Option<String>,
+    state: web::Data<AppState>,
+    client: awc::Client,
+}
+
+impl Relay {
+    pub fn new(upstream: &str, state: web::Data<AppState>) -> Self {
+        Relay {
+            upstream: upstream.trim_end_matches('/').to_string(),
+            key: None,
+            state,
+            client: awc::Client::new(),
+        }
+    }
+
+    /// Presents `key` to the upstream, which is needed to follow one with
+    /// `private_stream` set.
+    pub fn with_key(mut self, key: Option<String>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Follows the upstream forever, reconnecting with exponential backoff.
+    pub async fn run(self) {
+        let mut backoff = MIN_BACKOFF;
+
+        loop {
+            match self.follow(&mut backoff).await {
+                Ok(_) => info!("Upstream {} closed the connection", self.upstream),
+                Err(e) => warn!("Lost upstream {}: {}", self.upstream, e),
+            }
+
+            info!("Reconnecting to upstream in {:?}", backoff);
+            delay_for(backoff).await;
+            backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+        }
+    }
+
+    async fn follow(&self, backoff: &mut Duration) -> Result<()> {
+        let mut protocols = vec!["task-streamer".to_string()];
+        if let Some(ref key) = self.key {
+            protocols.push(format!("{}{}", BEARER_PROTOCOL_PREFIX, key));
+        }
+
+        let (_, mut socket) = self
+            .client
+            .ws(format!("{}/ws/", self.upstream))
+            .protocols(protocols)
+            .connect()
+            .await
+            .map_err(|e| TSError::Error(format!("could not connect: {}", e)))?;
+
+        // anything that changed while we were away is only picked up by
+        // fetching everything, and subscribing first means nothing is missed
+        // in between
+        self.resync().await?;
+        *backoff = MIN_BACKOFF;
+        info!("Following upstream {}", self.upstream);
+
+        let mut fragments = BytesMut::new();
+
+        loop {
+            let frame = match timeout(UPSTREAM_TIMEOUT, socket.next()).await {
+                Ok(Some(frame)) => frame?,
+                Ok(None) => return Ok(()),
+                Err(_) => return Err(TSError::Error("upstream timed out".to_string())),
+            };
+
+            match frame {
+                Frame::Text(text) => self.handle(&text).await?,
+                Frame::Continuation(Item::FirstText(data))
+                | Frame::Continuation(Item::Continue(data)) => fragments.extend_from_slice(&data),
+                Frame::Continuation(Item::Last(data)) => {
+                    fragments.extend_from_slice(&data);
+                    let text = fragments.split().freeze();
+                    self.handle(&text).await?;
+                }
+                Frame::Ping(msg) => socket.send(Message::Pong(msg)).await?,
+                Frame::Close(reason) => {
+                    debug!("Upstream closed with {:?}", reason);
+                    return Ok(());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    async fn handle(&self, text: &Bytes) -> Result<()> {
+        // older servers send the bare event name, without the data
+        let envelope = serde_json::from_slice(text).unwrap_or_else(|_| Envelope {
+            event: String::from_utf8_lossy(text).to_string(),
+            data: None,
+        });
+
+        debug!("Upstream event {}", envelope.event);
+
+        match (envelope.event.as_str(), envelope.data) {
+            (TASKS_UPDATED, Some(data)) => self
+                .state
+                .set_tasks_by(serde_json::from_value(data)?, Some(UPDATED_BY))
+                .map(drop),
+            (TASKS_UPDATED, None) => self
+                .state
+                .set_tasks_by(self.fetch_tasks().await?, Some(UPDATED_BY))
+                .map(drop),
+            (TOPIC_UPDATED, Some(data)) => self
+                .state
+                .set_topic_by(serde_json::from_value(data)?, Some(UPDATED_BY)),
+            (TOPIC_UPDATED, None) => self
+                .state
+                .set_topic_by(self.fetch_topic().await?, Some(UPDATED_BY)),
+            (RESYNC_REQUIRED, _) => self.resync().await,
+            (event, _) => {
+                debug!("Ignoring unknown upstream event {}", event);
+                Ok(())
+            }
+        }
+    }
+
+    async fn resync(&self) -> Result<()> {
+        self.state
+            .set_tasks_by(self.fetch_tasks().await?, Some(UPDATED_BY))?;
+        self.state
+            .set_topic_by(self.fetch_topic().await?, Some(UPDATED_BY))
+    }
+
+    fn get(&self, path: &str) -> awc::ClientRequest {
+        let request = self.client.get(format!("{}{}", self.upstream, path));
+        match self.key {
+            Some(ref key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+
+    async fn fetch_tasks(&self) -> Result<Vec<Task>> {
+        self.get("/api/v1/tasks")
+            .send()
+            .await
+            .map_err(|e| TSError::Error(format!("could not fetch tasks: {}", e)))?
+            .json()
+            .limit(MAX_TASKS_SIZE)
+            .await
+            .map_err(|e| TSError::Error(format!("could not read tasks: {}", e)))
+    }
+
+    async fn fetch_topic(&self) -> Result<Topic> {
+        self.get("/api/v1/topic")
+            .send()
+            .await
+            .map_err(|e| TSError::Error(format!("could not fetch topic: {}", e)))?
+            .json()
+            .await
+            .map_err(|e| TSError::Error(format!("could not read topic: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::app_config;
+    use crate::redact::Redaction;
+    use actix_web::{rt as actix_rt, test, App};
+
+    fn tasks() -> Vec<Task> {
+        let task_json = r#"
+        [{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20201118T071926Z","description":"figure out frontend static asset storage/serving","tags":["@stream","@home"],"project":"twitch.task-display"},
+         {"status":"pending","uuid":"8699cf59-59d4-4f42-812d-0d2de0cad191","entry":"20201120T064735Z","description":"call the bank","tags":["@home","private"],"project":"personal"}]
+        "#;
+        serde_json::from_str(task_json).unwrap()
+    }
+
+    /// Polls until `done` holds, giving up after five seconds.
+    async fn wait_until<F: Fn() -> bool>(done: F) -> bool {
+        for _ in 0..500 {
+            if done() {
+                return true;
+            }
+            delay_for(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[actix_rt::test]
+    async fn mirrors_upstream() {
+        let upstream = web::Data::new(AppState::new("Foo bar baz".to_string()));
+        upstream.set_tasks(tasks()).unwrap();
+
+        let upstream_state = upstream.clone();
+        let srv = test::start(move || {
+            App::new()
+                .app_data(upstream_state.clone())
+                .configure(app_config)
+        });
+
+        let redaction = Redaction {
+            exclude_tags: vec!["private".to_string()],
+            ..Redaction::default()
+        };
+        let mirror = web::Data::new(AppState::read_only().with_redaction(redaction));
+        let url = format!("http://{}", srv.addr());
+        actix_rt::spawn(Relay::new(&url, mirror.clone()).run());
+
+        // the initial sync
+        assert!(wait_until(|| mirror.public_tasks.load().value.len() == 1).await);
+        assert_eq!(mirror.public_tasks.load().value, tasks()[..1].to_vec());
+
+        // and updates pushed over the websocket
+        let topic = Topic::new("herp".to_string(), "derp".to_string());
+        upstream.set_topic(topic.clone()).unwrap();
+        assert!(wait_until(|| mirror.topic.load().value == topic).await);
+
+        upstream.set_tasks(Vec::new()).unwrap();
+        assert!(wait_until(|| mirror.public_tasks.load().value.is_empty()).await);
+    }
+
+    #[actix_rt::test]
+    async fn follows_private_streams_with_the_key() {
+        let upstream =
+            web::Data::new(AppState::new("secret".to_string()).with_private_stream(true));
+        upstream.set_tasks(tasks()).unwrap();
+
+        let upstream_state = upstream.clone();
+        let srv = test::start(move || {
+            App::new()
+                .app_data(upstream_state.clone())
+                .configure(app_config)
+        });
+
+        let mirror = web::Data::new(AppState::read_only());
+        let url = format!("http://{}", srv.addr());
+        let relay = Relay::new(&url, mirror.clone()).with_key(Some("secret".to_string()));
+        actix_rt::spawn(relay.run());
+
+        assert!(wait_until(|| mirror.public_tasks.load().value.len() == 2).await);
+
+        let topic = Topic::new("herp".to_string(), "derp".to_string());
+        upstream.set_topic(topic.clone()).unwrap();
+        assert!(wait_until(|| mirror.topic.load().value == topic).await);
+    }
+}
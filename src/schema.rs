@@ -1,14 +1,738 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use task_hookrs::date::Date;
+use task_hookrs::status::TaskStatus;
+use task_hookrs::task::Task;
+
+use crate::filter::{status_name, urgency};
+use crate::snapshot::Snapshot;
+
+/// `/api/v1`: tasks exactly as taskwarrior exports them, and the topic, as
+/// bare values.
+pub const SCHEMA_V1: u32 = 1;
+/// `/api/v2`: `DisplayTask`s and the topic, each wrapped in an `Envelope`.
+pub const SCHEMA_V2: u32 = 2;
 
+/// What the stream is about. Everything past the title and description is
+/// optional, and left out of the JSON when unset, so older clients see the
+/// topic they always have.
 #[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Topic {
     pub title: String,
     #[serde(default)]
     pub description: String,
+    /// The repo, PR, docs and such being worked on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<Link>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub goal: Option<String>,
+    /// The agenda, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Link {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Segment {
+    pub title: String,
+    #[serde(default)]
+    pub done: bool,
 }
 
 impl Topic {
     pub fn new(title: String, description: String) -> Self {
-        Topic { title, description }
+        Topic {
+            title,
+            description,
+            ..Topic::default()
+        }
+    }
+
+    /// Marks the first segment that isn't done as done, and gives it, or
+    /// `None` if they all are.
+    pub fn complete_next_segment(&mut self) -> Option<&Segment> {
+        let segment = self.segments.iter_mut().find(|segment| !segment.done)?;
+        segment.done = true;
+        Some(segment)
+    }
+}
+
+impl Link {
+    /// Reads `url` or `title=url`. A `=` only ends a title when what follows
+    /// has a scheme, so a `=` in a url's query stays part of it.
+    pub fn parse(link: &str) -> Self {
+        match link.split_once('=') {
+            Some((title, url)) if !title.contains("://") && has_scheme(url) => Link {
+                url: url.to_string(),
+                title: Some(title.to_string()),
+            },
+            _ => Link {
+                url: link.to_string(),
+                title: None,
+            },
+        }
+    }
+}
+
+/// Whether `url` starts with a scheme, like `https:` or `mailto:`.
+fn has_scheme(url: &str) -> bool {
+    match url.split_once(':') {
+        Some((scheme, _)) => {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        }
+        None => false,
+    }
+}
+
+impl Segment {
+    pub fn new(title: String) -> Self {
+        Segment { title, done: false }
+    }
+}
+
+/// A value along with where it came from, as `/api/v2` responds with it.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Envelope<T> {
+    pub revision: u64,
+    pub updated_at: DateTime<Utc>,
+    /// How the value was last set, e.g. `api` or `relay`. `None` for the
+    /// values the server starts with.
+    pub updated_by: Option<String>,
+    pub data: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new<S>(snapshot: &Snapshot<S>, data: T) -> Self {
+        Envelope {
+            revision: snapshot.revision,
+            updated_at: DateTime::<Utc>::from(snapshot.updated_at),
+            updated_by: snapshot.updated_by.clone(),
+            data,
+        }
+    }
+}
+
+/// A task as overlays show it, rather than as taskwarrior stores it: dates
+/// are RFC 3339 and the urgency is always there.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DisplayTask {
+    pub uuid: String,
+    pub id: Option<u64>,
+    pub description: String,
+    pub status: String,
+    pub project: Option<String>,
+    pub tags: Vec<String>,
+    pub urgency: f64,
+    pub entry: DateTime<Utc>,
+    pub start: Option<DateTime<Utc>>,
+    pub due: Option<DateTime<Utc>>,
+}
+
+impl From<&Task> for DisplayTask {
+    fn from(task: &Task) -> Self {
+        DisplayTask {
+            uuid: task.uuid().to_string(),
+            id: task.id(),
+            description: task.description().clone(),
+            status: status_name(task).unwrap_or_default(),
+            project: task.project().cloned(),
+            tags: task.tags().cloned().unwrap_or_default(),
+            urgency: urgency(task),
+            entry: to_utc(task.entry()),
+            start: task.start().map(to_utc),
+            due: task.due().map(to_utc),
+        }
+    }
+}
+
+/// What a push of the tasks left the server at. `epoch` tells a restarted
+/// server's revisions from those before.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Pushed {
+    pub epoch: u64,
+    pub revision: u64,
+}
+
+/// The changes between two lists of tasks, to send in place of the whole
+/// list. Only applies to the revision it was made against.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct TaskDelta {
+    pub epoch: u64,
+    pub base_revision: u64,
+    /// Added and changed tasks.
+    #[serde(default)]
+    pub upserted: Vec<Task>,
+    /// The uuids of tasks that are gone.
+    #[serde(default)]
+    pub removed: Vec<String>,
+    /// Every uuid, in order, when applying the rest wouldn't give it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<Vec<String>>,
+}
+
+impl TaskDelta {
+    /// The delta taking `old`, pushed as `base`, to `new`.
+    pub fn between(base: Pushed, old: &[Task], new: &[Task]) -> Self {
+        let old_by_uuid: HashMap<_, _> = old.iter().map(|task| (task.uuid(), task)).collect();
+        let new_uuids: HashSet<_> = new.iter().map(|task| task.uuid()).collect();
+
+        let mut delta = TaskDelta {
+            epoch: base.epoch,
+            base_revision: base.revision,
+            upserted: new
+                .iter()
+                .filter(|task| old_by_uuid.get(task.uuid()).copied() != Some(*task))
+                .cloned()
+                .collect(),
+            removed: old
+                .iter()
+                .filter(|task| !new_uuids.contains(task.uuid()))
+                .map(|task| task.uuid().to_string())
+                .collect(),
+            order: None,
+        };
+
+        let order: Vec<_> = new.iter().map(|task| task.uuid().to_string()).collect();
+        let applied: Vec<_> = delta
+            .apply(old.to_vec())
+            .iter()
+            .map(|task| task.uuid().to_string())
+            .collect();
+        if applied != order {
+            delta.order = Some(order);
+        }
+
+        delta
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upserted.is_empty() && self.removed.is_empty() && self.order.is_none()
+    }
+
+    /// Changed tasks stay where they were, new ones go last, and then
+    /// everything is put in `order` if there is one.
+    pub fn apply(&self, tasks: Vec<Task>) -> Vec<Task> {
+        let mut index: HashMap<String, usize> = tasks
+            .iter()
+            .enumerate()
+            .map(|(i, task)| (task.uuid().to_string(), i))
+            .collect();
+        let mut slots: Vec<Option<Task>> = tasks.into_iter().map(Some).collect();
+
+        for uuid in &self.removed {
+            if let Some(i) = index.remove(uuid) {
+                slots[i] = None;
+            }
+        }
+
+        for upserted in &self.upserted {
+            let uuid = upserted.uuid().to_string();
+            match index.get(&uuid) {
+                Some(&i) => slots[i] = Some(upserted.clone()),
+                None => {
+                    index.insert(uuid, slots.len());
+                    slots.push(Some(upserted.clone()));
+                }
+            }
+        }
+
+        let mut tasks: Vec<Task> = slots.into_iter().flatten().collect();
+
+        if let Some(ref order) = self.order {
+            let position: HashMap<_, _> = order
+                .iter()
+                .enumerate()
+                .map(|(i, uuid)| (uuid.as_str(), i))
+                .collect();
+            // anything the order leaves out goes last
+            tasks.sort_by_cached_key(|task| {
+                position
+                    .get(task.uuid().to_string().as_str())
+                    .copied()
+                    .unwrap_or(usize::MAX)
+            });
+        }
+
+        tasks
+    }
+}
+
+/// Counts over a list of tasks, for `client get stats`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TaskStats {
+    pub total: usize,
+    pub active: usize,
+    pub overdue: usize,
+    pub by_status: BTreeMap<String, usize>,
+    /// Tasks without a project aren't counted.
+    pub by_project: BTreeMap<String, usize>,
+    pub by_tag: BTreeMap<String, usize>,
+}
+
+impl TaskStats {
+    pub fn new(tasks: &[Task], now: DateTime<Utc>) -> Self {
+        let mut stats = TaskStats {
+            total: tasks.len(),
+            ..TaskStats::default()
+        };
+
+        for task in tasks {
+            let view = TaskView::new(task, now, &UrgencyLevels::default());
+            stats.active += view.is_active as usize;
+            stats.overdue += view.is_overdue as usize;
+
+            let task = view.task;
+            *stats.by_status.entry(task.status).or_default() += 1;
+            if let Some(project) = task.project {
+                *stats.by_project.entry(project).or_default() += 1;
+            }
+            for tag in task.tags {
+                *stats.by_tag.entry(tag).or_default() += 1;
+            }
+        }
+
+        stats
+    }
+}
+
+/// How a local export differs from the tasks the server has, for
+/// `client diff`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TaskDiff {
+    /// Only exported locally.
+    pub added: Vec<Task>,
+    /// Only on the server.
+    pub removed: Vec<Task>,
+    /// On both but different, as exported locally.
+    pub changed: Vec<Task>,
+    /// Whether the tasks on both are in a different order.
+    pub reordered: bool,
+}
+
+impl TaskDiff {
+    pub fn between(server: &[Task], local: &[Task]) -> Self {
+        let server_by_uuid: HashMap<_, _> = server.iter().map(|task| (task.uuid(), task)).collect();
+        let local_uuids: HashSet<_> = local.iter().map(|task| task.uuid()).collect();
+
+        let in_both = |tasks: &[Task]| -> Vec<_> {
+            tasks
+                .iter()
+                .map(|task| *task.uuid())
+                .filter(|uuid| server_by_uuid.contains_key(uuid) && local_uuids.contains(uuid))
+                .collect()
+        };
+
+        TaskDiff {
+            added: local
+                .iter()
+                .filter(|task| !server_by_uuid.contains_key(task.uuid()))
+                .cloned()
+                .collect(),
+            removed: server
+                .iter()
+                .filter(|task| !local_uuids.contains(task.uuid()))
+                .cloned()
+                .collect(),
+            changed: local
+                .iter()
+                .filter(|task| {
+                    matches!(server_by_uuid.get(task.uuid()), Some(on_server) if on_server != task)
+                })
+                .cloned()
+                .collect(),
+            reordered: in_both(server) != in_both(local),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && !self.reordered
+    }
+}
+
+/// A `DisplayTask` along with what overlays would otherwise each work out
+/// for themselves. The durations are as of when the view was made.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TaskView {
+    #[serde(flatten)]
+    pub task: DisplayTask,
+    pub is_active: bool,
+    pub age_seconds: i64,
+    /// Since the task was started, if it is active.
+    pub active_for_seconds: Option<i64>,
+    /// Negative once the task is overdue.
+    pub due_in_seconds: Option<i64>,
+    pub is_overdue: bool,
+    pub urgency_level: UrgencyLevel,
+    /// The project split on `.`, e.g. `["twitch", "task-display"]`.
+    pub project_path: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UrgencyLevel {
+    Low,
+    Medium,
+    High,
+}
+
+/// The urgencies at which tasks become `Medium` and `High`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "RawUrgencyLevels")]
+pub struct UrgencyLevels {
+    pub medium: f64,
+    pub high: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RawUrgencyLevels {
+    medium: f64,
+    high: f64,
+}
+
+impl Default for RawUrgencyLevels {
+    fn default() -> Self {
+        let levels = UrgencyLevels::default();
+        RawUrgencyLevels {
+            medium: levels.medium,
+            high: levels.high,
+        }
+    }
+}
+
+impl TryFrom<RawUrgencyLevels> for UrgencyLevels {
+    type Error = String;
+
+    fn try_from(raw: RawUrgencyLevels) -> Result<Self, Self::Error> {
+        if raw.medium.is_nan() || raw.high.is_nan() || raw.medium > raw.high {
+            return Err(format!(
+                "urgency level medium ({}) must not be above high ({})",
+                raw.medium, raw.high
+            ));
+        }
+
+        Ok(UrgencyLevels {
+            medium: raw.medium,
+            high: raw.high,
+        })
+    }
+}
+
+impl Default for UrgencyLevels {
+    fn default() -> Self {
+        UrgencyLevels {
+            medium: 5.0,
+            high: 10.0,
+        }
+    }
+}
+
+impl UrgencyLevels {
+    pub fn level(&self, urgency: f64) -> UrgencyLevel {
+        if urgency >= self.high {
+            UrgencyLevel::High
+        } else if urgency >= self.medium {
+            UrgencyLevel::Medium
+        } else {
+            UrgencyLevel::Low
+        }
+    }
+}
+
+impl TaskView {
+    pub fn new(task: &Task, now: DateTime<Utc>, levels: &UrgencyLevels) -> Self {
+        let active = is_active(task);
+        let pending = *task.status() == TaskStatus::Pending;
+        let seconds_since = |date: &Date| (now - to_utc(date)).num_seconds();
+        let due_in_seconds = task.due().map(|due| -seconds_since(due));
+        let task = DisplayTask::from(task);
+
+        TaskView {
+            is_active: active,
+            age_seconds: (now - task.entry).num_seconds().max(0),
+            active_for_seconds: match task.start {
+                Some(start) if active => Some((now - start).num_seconds()),
+                _ => None,
+            },
+            due_in_seconds,
+            is_overdue: pending && matches!(due_in_seconds, Some(due_in) if due_in < 0),
+            urgency_level: levels.level(task.urgency),
+            project_path: task
+                .project
+                .iter()
+                .flat_map(|project| project.split('.'))
+                .filter(|segment| !segment.is_empty())
+                .map(str::to_string)
+                .collect(),
+            task,
+        }
+    }
+}
+
+/// Started, and not yet done.
+pub(crate) fn is_active(task: &Task) -> bool {
+    task.start().is_some() && *task.status() == TaskStatus::Pending
+}
+
+pub(crate) fn to_utc(date: &Date) -> DateTime<Utc> {
+    DateTime::from_utc(**date, Utc)
+}
+
+/// RFC 3339, in UTC as taskwarrior keeps its dates.
+pub(crate) fn format_date(date: &Date) -> String {
+    date.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unset_fields_are_left_out() {
+        let topic = Topic::new("title".to_string(), "description".to_string());
+        assert_eq!(
+            serde_json::to_string(&topic).unwrap(),
+            r#"{"title":"title","description":"description"}"#
+        );
+
+        let topic: Topic = serde_json::from_str(r#"{"title":"title"}"#).unwrap();
+        assert_eq!(topic, Topic::new("title".to_string(), "".to_string()));
+    }
+
+    #[test]
+    fn completing_segments_in_order() {
+        let mut topic = Topic {
+            segments: vec![
+                Segment::new("intro".to_string()),
+                Segment::new("code".to_string()),
+            ],
+            ..Topic::default()
+        };
+
+        assert_eq!(topic.complete_next_segment().unwrap().title, "intro");
+        assert_eq!(topic.complete_next_segment().unwrap().title, "code");
+        assert!(topic.complete_next_segment().is_none());
+        assert!(topic.segments.iter().all(|segment| segment.done));
+    }
+
+    #[test]
+    fn display_tasks() {
+        let task: Task = serde_json::from_str(
+            r#"{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20201118T071926Z","description":"serve v2","id":13,"project":"twitch.task-display","urgency":2.5}"#,
+        )
+        .unwrap();
+        let snapshot = Snapshot::new("tasks_updated", 4, vec![task])
+            .unwrap()
+            .updated_by(Some("api"));
+
+        let envelope = Envelope::new(
+            &snapshot,
+            snapshot
+                .value
+                .iter()
+                .map(DisplayTask::from)
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(envelope.revision, 4);
+        assert_eq!(envelope.updated_by.as_deref(), Some("api"));
+
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(
+            json["data"][0],
+            serde_json::json!({
+                "uuid": "d3c2052f-31b5-4544-94bc-af3ef1b10c4b",
+                "id": 13,
+                "description": "serve v2",
+                "status": "pending",
+                "project": "twitch.task-display",
+                "tags": [],
+                "urgency": 2.5,
+                "entry": "2020-11-18T07:19:26Z",
+                "start": null,
+                "due": null,
+            })
+        );
+    }
+
+    #[test]
+    fn task_views() {
+        let task: Task = serde_json::from_str(
+            r#"{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20210101T000000Z","start":"20210101T120000Z","due":"20210102T000000Z","description":"compute views","project":"twitch.task-display","urgency":7}"#,
+        )
+        .unwrap();
+        let now = DateTime::parse_from_rfc3339("2021-01-02T01:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let view = TaskView::new(&task, now, &UrgencyLevels::default());
+        assert!(view.is_active);
+        assert_eq!(view.age_seconds, 25 * 3600);
+        assert_eq!(view.active_for_seconds, Some(13 * 3600));
+        assert_eq!(view.due_in_seconds, Some(-3600));
+        assert!(view.is_overdue);
+        assert_eq!(view.urgency_level, UrgencyLevel::Medium);
+        assert_eq!(view.project_path, vec!["twitch", "task-display"]);
+
+        let levels = UrgencyLevels {
+            medium: 1.0,
+            high: 7.0,
+        };
+        assert_eq!(
+            TaskView::new(&task, now, &levels).urgency_level,
+            UrgencyLevel::High
+        );
+
+        let json = serde_json::to_value(&view).unwrap();
+        assert_eq!(json["description"], "compute views");
+        assert_eq!(json["urgency_level"], "medium");
+    }
+
+    #[test]
+    fn urgency_levels_must_be_ordered() {
+        let parse = |json: &str| serde_json::from_str::<UrgencyLevels>(json);
+
+        assert_eq!(parse("{}").unwrap(), UrgencyLevels::default());
+        assert_eq!(parse(r#"{"high": 20.0}"#).unwrap().medium, 5.0);
+        assert!(parse(r#"{"medium": 8.0, "high": 3.0}"#).is_err());
+        assert!(parse(r#"{"medium": 12.0}"#).is_err());
+    }
+
+    fn tasks(json: &str) -> Vec<Task> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn task_deltas() {
+        let base = Pushed {
+            epoch: 1,
+            revision: 2,
+        };
+        let old = tasks(
+            r#"[{"uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","status":"pending","entry":"20201118T071926Z","description":"one"},
+                {"uuid":"8699cf59-59d4-4f42-812d-0d2de0cad191","status":"pending","entry":"20201118T071926Z","description":"two"},
+                {"uuid":"6c2b9f0f-10a2-4e36-8f13-c160e7dbc3cb","status":"pending","entry":"20201118T071926Z","description":"three"}]"#,
+        );
+        let new = tasks(
+            r#"[{"uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","status":"pending","entry":"20201118T071926Z","description":"one, changed"},
+                {"uuid":"6c2b9f0f-10a2-4e36-8f13-c160e7dbc3cb","status":"pending","entry":"20201118T071926Z","description":"three"},
+                {"uuid":"02cb9bfc-fa96-4293-a71e-b833ca3e8795","status":"pending","entry":"20201118T071926Z","description":"four"}]"#,
+        );
+
+        let delta = TaskDelta::between(base, &old, &new);
+        let upserted: Vec<_> = delta.upserted.iter().map(|t| t.description()).collect();
+        assert_eq!(upserted, vec!["one, changed", "four"]);
+        assert_eq!(delta.removed, vec!["8699cf59-59d4-4f42-812d-0d2de0cad191"]);
+        // applying it already gives the new order
+        assert_eq!(delta.order, None);
+        assert_eq!(delta.apply(old.clone()), new);
+
+        // the same tasks, reordered
+        let reordered: Vec<_> = new.iter().rev().cloned().collect();
+        let delta = TaskDelta::between(base, &new, &reordered);
+        assert!(delta.upserted.is_empty());
+        assert!(delta.order.is_some());
+        assert_eq!(delta.apply(new.clone()), reordered);
+
+        assert!(TaskDelta::between(base, &new, &new).is_empty());
+    }
+
+    #[test]
+    fn task_stats() {
+        let tasks = tasks(
+            r#"[{"uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","status":"pending","entry":"20201118T071926Z","description":"one","project":"stream","tags":["home"],"start":"20201119T071926Z"},
+                {"uuid":"8699cf59-59d4-4f42-812d-0d2de0cad191","status":"pending","entry":"20201118T071926Z","description":"two","project":"stream","due":"20201120T000000Z"},
+                {"uuid":"6c2b9f0f-10a2-4e36-8f13-c160e7dbc3cb","status":"completed","entry":"20201118T071926Z","description":"three","tags":["home"]}]"#,
+        );
+        let now = DateTime::parse_from_rfc3339("2020-12-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let stats = TaskStats::new(&tasks, now);
+        assert_eq!(stats.total, 3);
+        assert_eq!(stats.active, 1);
+        assert_eq!(stats.overdue, 1);
+        assert_eq!(stats.by_status["pending"], 2);
+        assert_eq!(stats.by_status["completed"], 1);
+        assert_eq!(stats.by_project.len(), 1);
+        assert_eq!(stats.by_project["stream"], 2);
+        assert_eq!(stats.by_tag["home"], 2);
+    }
+
+    #[test]
+    fn task_diffs() {
+        let server = tasks(
+            r#"[{"uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","status":"pending","entry":"20201118T071926Z","description":"one"},
+                {"uuid":"8699cf59-59d4-4f42-812d-0d2de0cad191","status":"pending","entry":"20201118T071926Z","description":"two"},
+                {"uuid":"6c2b9f0f-10a2-4e36-8f13-c160e7dbc3cb","status":"pending","entry":"20201118T071926Z","description":"three"}]"#,
+        );
+        let mut local = server.clone();
+        assert!(TaskDiff::between(&server, &local).is_empty());
+
+        *local[0].description_mut() = "one, changed".to_string();
+        local.remove(1);
+        local.insert(0, tasks(
+            r#"[{"uuid":"02cb9bfc-fa96-4293-a71e-b833ca3e8795","status":"pending","entry":"20201118T071926Z","description":"four"}]"#,
+        ).remove(0));
+
+        let diff = TaskDiff::between(&server, &local);
+        let descriptions = |tasks: &[Task]| -> Vec<String> {
+            tasks
+                .iter()
+                .map(|task| task.description().clone())
+                .collect()
+        };
+        assert_eq!(descriptions(&diff.added), vec!["four"]);
+        assert_eq!(descriptions(&diff.removed), vec!["two"]);
+        assert_eq!(descriptions(&diff.changed), vec!["one, changed"]);
+        // a task added in front doesn't move the others
+        assert!(!diff.reordered);
+
+        local.swap(1, 2);
+        assert!(TaskDiff::between(&server, &local).reordered);
+    }
+
+    #[test]
+    fn parsing_links() {
+        assert_eq!(
+            Link::parse("repo=https://example.com/repo"),
+            Link {
+                url: "https://example.com/repo".to_string(),
+                title: Some("repo".to_string()),
+            }
+        );
+        assert_eq!(
+            Link::parse("https://example.com/search?q=tasks").title,
+            None
+        );
+        assert_eq!(
+            Link::parse("example.com/search?q=tasks"),
+            Link {
+                url: "example.com/search?q=tasks".to_string(),
+                title: None,
+            }
+        );
+        assert_eq!(
+            Link::parse("mail=mailto:me@example.com").title,
+            Some("mail".to_string())
+        );
     }
 }
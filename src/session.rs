@@ -1,30 +1,139 @@
-use log::{debug, error, info};
-use std::collections::HashMap;
+use log::{debug, error, info, warn};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use actix::dev::SendError;
 use actix::prelude::*;
+use actix_http::ws::Item;
+use actix_web::http::Method;
+use actix_web::web;
 use actix_web_actors::ws;
+use bytes::Bytes;
+use chrono::Utc;
+use futures::channel::mpsc;
+use serde_json::{json, Value};
+use task_hookrs::task::Task;
 use uuid;
 
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(20);
+use crate::app::AppState;
+use crate::encoding::Encoding;
+use crate::filter::TaskFilter;
+use crate::limits::SessionPermit;
+use crate::protocol::{self, Command, DEFAULT_EVENTS};
+use crate::schema::{TaskView, Topic, UrgencyLevels};
+use crate::snapshot::Snapshot;
 
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+pub(crate) const CLIENT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Recorded as `updated_by` for changes made with websocket commands.
+const UPDATED_BY: &str = "websocket";
+
+/// Number of undelivered messages a session may have queued before further
+/// updates to it are refused.
+const SESSION_MAILBOX_CAPACITY: usize = 8;
+
+/// Number of updates a `Watch` channel may hold before its watcher is
+/// dropped.
+pub const WATCH_CAPACITY: usize = 8;
+
+/// Number of consecutive updates a session may miss before it is dropped.
+const MAX_MISSED_UPDATES: usize = 16;
+
+/// Events are sent as `{"event": ..., "data": ...}` envelopes to clients
+/// that offer one of the `task-streamer` subprotocols. Clients that don't
+/// are only ever sent the bare name of these two, as they were before the
+/// envelopes, and fetch the tasks or topic themselves when they get it.
+pub const TASKS_UPDATED: &str = "tasks-updated";
+pub const TOPIC_UPDATED: &str = "topic_updated";
+pub const TASK_VIEWS_UPDATED: &str = "task-views-updated";
+pub const RESYNC_REQUIRED: &str = "resync-required";
+
+/// Sent in place of the regular notification to a session that missed
+/// updates, telling it to re-fetch everything.
+const RESYNC_REQUIRED_ENVELOPE: &[u8] = br#"{"event":"resync-required"}"#;
+
+fn resync_required(encoding: Encoding) -> Bytes {
+    if !encoding.is_binary() {
+        return Bytes::from_static(RESYNC_REQUIRED_ENVELOPE);
+    }
+
+    match encoding.encode(&json!({ "event": RESYNC_REQUIRED })) {
+        Ok(envelope) => envelope.into(),
+        Err(e) => {
+            error!("Could not encode resync notification: {}", e);
+            Bytes::new()
+        }
+    }
+}
+
+/// A serialized notification, shared between every session it is sent to.
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Message(pub String);
+pub struct Message(pub Bytes);
+
+/// Carries the new tasks, for each `View`.
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "()")]
+pub struct TasksUpdated {
+    pub private: Arc<Snapshot<Vec<Task>>>,
+    pub public: Arc<Snapshot<Vec<Task>>>,
+    /// What the `TaskView`s sent along are made with.
+    pub urgency_levels: UrgencyLevels,
+}
+
+impl TasksUpdated {
+    pub fn view(&self, view: View) -> &Arc<Snapshot<Vec<Task>>> {
+        match view {
+            View::Private => &self.private,
+            View::Public => &self.public,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "()")]
+pub struct TopicUpdated(pub Arc<Snapshot<Topic>>);
+
+/// What a `Watch` channel is sent.
+#[derive(Clone, Debug)]
+pub enum Update {
+    Tasks(TasksUpdated),
+    Topic(TopicUpdated),
+}
 
+/// Asks for every update on a channel, for consumers that aren't websocket
+/// sessions. Watchers that let the channel fill up are dropped, closing it.
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct TasksUpdated;
+pub struct Watch(pub mpsc::Sender<Update>);
 
+/// Tells a session it is being dropped by the `SessionManager`.
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct TopicUpdated;
+pub struct Evict;
+
+/// Which version of the tasks a session is sent.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum View {
+    /// Redacted, for anonymous clients.
+    Public,
+    /// Everything, for clients that presented the api key.
+    Private,
+}
 
 #[derive(Message)]
 #[rtype(String)]
 pub struct Connect {
     pub addr: Recipient<Message>,
+    pub evict: Recipient<Evict>,
+    pub view: View,
+    pub filter: TaskFilter,
+    pub encoding: Encoding,
+    pub legacy: bool,
 }
 
 #[derive(Message)]
@@ -33,35 +142,322 @@ pub struct Disconnect {
     pub id: String,
 }
 
+/// Replaces the events a session is sent, and the slice of the tasks.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Subscribe {
+    pub id: String,
+    pub events: HashSet<&'static str>,
+    pub filter: TaskFilter,
+}
+
 pub struct TaskSession {
     pub hb: Instant,
     pub addr: Addr<SessionManager>,
     pub id: String,
+    pub view: View,
+    /// Counts against the session limits until the session ends.
+    pub permit: SessionPermit,
+    state: web::Data<AppState>,
+    /// The name and version the client gave in its `hello`.
+    client: Option<String>,
+    events: HashSet<&'static str>,
+    filter: TaskFilter,
+    encoding: Encoding,
+    /// Whether the client offered no `task-streamer` subprotocol, see
+    /// `SessionHandle::legacy`.
+    legacy: bool,
+}
+
+#[derive(Debug)]
+struct SessionHandle {
+    addr: Recipient<Message>,
+    evict: Recipient<Evict>,
+    view: View,
+    events: HashSet<&'static str>,
+    filter: TaskFilter,
+    encoding: Encoding,
+    /// Digest of the last slice of the tasks delivered, if it is known.
+    sent: Option<u64>,
+    missed: usize,
+    /// Clients that offer none of the `task-streamer` subprotocols predate
+    /// the envelopes, and are sent the bare `tasks-updated` text they always
+    /// were, as a single frame, on every task update and nothing else.
+    legacy: bool,
+}
+
+/// A session's slice of the tasks, ready to send.
+struct Slice {
+    snapshot: Arc<Snapshot<Vec<Task>>>,
+    digest: u64,
+}
+
+impl Slice {
+    fn new(snapshot: Arc<Snapshot<Vec<Task>>>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        snapshot.body.hash(&mut hasher);
+
+        Slice {
+            snapshot,
+            digest: hasher.finish(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct SessionManager {
-    sessions: HashMap<String, Recipient<Message>>,
+    sessions: HashMap<String, SessionHandle>,
+    watchers: Vec<mpsc::Sender<Update>>,
 }
 
 impl SessionManager {
     pub fn new() -> Self {
         SessionManager {
             sessions: HashMap::new(),
+            watchers: Vec::new(),
+        }
+    }
+
+    fn notify_watchers(&mut self, update: Update) {
+        self.watchers
+            .retain_mut(|watcher| match watcher.try_send(update.clone()) {
+                Ok(()) => true,
+                Err(e) => {
+                    if e.is_full() {
+                        warn!("Dropping a watcher that stopped reading");
+                    }
+                    false
+                }
+            });
+    }
+
+    fn connect(
+        &mut self,
+        addr: Recipient<Message>,
+        evict: Recipient<Evict>,
+        view: View,
+        filter: TaskFilter,
+        encoding: Encoding,
+    ) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+
+        self.sessions.insert(
+            id.clone(),
+            SessionHandle {
+                addr,
+                evict,
+                view,
+                events: DEFAULT_EVENTS.iter().copied().collect(),
+                filter,
+                encoding,
+                sent: None,
+                missed: 0,
+                legacy: false,
+            },
+        );
+
+        id
+    }
+
+    /// Sends every session subscribed to topic updates the new topic.
+    pub fn notify_topic(&mut self, topic: &Snapshot<Topic>) {
+        self.broadcast(TOPIC_UPDATED, |session| {
+            match topic.envelope_in(session.encoding) {
+                Ok(envelope) => Some((envelope, None)),
+                Err(e) => {
+                    error!("Could not encode topic: {}", e);
+                    None
+                }
+            }
+        });
+    }
+
+    /// Sends every session subscribed to task updates its slice of the tasks,
+    /// unless that is what it was last sent.
+    ///
+    /// Each distinct slice is only serialized once per encoding, however many
+    /// sessions share its view and filter.
+    pub fn notify_tasks(
+        &mut self,
+        private: &Arc<Snapshot<Vec<Task>>>,
+        public: &Arc<Snapshot<Vec<Task>>>,
+    ) {
+        let mut slices: HashMap<View, HashMap<TaskFilter, Slice>> = HashMap::new();
+
+        for session in self.sessions.values() {
+            if session.legacy {
+                continue;
+            }
+
+            let by_filter = slices.entry(session.view).or_default();
+
+            if by_filter.contains_key(&session.filter) {
+                continue;
+            }
+
+            let snapshot = match session.view {
+                View::Private => private,
+                View::Public => public,
+            };
+
+            if session.filter.is_empty() {
+                by_filter.insert(TaskFilter::default(), Slice::new(snapshot.clone()));
+                continue;
+            }
+
+            let tasks = session.filter.apply(&snapshot.value);
+            match Snapshot::new(TASKS_UPDATED, snapshot.revision, tasks) {
+                Ok(filtered) => {
+                    by_filter.insert(session.filter.clone(), Slice::new(Arc::new(filtered)));
+                }
+                Err(e) => error!("Could not serialize tasks for {:?}: {}", session.filter, e),
+            }
         }
+
+        self.broadcast(TASKS_UPDATED, |session| {
+            let slice = slices
+                .get(&session.view)
+                .and_then(|by_filter| by_filter.get(&session.filter))?;
+
+            match slice.snapshot.envelope_in(session.encoding) {
+                Ok(envelope) => Some((envelope, Some(slice.digest))),
+                Err(e) => {
+                    error!("Could not encode tasks: {}", e);
+                    None
+                }
+            }
+        });
     }
 
-    pub fn notify_update(&self, msg: &str) {
+    /// Sends every session subscribed to task views its slice of the tasks
+    /// as `TaskView`s. The views change with time, so unlike the tasks they
+    /// are sent on every update.
+    pub fn notify_task_views(&mut self, tasks: &TasksUpdated) {
+        let now = Utc::now();
+        let mut slices: HashMap<View, HashMap<TaskFilter, Snapshot<Vec<TaskView>>>> =
+            HashMap::new();
+
         for session in self.sessions.values() {
-            match session.do_send(Message(msg.to_string())) {
-                // TODO: something better - MCL - 2020-11-24
-                Ok(_) => (),
-                Err(_) => (),
+            if !session.events.contains(TASK_VIEWS_UPDATED) {
+                continue;
+            }
+
+            let by_filter = slices.entry(session.view).or_default();
+            if by_filter.contains_key(&session.filter) {
+                continue;
+            }
+
+            let snapshot = tasks.view(session.view);
+            let views = session
+                .filter
+                .apply(&snapshot.value)
+                .iter()
+                .map(|task| TaskView::new(task, now, &tasks.urgency_levels))
+                .collect();
+            match Snapshot::new(TASK_VIEWS_UPDATED, snapshot.revision, views) {
+                Ok(views) => {
+                    by_filter.insert(session.filter.clone(), views);
+                }
+                Err(e) => error!(
+                    "Could not serialize task views for {:?}: {}",
+                    session.filter, e
+                ),
+            }
+        }
+
+        self.broadcast(TASK_VIEWS_UPDATED, |session| {
+            let views = slices
+                .get(&session.view)
+                .and_then(|by_filter| by_filter.get(&session.filter))?;
+
+            match views.envelope_in(session.encoding) {
+                Ok(envelope) => Some((envelope, None)),
+                Err(e) => {
+                    error!("Could not encode task views: {}", e);
+                    None
+                }
+            }
+        });
+    }
+
+    /// Sends every session subscribed to `event` the payload `pick` gives
+    /// for it, without waiting on any of them. Payloads with a digest are
+    /// skipped for sessions that were last sent the same one.
+    ///
+    /// A session whose mailbox is full misses the update, and is sent
+    /// a resync notification instead once it has room again. Sessions that fall
+    /// too far behind are evicted, and sessions whose mailbox is closed are
+    /// removed.
+    fn broadcast<F>(&mut self, event: &str, pick: F)
+    where
+        F: Fn(&SessionHandle) -> Option<(Bytes, Option<u64>)>,
+    {
+        let mut dropped = Vec::new();
+
+        for (id, session) in self.sessions.iter_mut() {
+            if !session.events.contains(event) {
+                continue;
+            }
+
+            let (payload, digest) = if session.legacy {
+                // telling it to re-fetch the tasks doubles as the resync
+                let name = match event {
+                    _ if session.missed > 0 => TASKS_UPDATED,
+                    TASKS_UPDATED => TASKS_UPDATED,
+                    TOPIC_UPDATED => TOPIC_UPDATED,
+                    _ => continue,
+                };
+                (Bytes::from_static(name.as_bytes()), None)
+            } else if session.missed > 0 {
+                (resync_required(session.encoding), None)
+            } else {
+                match pick(session) {
+                    Some((_, Some(digest))) if session.sent == Some(digest) => continue,
+                    Some(picked) => picked,
+                    None => continue,
+                }
             };
+
+            match session.addr.try_send(Message(payload)) {
+                Ok(_) => {
+                    // after a resync the client could have anything
+                    if session.missed > 0 {
+                        session.sent = None;
+                    } else if digest.is_some() {
+                        session.sent = digest;
+                    }
+                    session.missed = 0;
+                }
+                Err(SendError::Full(_)) => {
+                    session.missed += 1;
+
+                    if session.missed > MAX_MISSED_UPDATES {
+                        warn!("Session {} is not keeping up, dropping it", id);
+                        let _ = session.evict.do_send(Evict);
+                        dropped.push(id.clone());
+                    } else {
+                        debug!("Session {} is lagging, missed {}", id, session.missed);
+                    }
+                }
+                Err(SendError::Closed(_)) => {
+                    warn!("Session {} is closed, removing it", id);
+                    dropped.push(id.clone());
+                }
+            }
+        }
+
+        for id in dropped {
+            self.sessions.remove(&id);
         }
     }
 }
 
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Actor for SessionManager {
     type Context = Context<Self>;
 }
@@ -72,10 +468,10 @@ impl Handler<Connect> for SessionManager {
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
         debug!("New session established");
 
-        let id = uuid::Uuid::new_v4().to_string();
-
-        self.sessions.insert(id.clone(), msg.addr);
-
+        let id = self.connect(msg.addr, msg.evict, msg.view, msg.filter, msg.encoding);
+        if let Some(session) = self.sessions.get_mut(&id) {
+            session.legacy = msg.legacy;
+        }
         id
     }
 }
@@ -92,13 +488,30 @@ impl Handler<Disconnect> for SessionManager {
     }
 }
 
+impl Handler<Subscribe> for SessionManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) -> Self::Result {
+        if let Some(session) = self.sessions.get_mut(&msg.id) {
+            session.events = msg.events;
+
+            if session.filter != msg.filter {
+                session.filter = msg.filter;
+                session.sent = None;
+            }
+        }
+    }
+}
+
 impl Handler<TasksUpdated> for SessionManager {
     type Result = ();
 
-    fn handle(&mut self, _: TasksUpdated, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: TasksUpdated, _: &mut Context<Self>) -> Self::Result {
         info!("Notifying tasks updated");
 
-        self.notify_update("tasks-updated");
+        self.notify_tasks(&msg.private, &msg.public);
+        self.notify_task_views(&msg);
+        self.notify_watchers(Update::Tasks(msg));
 
         ()
     }
@@ -107,16 +520,47 @@ impl Handler<TasksUpdated> for SessionManager {
 impl Handler<TopicUpdated> for SessionManager {
     type Result = ();
 
-    fn handle(&mut self, _: TopicUpdated, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: TopicUpdated, _: &mut Context<Self>) -> Self::Result {
         info!("Notifying topic updated");
 
-        self.notify_update("topic_updated");
+        self.notify_topic(&msg.0);
+        self.notify_watchers(Update::Topic(msg));
 
         ()
     }
 }
 
+impl Handler<Watch> for SessionManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
+        self.watchers.push(msg.0);
+    }
+}
+
 impl TaskSession {
+    pub fn new(
+        state: web::Data<AppState>,
+        view: View,
+        filter: TaskFilter,
+        permit: SessionPermit,
+        encoding: Option<Encoding>,
+    ) -> Self {
+        TaskSession {
+            hb: Instant::now(),
+            addr: state.session_manager.clone(),
+            id: "".to_string(),
+            view,
+            permit,
+            state,
+            client: None,
+            events: DEFAULT_EVENTS.iter().copied().collect(),
+            filter,
+            encoding: encoding.unwrap_or(Encoding::Json),
+            legacy: encoding.is_none(),
+        }
+    }
+
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
@@ -132,18 +576,159 @@ impl TaskSession {
             ctx.ping(b"");
         });
     }
+
+    /// Answers a command frame from the client. Text frames are always
+    /// JSON, whatever the session's encoding.
+    fn command(&mut self, frame: &[u8], encoding: Encoding, ctx: &mut ws::WebsocketContext<Self>) {
+        let request = match protocol::parse(frame, encoding) {
+            Ok(request) => request,
+            Err(error) => return self.send(&error, ctx),
+        };
+        let id = request.id;
+        let name = request.command.name();
+
+        if request.command.is_write() {
+            if self.view != View::Private {
+                return self.send(&protocol::error(&id, "unauthorized"), ctx);
+            }
+
+            // the same limit as writes over HTTP
+            if let Err(retry_after) = self.state.limiter.check(&Method::POST, self.permit.ip()) {
+                let message = format!("rate limited, retry in {}s", retry_after.as_secs() + 1);
+                return self.send(&protocol::error(&id, &message), ctx);
+            }
+        }
+
+        let result = match request.command {
+            Command::Hello { client, version } => {
+                let client = match version {
+                    Some(version) => format!("{} {}", client, version),
+                    None => client,
+                };
+                info!("Session {} is {}", self.id, client);
+                self.client = Some(client);
+
+                Ok(json!({
+                    "server": "task-streamer",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "session": self.id,
+                    "authenticated": self.view == View::Private,
+                }))
+            }
+            Command::Ping => Ok(Value::Null),
+            Command::Snapshot => {
+                let tasks = self.state.tasks_for(self.view);
+                let topic = self.state.topic.load();
+
+                let reply = if self.filter.is_empty() {
+                    protocol::snapshot_reply(&id, &tasks, &topic, self.encoding)
+                } else {
+                    Snapshot::new(
+                        TASKS_UPDATED,
+                        tasks.revision,
+                        self.filter.apply(&tasks.value),
+                    )
+                    .and_then(|tasks| protocol::snapshot_reply(&id, &tasks, &topic, self.encoding))
+                };
+
+                return match reply {
+                    Ok(reply) => self.write_shared(reply, ctx),
+                    Err(e) => {
+                        error!("Could not build snapshot: {}", e);
+                        self.send(&protocol::error(&id, "internal error"), ctx)
+                    }
+                };
+            }
+            Command::Subscribe { events, filter } => protocol::events(&events).map(|events| {
+                self.events.extend(events);
+                if let Some(filter) = filter {
+                    self.filter = filter;
+                }
+                self.subscribed()
+            }),
+            Command::Unsubscribe { events } => protocol::events(&events).map(|events| {
+                for event in events {
+                    self.events.remove(event);
+                }
+                self.subscribed()
+            }),
+            Command::SetTasks { data } => self
+                .state
+                .set_tasks_by(data, Some(UPDATED_BY))
+                .map(|_| Value::Null)
+                .map_err(|e| e.to_string()),
+            Command::SetTopic { data } => self
+                .state
+                .set_topic_by(data, Some(UPDATED_BY))
+                .map(|_| Value::Null)
+                .map_err(|e| e.to_string()),
+        };
+
+        match result {
+            Ok(data) => self.send(&protocol::reply(&id, name, data), ctx),
+            Err(message) => self.send(&protocol::error(&id, &message), ctx),
+        }
+    }
+
+    /// Sends an answer in the session's encoding.
+    fn send(&self, value: &Value, ctx: &mut ws::WebsocketContext<Self>) {
+        match self.encoding {
+            Encoding::Json => ctx.text(value.to_string()),
+            encoding => match encoding.encode(value) {
+                Ok(payload) => ctx.binary(payload),
+                Err(e) => error!("Could not encode answer: {}", e),
+            },
+        }
+    }
+
+    /// Tells the `SessionManager` about a change in subscriptions, and
+    /// gives what is now subscribed to.
+    fn subscribed(&self) -> Value {
+        self.addr.do_send(Subscribe {
+            id: self.id.clone(),
+            events: self.events.clone(),
+            filter: self.filter.clone(),
+        });
+
+        let mut events: Vec<_> = self.events.iter().collect();
+        events.sort();
+        json!({"events": events, "filter": self.filter})
+    }
+
+    fn write_shared(&self, payload: Bytes, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.encoding.is_binary() {
+            return ctx.binary(payload);
+        }
+
+        // clients from before the envelopes needn't reassemble fragments
+        if self.legacy {
+            return ctx.text(String::from_utf8_lossy(&payload));
+        }
+
+        // `ctx.text` would need its own `String` copy of the payload for every
+        // session. Sending it as a single text fragment followed by an empty
+        // final one lets the shared buffer go straight to the encoder.
+        ctx.write_raw(ws::Message::Continuation(Item::FirstText(payload)));
+        ctx.write_raw(ws::Message::Continuation(Item::Last(Bytes::new())));
+    }
 }
 
 impl Actor for TaskSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(SESSION_MAILBOX_CAPACITY);
         self.hb(ctx);
 
         let addr = ctx.address();
         self.addr
             .send(Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                evict: addr.recipient(),
+                view: self.view,
+                filter: self.filter.clone(),
+                encoding: self.encoding,
+                legacy: self.legacy,
             })
             .into_actor(self)
             .then(|res, act, ctx| {
@@ -158,6 +743,10 @@ impl Actor for TaskSession {
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        if let Some(ref client) = self.client {
+            info!("Session {} ({}) is closing", self.id, client);
+        }
+
         self.addr.do_send(Disconnect {
             id: self.id.clone(),
         });
@@ -169,7 +758,19 @@ impl Handler<Message> for TaskSession {
     type Result = ();
 
     fn handle(&mut self, msg: Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        self.write_shared(msg.0, ctx);
+    }
+}
+
+impl Handler<Evict> for TaskSession {
+    type Result = ();
+
+    fn handle(&mut self, _: Evict, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Again,
+            description: Some(RESYNC_REQUIRED.to_string()),
+        }));
+        ctx.stop();
     }
 }
 
@@ -192,8 +793,14 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for TaskSession {
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
             }
-            ws::Message::Text(_) => {}
-            ws::Message::Binary(_) => error!("Unexpected binary"),
+            ws::Message::Text(text) => self.command(text.as_bytes(), Encoding::Json, ctx),
+            ws::Message::Binary(bytes) if self.encoding.is_binary() => {
+                self.command(&bytes, self.encoding, ctx)
+            }
+            ws::Message::Binary(_) => self.send(
+                &protocol::error(&Value::Null, "binary frames need a binary subprotocol"),
+                ctx,
+            ),
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();
@@ -205,3 +812,356 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for TaskSession {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt as actix_rt;
+    use std::sync::{Arc, Mutex};
+
+    /// Stands in for a `TaskSession` whose client has stopped reading.
+    struct StalledSession {
+        received: Arc<Mutex<Vec<Bytes>>>,
+        evicted: Arc<Mutex<bool>>,
+    }
+
+    impl Actor for StalledSession {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for StalledSession {
+        type Result = ();
+
+        fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
+            self.received.lock().unwrap().push(msg.0);
+        }
+    }
+
+    impl Handler<Evict> for StalledSession {
+        type Result = ();
+
+        fn handle(&mut self, _: Evict, ctx: &mut Context<Self>) {
+            *self.evicted.lock().unwrap() = true;
+            ctx.stop();
+        }
+    }
+
+    fn stalled_session() -> (Addr<StalledSession>, StalledSession) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let evicted = Arc::new(Mutex::new(false));
+        let probe = StalledSession {
+            received: received.clone(),
+            evicted: evicted.clone(),
+        };
+        let addr = StalledSession::create(|ctx| {
+            ctx.set_mailbox_capacity(SESSION_MAILBOX_CAPACITY);
+            StalledSession { received, evicted }
+        });
+        (addr, probe)
+    }
+
+    fn notify(manager: &mut SessionManager, msg: &'static [u8]) {
+        manager.broadcast(TASKS_UPDATED, |_| Some((Bytes::from_static(msg), None)));
+    }
+
+    /// Lets the sessions run until `done` holds, giving up after two seconds.
+    async fn run_sessions_until<F: Fn() -> bool>(done: F) {
+        for _ in 0..200 {
+            if done() {
+                return;
+            }
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[actix_rt::test]
+    async fn stalled_sessions_are_evicted() {
+        let mut manager = SessionManager::new();
+        let mut probes = Vec::new();
+
+        for _ in 0..1000 {
+            let (addr, probe) = stalled_session();
+            manager.connect(
+                addr.clone().recipient(),
+                addr.recipient(),
+                View::Public,
+                TaskFilter::default(),
+                Encoding::Json,
+            );
+            probes.push(probe);
+        }
+
+        // nothing is processed until we yield, so every mailbox fills up
+        for _ in 0..SESSION_MAILBOX_CAPACITY {
+            notify(&mut manager, b"tasks-updated");
+        }
+        assert_eq!(manager.sessions.len(), 1000);
+
+        for _ in 0..MAX_MISSED_UPDATES {
+            notify(&mut manager, b"tasks-updated");
+        }
+        assert_eq!(manager.sessions.len(), 1000);
+        assert!(manager
+            .sessions
+            .values()
+            .all(|s| s.missed == MAX_MISSED_UPDATES));
+
+        notify(&mut manager, b"tasks-updated");
+        assert!(manager.sessions.is_empty());
+
+        run_sessions_until(|| probes.iter().all(|p| *p.evicted.lock().unwrap())).await;
+
+        for probe in probes {
+            assert!(*probe.evicted.lock().unwrap());
+            assert_eq!(
+                probe.received.lock().unwrap().len(),
+                SESSION_MAILBOX_CAPACITY
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn lagging_sessions_are_told_to_resync() {
+        let mut manager = SessionManager::new();
+        let (addr, probe) = stalled_session();
+        manager.connect(
+            addr.clone().recipient(),
+            addr.recipient(),
+            View::Public,
+            TaskFilter::default(),
+            Encoding::Json,
+        );
+
+        for _ in 0..SESSION_MAILBOX_CAPACITY + 2 {
+            notify(&mut manager, b"tasks-updated");
+        }
+        assert_eq!(manager.sessions.values().next().unwrap().missed, 2);
+
+        run_sessions_until(|| probe.received.lock().unwrap().len() == SESSION_MAILBOX_CAPACITY)
+            .await;
+
+        notify(&mut manager, b"topic_updated");
+        notify(&mut manager, b"topic_updated");
+
+        run_sessions_until(|| probe.received.lock().unwrap().len() == SESSION_MAILBOX_CAPACITY + 2)
+            .await;
+
+        let received = probe.received.lock().unwrap();
+        assert_eq!(received.len(), SESSION_MAILBOX_CAPACITY + 2);
+        assert_eq!(received[SESSION_MAILBOX_CAPACITY], RESYNC_REQUIRED_ENVELOPE);
+        assert_eq!(received[SESSION_MAILBOX_CAPACITY + 1], "topic_updated");
+        assert!(!*probe.evicted.lock().unwrap());
+        assert_eq!(manager.sessions.values().next().unwrap().missed, 0);
+    }
+
+    #[actix_rt::test]
+    async fn closed_sessions_are_removed() {
+        let mut manager = SessionManager::new();
+
+        let mut closed = Vec::new();
+
+        for _ in 0..100 {
+            let (addr, _) = stalled_session();
+            manager.connect(
+                addr.clone().recipient(),
+                addr.clone().recipient(),
+                View::Public,
+                TaskFilter::default(),
+                Encoding::Json,
+            );
+            addr.do_send(Evict);
+            closed.push(addr);
+        }
+
+        let (addr, _probe) = stalled_session();
+        manager.connect(
+            addr.clone().recipient(),
+            addr.recipient(),
+            View::Public,
+            TaskFilter::default(),
+            Encoding::Json,
+        );
+
+        // let the evicted sessions stop, which closes their mailboxes
+        run_sessions_until(|| closed.iter().all(|a| !a.connected())).await;
+
+        notify(&mut manager, b"tasks-updated");
+        assert_eq!(manager.sessions.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn sessions_get_their_view_and_encoding() {
+        let mut manager = SessionManager::new();
+
+        let (public, public_probe) = stalled_session();
+        manager.connect(
+            public.clone().recipient(),
+            public.recipient(),
+            View::Public,
+            TaskFilter::default(),
+            Encoding::MessagePack,
+        );
+        let (private, private_probe) = stalled_session();
+        manager.connect(
+            private.clone().recipient(),
+            private.recipient(),
+            View::Private,
+            TaskFilter::default(),
+            Encoding::Json,
+        );
+
+        let tasks = tasks();
+        let private_tasks = Arc::new(Snapshot::new(TASKS_UPDATED, 1, tasks.clone()).unwrap());
+        let public_tasks = Arc::new(Snapshot::new(TASKS_UPDATED, 1, tasks[..1].to_vec()).unwrap());
+        manager.notify_tasks(&private_tasks, &public_tasks);
+
+        run_sessions_until(|| {
+            public_probe.received.lock().unwrap().len() == 1
+                && private_probe.received.lock().unwrap().len() == 1
+        })
+        .await;
+
+        let received = public_probe.received.lock().unwrap();
+        let envelope: Value = Encoding::MessagePack.decode(&received[0]).unwrap();
+        assert_eq!(envelope["event"], TASKS_UPDATED);
+        assert_eq!(envelope["data"][0]["description"], "write the overlay");
+        assert_eq!(envelope["data"].as_array().unwrap().len(), 1);
+
+        let received = private_probe.received.lock().unwrap();
+        assert_eq!(
+            descriptions(&received[0]),
+            vec!["write the overlay", "call the bank"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn sessions_only_get_subscribed_events() {
+        let mut manager = SessionManager::new();
+        let (addr, probe) = stalled_session();
+        let id = manager.connect(
+            addr.clone().recipient(),
+            addr.recipient(),
+            View::Public,
+            TaskFilter::default(),
+            Encoding::Json,
+        );
+
+        manager.sessions.get_mut(&id).unwrap().events = vec![TOPIC_UPDATED].into_iter().collect();
+
+        manager.broadcast(TASKS_UPDATED, |_| {
+            Some((Bytes::from_static(b"tasks"), None))
+        });
+        manager.broadcast(TOPIC_UPDATED, |_| {
+            Some((Bytes::from_static(b"topic"), None))
+        });
+
+        run_sessions_until(|| probe.received.lock().unwrap().len() == 1).await;
+        assert_eq!(*probe.received.lock().unwrap(), vec!["topic"]);
+    }
+
+    #[actix_rt::test]
+    async fn legacy_sessions_get_the_bare_notification() {
+        let mut manager = SessionManager::new();
+        let (addr, probe) = stalled_session();
+        let id = manager.connect(
+            addr.clone().recipient(),
+            addr.recipient(),
+            View::Public,
+            TaskFilter::default(),
+            Encoding::Json,
+        );
+        manager.sessions.get_mut(&id).unwrap().legacy = true;
+
+        manager.notify_topic(&Snapshot::new(TOPIC_UPDATED, 1, Topic::default()).unwrap());
+        let snapshot = Arc::new(Snapshot::new(TASKS_UPDATED, 1, tasks()).unwrap());
+        manager.notify_tasks(&snapshot, &snapshot);
+
+        run_sessions_until(|| probe.received.lock().unwrap().len() == 2).await;
+        assert_eq!(
+            *probe.received.lock().unwrap(),
+            vec![TOPIC_UPDATED, TASKS_UPDATED]
+        );
+    }
+
+    fn tasks() -> Vec<Task> {
+        let task_json = r#"
+        [{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20201118T071926Z","description":"write the overlay","project":"twitch"},
+         {"status":"pending","uuid":"8699cf59-59d4-4f42-812d-0d2de0cad191","entry":"20201120T064735Z","description":"call the bank","project":"personal"}]
+        "#;
+        serde_json::from_str(task_json).unwrap()
+    }
+
+    fn descriptions(envelope: &Bytes) -> Vec<String> {
+        let envelope: Value = serde_json::from_slice(envelope).unwrap();
+        envelope["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["description"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[actix_rt::test]
+    async fn sessions_get_their_slice_once() {
+        let mut manager = SessionManager::new();
+
+        let (all, all_probe) = stalled_session();
+        manager.connect(
+            all.clone().recipient(),
+            all.recipient(),
+            View::Public,
+            TaskFilter::default(),
+            Encoding::Json,
+        );
+        let (twitch, twitch_probe) = stalled_session();
+        manager.connect(
+            twitch.clone().recipient(),
+            twitch.recipient(),
+            View::Public,
+            TaskFilter {
+                projects: vec!["twitch".to_string()],
+                ..TaskFilter::default()
+            },
+            Encoding::Json,
+        );
+
+        let mut tasks = tasks();
+        let snapshot = Arc::new(Snapshot::new(TASKS_UPDATED, 1, tasks.clone()).unwrap());
+        manager.notify_tasks(&snapshot, &snapshot);
+
+        // only the personal task changes, which the twitch slice doesn't see
+        *tasks[1].description_mut() = "call the bank again".to_string();
+        let snapshot = Arc::new(Snapshot::new(TASKS_UPDATED, 2, tasks).unwrap());
+        manager.notify_tasks(&snapshot, &snapshot);
+
+        run_sessions_until(|| all_probe.received.lock().unwrap().len() == 2).await;
+
+        let received = all_probe.received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(
+            descriptions(&received[1]),
+            vec!["write the overlay", "call the bank again"]
+        );
+
+        let received = twitch_probe.received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(descriptions(&received[0]), vec!["write the overlay"]);
+    }
+
+    #[test]
+    fn watchers_are_dropped_when_they_fall_behind() {
+        let mut manager = SessionManager::new();
+        let (sender, mut receiver) = mpsc::channel(1);
+        manager.watchers.push(sender);
+
+        let topic = Arc::new(Snapshot::new(TOPIC_UPDATED, 1, Topic::default()).unwrap());
+        manager.notify_watchers(Update::Topic(TopicUpdated(topic.clone())));
+        assert!(matches!(receiver.try_recv(), Ok(Update::Topic(_))));
+
+        // a channel holds its buffer plus one message per sender
+        for _ in 0..3 {
+            manager.notify_watchers(Update::Topic(TopicUpdated(topic.clone())));
+        }
+        assert!(manager.watchers.is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use arc_swap::ArcSwap;
+use bytes::{BufMut, Bytes, BytesMut};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+use crate::encoding::Encoding;
+use crate::error::Result;
+
+/// An immutable value along with its serialized forms.
+///
+/// The value is serialized to JSON exactly once. `envelope` is the websocket
+/// notification `{"event":<event>,"revision":<revision>,"data":<value>}` and
+/// `body` is the `data` portion of that same buffer, so HTTP responses and
+/// websocket broadcasts share one allocation. The binary encodings are only
+/// made the first time someone asks for them.
+#[derive(Debug)]
+pub struct Snapshot<T> {
+    pub value: T,
+    pub revision: u64,
+    pub updated_at: SystemTime,
+    /// How the value was set, e.g. `api`, if anyone said.
+    pub updated_by: Option<String>,
+    pub body: Bytes,
+    pub envelope: Bytes,
+    event: String,
+    msgpack: OnceCell<Encoded>,
+    cbor: OnceCell<Encoded>,
+}
+
+#[derive(Debug)]
+struct Encoded {
+    body: Bytes,
+    envelope: Bytes,
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    event: &'a str,
+    revision: u64,
+    data: &'a T,
+}
+
+impl<T: Serialize> Snapshot<T> {
+    pub fn new(event: &str, revision: u64, value: T) -> Result<Self> {
+        let data = serde_json::to_vec(&value)?;
+        let prefix = format!(
+            r#"{{"event":{},"revision":{},"data":"#,
+            serde_json::to_string(event)?,
+            revision
+        );
+
+        let mut buf = BytesMut::with_capacity(prefix.len() + data.len() + 1);
+        buf.put_slice(prefix.as_bytes());
+        buf.put_slice(&data);
+        buf.put_u8(b'}');
+
+        let envelope = buf.freeze();
+        let body = envelope.slice(prefix.len()..envelope.len() - 1);
+
+        Ok(Snapshot {
+            value,
+            revision,
+            updated_at: SystemTime::now(),
+            updated_by: None,
+            body,
+            envelope,
+            event: event.to_string(),
+            msgpack: OnceCell::new(),
+            cbor: OnceCell::new(),
+        })
+    }
+
+    pub fn updated_by(mut self, updated_by: Option<&str>) -> Self {
+        self.updated_by = updated_by.map(str::to_string);
+        self
+    }
+
+    /// `body` in the given encoding.
+    pub fn body_in(&self, encoding: Encoding) -> Result<Bytes> {
+        Ok(match self.encoded(encoding)? {
+            Some(encoded) => encoded.body.clone(),
+            None => self.body.clone(),
+        })
+    }
+
+    /// `envelope` in the given encoding.
+    pub fn envelope_in(&self, encoding: Encoding) -> Result<Bytes> {
+        Ok(match self.encoded(encoding)? {
+            Some(encoded) => encoded.envelope.clone(),
+            None => self.envelope.clone(),
+        })
+    }
+
+    fn encoded(&self, encoding: Encoding) -> Result<Option<&Encoded>> {
+        let cell = match encoding {
+            Encoding::Json => return Ok(None),
+            Encoding::MessagePack => &self.msgpack,
+            Encoding::Cbor => &self.cbor,
+        };
+
+        cell.get_or_try_init(|| {
+            let envelope = Envelope {
+                event: &self.event,
+                revision: self.revision,
+                data: &self.value,
+            };
+
+            Ok(Encoded {
+                body: encoding.encode(&self.value)?.into(),
+                envelope: encoding.encode(&envelope)?.into(),
+            })
+        })
+        .map(Some)
+    }
+}
+
+/// Holds the current `Snapshot` of a value.
+///
+/// Readers never block: `load` hands out the current snapshot and `store`
+/// atomically replaces it, leaving existing readers with the old one.
+#[derive(Debug)]
+pub struct SnapshotCell<T> {
+    event: &'static str,
+    current: ArcSwap<Snapshot<T>>,
+}
+
+impl<T: Serialize> SnapshotCell<T> {
+    pub fn new(event: &'static str, value: T) -> Result<Self> {
+        Ok(SnapshotCell {
+            event,
+            current: ArcSwap::from_pointee(Snapshot::new(event, 0, value)?),
+        })
+    }
+
+    pub fn load(&self) -> Arc<Snapshot<T>> {
+        self.current.load_full()
+    }
+
+    pub fn store(&self, revision: u64, value: T) -> Result<Arc<Snapshot<T>>> {
+        let snapshot = Arc::new(Snapshot::new(self.event, revision, value)?);
+        self.replace(snapshot.clone());
+        Ok(snapshot)
+    }
+
+    /// Stores a snapshot made elsewhere, e.g. one shared with another cell.
+    pub fn replace(&self, snapshot: Arc<Snapshot<T>>) {
+        self.current.store(snapshot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body_is_a_view_into_the_envelope() {
+        let snapshot = Snapshot::new("tasks-updated", 3, vec!["foo", "bar"]).unwrap();
+
+        assert_eq!(&snapshot.body[..], br#"["foo","bar"]"#);
+        assert_eq!(
+            &snapshot.envelope[..],
+            br#"{"event":"tasks-updated","revision":3,"data":["foo","bar"]}"#
+        );
+
+        let envelope: serde_json::Value = serde_json::from_slice(&snapshot.envelope).unwrap();
+        assert_eq!(envelope["data"], serde_json::json!(["foo", "bar"]));
+    }
+
+    #[test]
+    fn store_leaves_existing_readers_alone() {
+        let cell = SnapshotCell::new("topic_updated", 1).unwrap();
+        let before = cell.load();
+
+        cell.store(1, 2).unwrap();
+
+        assert_eq!(before.value, 1);
+        assert_eq!(before.revision, 0);
+        assert_eq!(&before.body[..], b"1");
+        assert_eq!(cell.load().value, 2);
+        assert_eq!(&cell.load().body[..], b"2");
+        assert_eq!(cell.load().revision, 1);
+    }
+
+    #[test]
+    fn binary_encodings_are_made_once() {
+        let snapshot = Snapshot::new("tasks-updated", 3, vec!["foo", "bar"]).unwrap();
+
+        let body = snapshot.body_in(Encoding::MessagePack).unwrap();
+        assert_eq!(
+            Encoding::MessagePack.decode::<Vec<String>>(&body).unwrap(),
+            vec!["foo", "bar"]
+        );
+        // the same buffer is handed out every time
+        assert_eq!(
+            body.as_ptr(),
+            snapshot.body_in(Encoding::MessagePack).unwrap().as_ptr()
+        );
+
+        let envelope: serde_json::Value = Encoding::Cbor
+            .decode(&snapshot.envelope_in(Encoding::Cbor).unwrap())
+            .unwrap();
+        assert_eq!(
+            envelope,
+            serde_json::json!({"event": "tasks-updated", "revision": 3, "data": ["foo", "bar"]})
+        );
+    }
+}
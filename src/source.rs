@@ -0,0 +1,577 @@
+//! Where the client gets the tasks it pushes from: taskwarrior, or a file
+//! for those who keep their tasks in todo.txt or a plain JSON list.
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+use task_hookrs::priority::TaskPriority;
+use task_hookrs::task::Task;
+use uuid::Uuid;
+
+use crate::error::{Result, TSError};
+use crate::filter::has_status;
+use crate::redact::{has_any_tag, in_projects};
+use crate::taskwarrior::Taskwarrior;
+
+pub trait TaskSource {
+    /// The tasks matching `filter`, in taskwarrior's filter syntax.
+    fn tasks(&self, filter: &str) -> Result<Vec<Task>>;
+}
+
+/// Which `TaskSource` the client exports from, written `taskwarrior`,
+/// `todotxt:PATH` or `json:PATH`.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Source {
+    #[default]
+    Taskwarrior,
+    TodoTxt(PathBuf),
+    Json(PathBuf),
+}
+
+impl Source {
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let (kind, path) = match spec.find(':') {
+            Some(i) => (&spec[..i], Some(&spec[i + 1..])),
+            None => (spec, None),
+        };
+
+        match (kind, path) {
+            ("taskwarrior", None) => Ok(Source::Taskwarrior),
+            ("todotxt", Some(path)) if !path.is_empty() => Ok(Source::TodoTxt(expand(path))),
+            ("json", Some(path)) if !path.is_empty() => Ok(Source::Json(expand(path))),
+            ("todotxt", _) | ("json", _) => {
+                Err(format!("{} sources need a path: {}:PATH", kind, kind))
+            }
+            _ => Err(format!(
+                "unknown source {}, expected taskwarrior, todotxt:PATH or json:PATH",
+                spec
+            )),
+        }
+    }
+
+    /// `taskwarrior` is how `task` is run, if this is taskwarrior.
+    pub fn open(&self, taskwarrior: &Taskwarrior) -> Box<dyn TaskSource> {
+        match self {
+            Source::Taskwarrior => Box::new(taskwarrior.clone()),
+            Source::TodoTxt(path) => Box::new(TodoTxt(path.clone())),
+            Source::Json(path) => Box::new(JsonFile(path.clone())),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Taskwarrior => write!(f, "taskwarrior"),
+            Source::TodoTxt(path) => write!(f, "todotxt:{}", path.display()),
+            Source::Json(path) => write!(f, "json:{}", path.display()),
+        }
+    }
+}
+
+impl TryFrom<String> for Source {
+    type Error = String;
+
+    fn try_from(spec: String) -> std::result::Result<Self, Self::Error> {
+        Source::parse(&spec)
+    }
+}
+
+pub(crate) fn expand(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// A todo.txt file, one task per line:
+/// `x 2020-11-20 2020-11-18 (A) call the bank +personal @phone due:2020-11-30`.
+///
+/// Priorities A, B and C become taskwarrior's H, M and L. The first
+/// `+project` is the task's project and any others become tags, as do
+/// `@contexts`, which keep their `@`. `due:` is the due date and other
+/// `key:value` pairs become UDAs. Tasks are numbered by line.
+pub struct TodoTxt(pub PathBuf);
+
+impl TaskSource for TodoTxt {
+    fn tasks(&self, filter: &str) -> Result<Vec<Task>> {
+        let filter = FileFilter::parse(filter)?;
+        let text = read(&self.0)?;
+        Ok(filter.apply(todo_txt(&text)?))
+    }
+}
+
+/// A JSON list of tasks, of which only `description` is needed:
+/// `[{"description": "call the bank", "project": "personal", "done": true}]`.
+///
+/// Dates are `2020-11-30` or as taskwarrior writes them and `priority` is
+/// `H`, `M` or `L`. `contexts` become tags prefixed with `@`.
+pub struct JsonFile(pub PathBuf);
+
+impl TaskSource for JsonFile {
+    fn tasks(&self, filter: &str) -> Result<Vec<Task>> {
+        let filter = FileFilter::parse(filter)?;
+        let listed: Vec<Listed> = serde_json::from_str(&read(&self.0)?)?;
+
+        let mut uuids = Uuids::default();
+        let tasks = listed
+            .into_iter()
+            .enumerate()
+            .map(|(i, listed)| {
+                let name = listed.description.clone();
+                listed.into_task(i as u64 + 1, &name, &mut uuids)
+            })
+            .collect::<Result<_>>()?;
+        Ok(filter.apply(tasks))
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path)
+        .map_err(|e| TSError::Error(format!("could not read {}: {}", path.display(), e)))
+}
+
+/// A task as a file lists it, with whatever the file leaves out defaulted.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Listed {
+    uuid: Option<String>,
+    description: String,
+    #[serde(default)]
+    done: bool,
+    priority: Option<TaskPriority>,
+    project: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    contexts: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_date")]
+    entry: Option<NaiveDateTime>,
+    #[serde(default, deserialize_with = "deserialize_date")]
+    start: Option<NaiveDateTime>,
+    #[serde(default, deserialize_with = "deserialize_date")]
+    end: Option<NaiveDateTime>,
+    #[serde(default, deserialize_with = "deserialize_date")]
+    due: Option<NaiveDateTime>,
+    #[serde(skip)]
+    uda: BTreeMap<String, String>,
+}
+
+impl Listed {
+    /// Tasks without a uuid get one made from `name`, so the same task has
+    /// the same uuid every export. Those without an entry date are entered
+    /// at the epoch, for the same reason.
+    fn into_task(self, id: u64, name: &str, uuids: &mut Uuids) -> Result<Task> {
+        let uuid = match self.uuid {
+            Some(ref uuid) => uuids.claim(
+                Uuid::parse_str(uuid)
+                    .map_err(|e| TSError::Error(format!("invalid uuid {}: {}", uuid, e)))?,
+            ),
+            None => uuids.name(name),
+        };
+        let entry = self
+            .entry
+            .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+
+        let mut tags = self.tags;
+        tags.extend(
+            self.contexts
+                .into_iter()
+                .map(|context| format!("@{}", context)),
+        );
+
+        // built as taskwarrior would export it, as task-hookrs wants its own
+        // version of `Uuid`
+        let mut task = json!({
+            "id": id,
+            "status": if self.done { "completed" } else { "pending" },
+            "uuid": uuid.to_string(),
+            "entry": taskwarrior_date(entry),
+            "description": self.description,
+        });
+        let fields = task.as_object_mut().unwrap();
+        let mut set = |key: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                fields.insert(key.to_string(), value);
+            }
+        };
+        set("priority", self.priority.map(|p| json!(p)));
+        set("project", self.project.map(Value::String));
+        set("tags", Some(json!(tags)).filter(|_| !tags.is_empty()));
+        set(
+            "start",
+            self.start.map(|date| json!(taskwarrior_date(date))),
+        );
+        set("end", self.end.map(|date| json!(taskwarrior_date(date))));
+        set("due", self.due.map(|date| json!(taskwarrior_date(date))));
+        for (key, value) in self.uda {
+            set(&key, Some(Value::String(value)));
+        }
+
+        Ok(serde_json::from_value(task)?)
+    }
+}
+
+fn taskwarrior_date(date: NaiveDateTime) -> String {
+    date.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Hands out uuids no other task in the file has.
+#[derive(Default)]
+struct Uuids(HashSet<Uuid>);
+
+impl Uuids {
+    fn claim(&mut self, uuid: Uuid) -> Uuid {
+        self.0.insert(uuid);
+        uuid
+    }
+
+    /// Tasks of the same name are told apart by how many came before.
+    fn name(&mut self, name: &str) -> Uuid {
+        let mut uuid = Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes());
+        let mut seen = 1;
+        while self.0.contains(&uuid) {
+            seen += 1;
+            let name = format!("{}#{}", name, seen);
+            uuid = Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes());
+        }
+        self.claim(uuid)
+    }
+}
+
+fn todo_txt(text: &str) -> Result<Vec<Task>> {
+    let mut uuids = Uuids::default();
+    let mut tasks = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let listed =
+            todo_txt_line(line).map_err(|e| TSError::Error(format!("line {}: {}", i + 1, e)))?;
+        // named by its description alone, so marking a task done, or changing
+        // its project, contexts or due date, keeps its uuid
+        let name = listed.description.clone();
+        tasks.push(listed.into_task(i as u64 + 1, &name, &mut uuids)?);
+    }
+
+    Ok(tasks)
+}
+
+fn todo_txt_line(line: &str) -> std::result::Result<Listed, String> {
+    let mut listed = Listed::default();
+    let mut rest = line;
+
+    if let Some(after) = rest.strip_prefix("x ") {
+        listed.done = true;
+        rest = after.trim_start();
+        if let Some((date, after)) = leading_date(rest) {
+            listed.end = Some(date);
+            rest = after;
+        }
+    }
+
+    if let Some(after) = rest.strip_prefix('(') {
+        let mut chars = after.chars();
+        if let (Some(letter), Some(')')) = (chars.next(), chars.next()) {
+            if letter.is_ascii_uppercase() {
+                listed.priority = priority(letter);
+                rest = chars.as_str().trim_start();
+            }
+        }
+    }
+
+    if let Some((date, after)) = leading_date(rest) {
+        listed.entry = Some(date);
+        rest = after;
+    }
+
+    let mut words = Vec::new();
+    for word in rest.split_whitespace() {
+        if let Some(project) = word.strip_prefix('+').filter(|p| !p.is_empty()) {
+            if listed.project.is_none() {
+                listed.project = Some(project.to_string());
+            } else {
+                listed.tags.push(project.to_string());
+            }
+        } else if let Some(context) = word.strip_prefix('@').filter(|c| !c.is_empty()) {
+            listed.contexts.push(context.to_string());
+        } else if let Some((key, value)) = key_value(word) {
+            if key == "due" {
+                listed.due =
+                    Some(parse_date(value).ok_or_else(|| format!("invalid due date {}", value))?);
+            } else if key == "pri" && listed.priority.is_none() {
+                // done tasks may keep their priority as `pri:A`
+                listed.priority = value.chars().next().and_then(priority);
+            } else {
+                listed.uda.insert(key.to_string(), value.to_string());
+            }
+        } else {
+            words.push(word);
+        }
+    }
+
+    if words.is_empty() {
+        return Err("no description".to_string());
+    }
+    listed.description = words.join(" ");
+    Ok(listed)
+}
+
+fn priority(letter: char) -> Option<TaskPriority> {
+    match letter {
+        'A' => Some(TaskPriority::High),
+        'B' => Some(TaskPriority::Medium),
+        'C' => Some(TaskPriority::Low),
+        _ => None,
+    }
+}
+
+fn leading_date(text: &str) -> Option<(NaiveDateTime, &str)> {
+    let (word, rest) = match text.find(' ') {
+        Some(i) => (&text[..i], text[i..].trim_start()),
+        None => (text, ""),
+    };
+    let date = NaiveDate::parse_from_str(word, "%Y-%m-%d").ok()?;
+    Some((date.and_hms(0, 0, 0), rest))
+}
+
+/// `key:value`, but not a URL.
+fn key_value(word: &str) -> Option<(&str, &str)> {
+    let i = word.find(':')?;
+    let (key, value) = (&word[..i], &word[i + 1..]);
+    if key.is_empty() || value.is_empty() || value.starts_with("//") {
+        return None;
+    }
+    Some((key, value))
+}
+
+/// A day, `2020-11-30`, or a time as taskwarrior or RFC 3339 writes it.
+fn parse_date(date: &str) -> Option<NaiveDateTime> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map(|date| date.and_hms(0, 0, 0))
+        .or_else(|_| NaiveDateTime::parse_from_str(date, "%Y%m%dT%H%M%SZ"))
+        .or_else(|_| DateTime::parse_from_rfc3339(date).map(|date| date.naive_utc()))
+        .ok()
+}
+
+fn deserialize_date<'de, D>(deserializer: D) -> std::result::Result<Option<NaiveDateTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(date) => parse_date(&date)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid date {}", date))),
+        None => Ok(None),
+    }
+}
+
+/// What file sources understand of taskwarrior's filters: `status:`,
+/// `project:`, `+tag` and `-tag`, all of which a task has to match.
+#[derive(Debug, Default, PartialEq)]
+struct FileFilter {
+    status: Vec<String>,
+    projects: Vec<String>,
+    tags: Vec<String>,
+    without_tags: Vec<String>,
+}
+
+impl FileFilter {
+    fn parse(filter: &str) -> Result<Self> {
+        let terms = shlex::split(filter)
+            .ok_or_else(|| TSError::Error(format!("could not parse filter '{}'", filter)))?;
+
+        let mut file_filter = FileFilter::default();
+        for term in terms {
+            if let Some(status) = term.strip_prefix("status:") {
+                file_filter.status.push(status.to_string());
+            } else if let Some(project) = term.strip_prefix("project:") {
+                file_filter.projects.push(project.to_string());
+            } else if let Some(tag) = term.strip_prefix('+') {
+                file_filter.tags.push(tag.to_string());
+            } else if let Some(tag) = term.strip_prefix('-') {
+                file_filter.without_tags.push(tag.to_string());
+            } else {
+                return Err(TSError::Error(format!(
+                    "file sources can't filter by '{}', only status:, project:, +tag and -tag",
+                    term
+                )));
+            }
+        }
+        Ok(file_filter)
+    }
+
+    fn matches(&self, task: &Task) -> bool {
+        self.status.iter().all(|status| has_status(task, status))
+            && self
+                .projects
+                .iter()
+                .all(|project| in_projects(task, std::slice::from_ref(project)))
+            && self
+                .tags
+                .iter()
+                .all(|tag| has_any_tag(task, std::slice::from_ref(tag)))
+            && !has_any_tag(task, &self.without_tags)
+    }
+
+    fn apply(&self, tasks: Vec<Task>) -> Vec<Task> {
+        tasks
+            .into_iter()
+            .filter(|task| self.matches(task))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use task_hookrs::status::TaskStatus;
+    use task_hookrs::uda::UDAValue;
+
+    const TODO_TXT: &str = "\
+(A) 2020-11-18 call the bank +personal @phone due:2020-11-30
+x 2020-11-20 2020-11-19 add tests +twitch.task-display +ci pri:B
+
+fix styling everywhere +twitch.task-display see:https://example.com
+";
+
+    #[test]
+    fn reading_todo_txt() {
+        let tasks = todo_txt(TODO_TXT).unwrap();
+        assert_eq!(tasks.len(), 3);
+
+        let bank = &tasks[0];
+        assert_eq!(bank.id(), Some(1));
+        assert_eq!(bank.description(), "call the bank");
+        assert_eq!(bank.priority(), Some(&TaskPriority::High));
+        assert_eq!(bank.project().map(String::as_str), Some("personal"));
+        assert_eq!(bank.tags(), Some(&vec!["@phone".to_string()]));
+        assert_eq!(**bank.entry(), parse_date("2020-11-18").unwrap());
+        assert_eq!(bank.due().map(|due| **due), parse_date("2020-11-30"));
+        assert_eq!(bank.status(), &TaskStatus::Pending);
+
+        let tests = &tasks[1];
+        assert_eq!(tests.id(), Some(2));
+        assert_eq!(tests.status(), &TaskStatus::Completed);
+        assert_eq!(tests.end().map(|end| **end), parse_date("2020-11-20"));
+        assert_eq!(**tests.entry(), parse_date("2020-11-19").unwrap());
+        assert_eq!(tests.priority(), Some(&TaskPriority::Medium));
+        assert_eq!(
+            tests.project().map(String::as_str),
+            Some("twitch.task-display")
+        );
+        assert_eq!(tests.tags(), Some(&vec!["ci".to_string()]));
+
+        // the blank line still counts, and URLs aren't key:value pairs
+        let styling = &tasks[2];
+        assert_eq!(styling.id(), Some(4));
+        assert_eq!(styling.description(), "fix styling everywhere");
+        assert_eq!(
+            styling.uda().get("see"),
+            Some(&UDAValue::Str("https://example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn todo_txt_uuids_are_stable() {
+        let uuids = |text: &str| -> Vec<String> {
+            todo_txt(text)
+                .unwrap()
+                .iter()
+                .map(|task| task.uuid().to_string())
+                .collect()
+        };
+
+        let pending = uuids("(A) call the bank\ncall the bank\n");
+        assert_ne!(pending[0], pending[1]);
+        // done, and moved down a line
+        let done = uuids("\nx 2020-11-20 call the bank\ncall the bank\n");
+        assert_eq!(pending, done);
+        // filed elsewhere, and given a due date
+        let moved = uuids("call the bank +errands @phone due:2020-12-01\ncall the bank\n");
+        assert_eq!(pending, moved);
+    }
+
+    #[test]
+    fn reading_json_lists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tasks.json");
+        fs::write(
+            &path,
+            r#"[{"description": "call the bank", "project": "personal", "contexts": ["phone"], "priority": "H", "due": "2020-11-30"},
+                {"description": "add tests", "done": true, "tags": ["ci"]},
+                {"uuid": "8699cf59-59d4-4f42-812d-0d2de0cad191", "description": "write the overlay", "start": "20201119T071926Z"}]"#,
+        )
+        .unwrap();
+
+        let tasks = JsonFile(path.clone()).tasks("").unwrap();
+        assert_eq!(tasks.len(), 3);
+        assert_eq!(tasks[0].tags(), Some(&vec!["@phone".to_string()]));
+        assert_eq!(tasks[0].priority(), Some(&TaskPriority::High));
+        assert_eq!(tasks[1].status(), &TaskStatus::Completed);
+        assert_eq!(
+            tasks[2].uuid().to_string(),
+            "8699cf59-59d4-4f42-812d-0d2de0cad191"
+        );
+        assert!(tasks[2].start().is_some());
+
+        let pending = JsonFile(path.clone()).tasks("status:pending").unwrap();
+        let descriptions: Vec<_> = pending
+            .iter()
+            .map(|task| task.description().as_str())
+            .collect();
+        assert_eq!(descriptions, vec!["call the bank", "write the overlay"]);
+
+        fs::write(
+            &path,
+            r#"[{"description": "call the bank", "colour": "red"}]"#,
+        )
+        .unwrap();
+        assert!(JsonFile(path).tasks("").is_err());
+    }
+
+    #[test]
+    fn filtering_files() {
+        let tasks = todo_txt(TODO_TXT).unwrap();
+        let filtered = |filter: &str| -> Vec<u64> {
+            FileFilter::parse(filter)
+                .unwrap()
+                .apply(tasks.clone())
+                .iter()
+                .filter_map(|task| task.id())
+                .collect()
+        };
+
+        assert_eq!(filtered(""), vec![1, 2, 4]);
+        assert_eq!(filtered("status:pending"), vec![1, 4]);
+        assert_eq!(filtered("project:twitch"), vec![2, 4]);
+        assert_eq!(filtered("+ci"), vec![2]);
+        assert_eq!(filtered("status:pending -@phone"), vec![4]);
+        assert!(FileFilter::parse("due.before:today").is_err());
+    }
+
+    #[test]
+    fn parsing_sources() {
+        assert_eq!(Source::parse("taskwarrior").unwrap(), Source::Taskwarrior);
+        assert_eq!(
+            Source::parse("todotxt:/home/me/todo.txt").unwrap(),
+            Source::TodoTxt("/home/me/todo.txt".into())
+        );
+        assert_eq!(
+            Source::parse("json:tasks.json").unwrap().to_string(),
+            "json:tasks.json"
+        );
+        assert!(Source::parse("todotxt").is_err());
+        assert!(Source::parse("taskwarrior:/usr/bin/task").is_err());
+        assert!(Source::parse("trello").is_err());
+    }
+}
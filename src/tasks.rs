@@ -1,22 +1,30 @@
-use log::{debug, error, info};
-use std::cmp::Ordering;
-use std::process::Command;
+use std::collections::HashMap;
 
-use task_hookrs::import::import;
 use task_hookrs::task::Task;
 
-use crate::error::{Result, TSError};
+use crate::config;
+use crate::error::Result;
+use crate::filter::SortSpec;
+use crate::privacy::Privacy;
+use crate::source::{Source, TaskSource};
+use crate::taskwarrior::Taskwarrior;
 
 pub struct TaskClient {
     pub tasks: Vec<Task>,
     pub filter: String,
+    privacy: Privacy,
+    sort: SortSpec,
+    source: Box<dyn TaskSource>,
 }
 
 impl TaskClient {
-    pub fn new(filter: &str) -> Result<Self> {
+    pub fn new(config: &config::Client) -> Result<Self> {
         let mut client = TaskClient {
             tasks: Vec::new(),
-            filter: filter.to_string(),
+            filter: config.filter.clone().unwrap_or_default(),
+            privacy: config.privacy.clone(),
+            sort: config.sort.clone().unwrap_or_default(),
+            source: config.source.open(&config.taskwarrior),
         };
 
         client.refresh_tasks()?;
@@ -24,89 +32,75 @@ impl TaskClient {
     }
 
     pub fn refresh_tasks(&mut self) -> Result<()> {
-        let mut task = Command::new("task");
-
-        task.arg("rc.json.array=on");
-        task.arg("rc.confirmation=off");
-        task.arg("export");
-
-        let filter = format!(
-            "{} {}",
-            self.filter,
-            self.get_context()?.unwrap_or("".to_string())
-        );
-
-        debug!("discovering tasks with filter: '{}'", filter);
+        let tasks = self.source.tasks(&self.filter)?;
+        self.tasks = prepare(&self.privacy, &self.sort, tasks)?;
+        Ok(())
+    }
+}
 
-        match shlex::split(&filter) {
-            Some(cmd) => {
-                for s in cmd {
-                    task.arg(&s);
-                }
-            }
+/// Exports for several clients, once for each source, taskwarrior and filter
+/// they share, with each client's own privacy and sort applied to its copy.
+#[derive(Default)]
+pub struct Exports(HashMap<(Source, Taskwarrior, String), Vec<Task>>);
+
+impl Exports {
+    pub fn tasks(&mut self, config: &config::Client) -> Result<Vec<Task>> {
+        let filter = config.filter.clone().unwrap_or_default();
+        let key = (config.source.clone(), config.taskwarrior.clone(), filter);
+        let tasks = match self.0.get(&key) {
+            Some(tasks) => tasks.clone(),
             None => {
-                task.arg("");
+                let tasks = config.source.open(&config.taskwarrior).tasks(&key.2)?;
+                self.0.insert(key, tasks.clone());
+                tasks
             }
-        }
-
-        let output = task.output()?;
-
-        match import(String::from_utf8_lossy(&output.stdout).as_bytes()) {
-            Ok(mut tasks) => {
-                tasks.sort_by(|a, b| {
-                    let a_start = a.start();
-                    let b_start = b.start();
-
-                    if a_start.is_some() && b_start.is_none() {
-                        return Ordering::Less;
-                    }
-
-                    if a_start.is_none() && b_start.is_some() {
-                        return Ordering::Greater;
-                    }
+        };
 
-                    return Ordering::Equal;
-                });
-                self.tasks = tasks;
-                Ok(())
-            }
-            Err(_) => Err(TSError::Error(
-                "could not load tasks from output".to_string(),
-            )),
-        }
+        prepare(
+            &config.privacy,
+            &config.sort.clone().unwrap_or_default(),
+            tasks,
+        )
     }
+}
 
-    fn get_context(&self) -> Result<Option<String>> {
-        let output = Command::new("task")
-            .arg("_get")
-            .arg("rc.context")
-            .output()?;
-        let context = String::from_utf8_lossy(&output.stdout)
-            .to_string()
-            .strip_suffix('\n')
-            .unwrap_or("")
-            .to_string();
-
-        if context.len() == 0 {
-            return Ok(None);
-        }
-
-        let output = Command::new("task")
-            .arg("_get")
-            .arg(format!("rc.context.{}", context))
-            .output()?;
-        match String::from_utf8_lossy(&output.stdout)
-            .to_string()
-            .strip_suffix('\n')
-        {
-            Some(filter) => Ok(Some(filter.to_string())),
-            // FIXME: make error - MCL - 2020-11-17
-            None => Ok(None),
-        }
-    }
+fn prepare(privacy: &Privacy, sort: &SortSpec, tasks: Vec<Task>) -> Result<Vec<Task>> {
+    let mut tasks = privacy.apply(tasks)?;
+    sort.sort(&mut tasks);
+    Ok(tasks)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn shared_exports_keep_their_own_privacy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tasks.json");
+        std::fs::write(&path, r#"[{"description": "mail jane@example.com"}]"#).unwrap();
+
+        let dev = config::Client {
+            source: Source::Json(path),
+            filter: Some("status:pending".to_string()),
+            ..config::Client::default()
+        };
+        let production = config::Client {
+            privacy: Privacy {
+                scrub_emails: true,
+                ..Privacy::default()
+            },
+            ..dev.clone()
+        };
+
+        let mut exports = Exports::default();
+        assert_eq!(
+            exports.tasks(&dev).unwrap()[0].description(),
+            "mail jane@example.com"
+        );
+        assert_eq!(
+            exports.tasks(&production).unwrap()[0].description(),
+            "mail [redacted]"
+        );
+    }
 }
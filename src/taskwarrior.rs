@@ -0,0 +1,482 @@
+//! Everything the client runs `task` for goes through here, so each run gets
+//! the same binary, rc file and data, and fails the same way.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use serde::Deserialize;
+use task_hookrs::task::Task;
+
+use crate::error::{Result, TSError};
+use crate::source::{expand, TaskSource};
+
+/// How often a running command is checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The version that split `context.X` into `context.X.read` and
+/// `context.X.write`.
+const READ_CONTEXTS: Version = Version {
+    major: 2,
+    minor: 6,
+    patch: 0,
+};
+
+/// How taskwarrior is run. `rc_file` and `data_dir` are handed over as
+/// `TASKRC` and `TASKDATA` when they are set, with a leading `~/` expanded.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize)]
+#[serde(try_from = "RawTaskwarrior")]
+pub struct Taskwarrior {
+    /// The `task` binary, looked up on `PATH` unless it is a path.
+    pub binary: PathBuf,
+    pub rc_file: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    /// How long a command may run before it is killed.
+    pub timeout_secs: u64,
+}
+
+impl Default for Taskwarrior {
+    fn default() -> Self {
+        Taskwarrior {
+            binary: PathBuf::from("task"),
+            rc_file: None,
+            data_dir: None,
+            timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RawTaskwarrior {
+    binary: PathBuf,
+    rc_file: Option<String>,
+    data_dir: Option<String>,
+    timeout_secs: u64,
+}
+
+impl Default for RawTaskwarrior {
+    fn default() -> Self {
+        let taskwarrior = Taskwarrior::default();
+        RawTaskwarrior {
+            binary: taskwarrior.binary,
+            rc_file: None,
+            data_dir: None,
+            timeout_secs: taskwarrior.timeout_secs,
+        }
+    }
+}
+
+impl TryFrom<RawTaskwarrior> for Taskwarrior {
+    type Error = String;
+
+    fn try_from(raw: RawTaskwarrior) -> std::result::Result<Self, Self::Error> {
+        // nothing could run in no time at all
+        if raw.timeout_secs < 1 {
+            return Err("timeout_secs must be at least 1".to_string());
+        }
+
+        Ok(Taskwarrior {
+            binary: raw.binary,
+            rc_file: raw.rc_file.as_deref().map(expand),
+            data_dir: raw.data_dir.as_deref().map(expand),
+            timeout_secs: raw.timeout_secs,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    /// Reads what `task _version` prints, e.g. `2.5.1` or `2.6.0 (a1b2c3d)`.
+    fn parse(version: &str) -> Option<Self> {
+        let mut numbers = version.split_whitespace().next()?.split('.').map(|part| {
+            let digits = part
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(part.len());
+            part[..digits].parse().ok()
+        });
+
+        Some(Version {
+            major: numbers.next()??,
+            minor: numbers.next().unwrap_or(Some(0))?,
+            patch: numbers.next().unwrap_or(Some(0))?,
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl Taskwarrior {
+    /// Runs `task` with `args`, giving what it printed. Failing, or running
+    /// past the timeout, is an error carrying what it printed to stderr.
+    pub fn run(&self, args: &[String]) -> Result<String> {
+        let command = format!("{} {}", self.binary.display(), args.join(" "));
+        debug!("running `{}`", command);
+
+        let mut task = Command::new(&self.binary);
+        task.args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(ref rc_file) = self.rc_file {
+            task.env("TASKRC", rc_file);
+        }
+        if let Some(ref data_dir) = self.data_dir {
+            task.env("TASKDATA", data_dir);
+        }
+
+        let mut child = task.spawn().map_err(|e| {
+            TSError::Error(format!("could not run {}: {}", self.binary.display(), e))
+        })?;
+        // read as it comes, so a full pipe can't stall the command
+        let stdout = read_all(child.stdout.take());
+        let stderr = read_all(child.stderr.take());
+
+        let timeout = Duration::from_secs(self.timeout_secs);
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                // the readers finish once the pipes close
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(TSError::TaskTimeout { command, timeout });
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        if !status.success() {
+            return Err(TSError::TaskError {
+                command,
+                code: status.code(),
+                stderr: String::from_utf8_lossy(&stderr).trim().to_string(),
+            });
+        }
+
+        Ok(String::from_utf8_lossy(&stdout).into_owned())
+    }
+
+    pub fn version(&self) -> Result<Version> {
+        let output = self.run(&["_version".to_string()])?;
+        Version::parse(&output).ok_or_else(|| {
+            TSError::Error(format!(
+                "could not read the taskwarrior version from '{}'",
+                output.trim()
+            ))
+        })
+    }
+
+    /// Every setting, as `task _show` lists them, read in one go.
+    pub fn settings(&self) -> Result<HashMap<String, String>> {
+        Ok(self
+            .run(&["_show".to_string()])?
+            .lines()
+            .filter_map(|line| {
+                let i = line.find('=')?;
+                Some((line[..i].to_string(), line[i + 1..].to_string()))
+            })
+            .collect())
+    }
+
+    /// The filter of the active context, if there is one.
+    pub fn context_filter(&self, settings: &HashMap<String, String>) -> Result<Option<String>> {
+        context_filter(settings, || self.version())
+    }
+
+    /// Exports the tasks matching `filter`, in the active context.
+    pub fn export(&self, filter: &str) -> Result<Vec<Task>> {
+        let settings = self.settings()?;
+        let filter = match self.context_filter(&settings)? {
+            Some(context) => format!("{} {}", filter, context),
+            None => filter.to_string(),
+        };
+        debug!("discovering tasks with filter: '{}'", filter);
+
+        let mut args: Vec<String> = vec![
+            "rc.json.array=on".to_string(),
+            "rc.confirmation=off".to_string(),
+            // exporting from inside a hook mustn't set off the hooks again
+            "rc.hooks=off".to_string(),
+            "export".to_string(),
+        ];
+        args.extend(
+            shlex::split(&filter)
+                .ok_or_else(|| TSError::Error(format!("could not parse filter '{}'", filter)))?,
+        );
+
+        let output = self.run(&args)?;
+        serde_json::from_str(&output).map_err(|e| {
+            TSError::Error(format!(
+                "could not read the tasks taskwarrior exported: {}",
+                e
+            ))
+        })
+    }
+
+    /// Where the data is: `data_dir`, `TASKDATA` if it is set, otherwise the
+    /// configured `data.location`.
+    pub fn data_location(&self) -> Result<PathBuf> {
+        if let Some(ref dir) = self.data_dir {
+            return Ok(dir.clone());
+        }
+        if let Some(dir) = std::env::var_os("TASKDATA") {
+            return Ok(PathBuf::from(dir));
+        }
+
+        let location = self
+            .settings()?
+            .remove("data.location")
+            .map(|location| location.trim().to_string())
+            .filter(|location| !location.is_empty())
+            .ok_or_else(|| {
+                TSError::Error("could not find the taskwarrior data location".to_string())
+            })?;
+
+        match (location.strip_prefix("~/"), dirs::home_dir()) {
+            (Some(rest), Some(home)) => Ok(home.join(rest)),
+            _ => Ok(PathBuf::from(location)),
+        }
+    }
+}
+
+impl TaskSource for Taskwarrior {
+    fn tasks(&self, filter: &str) -> Result<Vec<Task>> {
+        self.export(filter)
+    }
+}
+
+/// Looks the active context up as `context.X.read`, as taskwarrior 2.6 has
+/// it, or `context.X`, as older versions do. Only when both are set is the
+/// `version` needed to tell which counts.
+fn context_filter(
+    settings: &HashMap<String, String>,
+    version: impl FnOnce() -> Result<Version>,
+) -> Result<Option<String>> {
+    let context = match settings.get("context").map(|c| c.trim()) {
+        Some(context) if !context.is_empty() => context,
+        _ => return Ok(None),
+    };
+
+    let setting = |key: String| {
+        settings
+            .get(&key)
+            .map(|filter| filter.trim().to_string())
+            .filter(|filter| !filter.is_empty())
+    };
+    let read = setting(format!("context.{}.read", context));
+    let old = setting(format!("context.{}", context));
+
+    match (read, old) {
+        (Some(read), Some(old)) => Ok(Some(if version()? >= READ_CONTEXTS {
+            read
+        } else {
+            old
+        })),
+        (Some(filter), None) | (None, Some(filter)) => Ok(Some(filter)),
+        (None, None) => Err(TSError::Error(format!(
+            "context '{}' has no filter",
+            context
+        ))),
+    }
+}
+
+fn read_all<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn settings(lines: &[(&str, &str)]) -> HashMap<String, String> {
+        lines
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn version(version: &str) -> impl FnOnce() -> Result<Version> + '_ {
+        move || Ok(Version::parse(version).unwrap())
+    }
+
+    #[test]
+    fn reading_the_settings() {
+        let taskwarrior: Taskwarrior =
+            serde_json::from_str(r#"{"rc_file": "~/.taskrc", "data_dir": "/srv/task"}"#).unwrap();
+        assert_eq!(
+            taskwarrior.rc_file,
+            Some(dirs::home_dir().unwrap().join(".taskrc"))
+        );
+        assert_eq!(taskwarrior.data_dir, Some(PathBuf::from("/srv/task")));
+        assert_eq!(taskwarrior.timeout_secs, 30);
+
+        assert!(serde_json::from_str::<Taskwarrior>(r#"{"timeout_secs": 0}"#).is_err());
+    }
+
+    #[test]
+    fn parsing_versions() {
+        assert_eq!(
+            Version::parse("2.5.1\n"),
+            Some(Version {
+                major: 2,
+                minor: 5,
+                patch: 1
+            })
+        );
+        assert_eq!(
+            Version::parse("2.6.0 (a1b2c3d)").unwrap().to_string(),
+            "2.6.0"
+        );
+        assert_eq!(Version::parse("3.0.0-beta").unwrap().to_string(), "3.0.0");
+        assert!(Version::parse("2.6.0").unwrap() >= READ_CONTEXTS);
+        assert!(Version::parse("2.5.3").unwrap() < READ_CONTEXTS);
+        assert_eq!(Version::parse("task"), None);
+    }
+
+    #[test]
+    fn looking_up_contexts() {
+        let no_version = || -> Result<Version> { panic!("the version wasn't needed") };
+
+        assert_eq!(context_filter(&settings(&[]), no_version).unwrap(), None);
+        assert_eq!(
+            context_filter(&settings(&[("context", "")]), no_version).unwrap(),
+            None
+        );
+
+        let old = settings(&[("context", "work"), ("context.work", "+work")]);
+        assert_eq!(
+            context_filter(&old, no_version).unwrap().as_deref(),
+            Some("+work")
+        );
+
+        let read = settings(&[
+            ("context", "work"),
+            ("context.work.read", "+work"),
+            ("context.work.write", "+work"),
+        ]);
+        assert_eq!(
+            context_filter(&read, no_version).unwrap().as_deref(),
+            Some("+work")
+        );
+
+        let both = settings(&[
+            ("context", "work"),
+            ("context.work", "+old"),
+            ("context.work.read", "+read"),
+        ]);
+        assert_eq!(
+            context_filter(&both, version("2.6.2")).unwrap().as_deref(),
+            Some("+read")
+        );
+        assert_eq!(
+            context_filter(&both, version("2.5.1")).unwrap().as_deref(),
+            Some("+old")
+        );
+
+        let missing = settings(&[("context", "work")]);
+        assert!(context_filter(&missing, no_version).is_err());
+    }
+
+    /// A `task` that answers from `script`, in a directory of its own.
+    #[cfg(unix)]
+    fn fake_task(script: &str) -> (tempfile::TempDir, Taskwarrior) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("task");
+        fs::write(&binary, format!("#!/bin/sh\n{}", script)).unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let taskwarrior = Taskwarrior {
+            binary,
+            ..Taskwarrior::default()
+        };
+        (dir, taskwarrior)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exporting() {
+        let (dir, mut taskwarrior) = fake_task(
+            r#"
+            case "$1" in
+            _show) printf 'context=work\ncontext.work.read=project:work\ndata.location=~/.task\n' ;;
+            *) echo "$TASKRC $*" > "$(dirname "$0")/args"
+               echo '[{"status":"pending","uuid":"d3c2052f-31b5-4544-94bc-af3ef1b10c4b","entry":"20201118T071926Z","description":"write tests"}]' ;;
+            esac
+            "#,
+        );
+        taskwarrior.rc_file = Some("/home/me/.taskrc".into());
+
+        let tasks = taskwarrior.export("status:pending +stream").unwrap();
+        assert_eq!(tasks[0].description(), "write tests");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("args")).unwrap().trim(),
+            "/home/me/.taskrc rc.json.array=on rc.confirmation=off rc.hooks=off export \
+             status:pending +stream project:work"
+        );
+
+        taskwarrior.data_dir = Some("/home/me/tasks".into());
+        assert_eq!(
+            taskwarrior.data_location().unwrap(),
+            PathBuf::from("/home/me/tasks")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failures_carry_what_task_said() {
+        let (_dir, taskwarrior) = fake_task("echo 'Unrecognized setting' >&2; exit 2");
+        match taskwarrior.export("") {
+            Err(TSError::TaskError { code, stderr, .. }) => {
+                assert_eq!(code, Some(2));
+                assert_eq!(stderr, "Unrecognized setting");
+            }
+            other => panic!("expected a task error, got {:?}", other),
+        }
+
+        let (_dir, taskwarrior) = fake_task("case \"$1\" in _show) ;; *) echo '[{' ;; esac");
+        let error = taskwarrior.export("").unwrap_err().to_string();
+        assert!(error.starts_with("could not read the tasks taskwarrior exported: "));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn slow_commands_are_killed() {
+        let (_dir, mut taskwarrior) = fake_task("sleep 5");
+        taskwarrior.timeout_secs = 1;
+
+        let started = Instant::now();
+        assert!(matches!(
+            taskwarrior.version(),
+            Err(TSError::TaskTimeout { .. })
+        ));
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+}
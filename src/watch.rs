@@ -0,0 +1,193 @@
+//! `client watch`: pushes the tasks whenever taskwarrior's data changes, so
+//! nobody has to remember to run `client update`.
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::Duration;
+
+use actix_web::rt::time::delay_for;
+use futures::channel::mpsc;
+use futures::future::{select, Either};
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use task_hookrs::task::Task;
+
+use crate::client::{Client, Sent};
+use crate::error::Result;
+use crate::outbox::{Kind, Update};
+use crate::tasks::TaskClient;
+
+/// How long to wait for a burst of writes, e.g. a `task done` touching
+/// several files, to settle before exporting.
+pub const DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// How soon a push that failed, or anything else in the outbox, is tried
+/// again, changes or not.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Watches `data_dir` until the process is stopped, pushing the tasks
+/// whenever the exported list changes. Failed exports and pushes are logged
+/// and retried rather than ending the watch, as is whatever other commands
+/// left in the outbox.
+pub async fn watch(
+    client: &Client,
+    task_client: &mut TaskClient,
+    data_dir: &Path,
+    debounce: Duration,
+) -> Result<()> {
+    let (sender, receiver) = std_mpsc::channel();
+    let mut watcher = notify::watcher(sender, debounce)?;
+    watcher.watch(data_dir, RecursiveMode::NonRecursive)?;
+    info!("Watching {}", data_dir.display());
+
+    // notify only hands events to a blocking receiver
+    let (forward, mut changes) = mpsc::unbounded();
+    thread::spawn(move || {
+        for event in receiver {
+            if forward.unbounded_send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut pushed: Option<Vec<Task>> = None;
+    // the tasks may have changed since they were last pushed
+    let mut pending = true;
+
+    loop {
+        if pending {
+            pending = !push_if_changed(client, task_client, &mut pushed).await;
+        }
+
+        let event = match select(changes.next(), Box::pin(delay_for(RETRY_INTERVAL))).await {
+            Either::Left((event, _)) => event,
+            Either::Right(_) => {
+                // the tasks are this watch's to push
+                match client.replay_outbox(Some(Kind::Tasks)).await {
+                    Ok(0) => (),
+                    Ok(sent) => info!("Sent {} queued updates", sent),
+                    Err(e) => warn!("Could not read the outbox: {}", e),
+                }
+                continue;
+            }
+        };
+
+        match event {
+            Some(event) => pending |= is_change(&event),
+            None => return Ok(()),
+        }
+
+        // anything else that already arrived is part of the same change
+        while let Ok(event) = changes.try_recv() {
+            pending |= is_change(&event);
+        }
+    }
+}
+
+/// Exports the tasks and pushes them if they differ from what was last
+/// pushed. Gives whether the server has the current tasks.
+async fn push_if_changed(
+    client: &Client,
+    task_client: &mut TaskClient,
+    pushed: &mut Option<Vec<Task>>,
+) -> bool {
+    if let Err(e) = task_client.refresh_tasks() {
+        error!("Could not export tasks: {}", e);
+        return false;
+    }
+
+    if pushed.as_ref() == Some(&task_client.tasks) {
+        debug!("Tasks unchanged, not pushing");
+        return true;
+    }
+
+    match client.send(Update::Tasks(task_client.tasks.clone())).await {
+        Ok(Sent::Delivered) => {
+            info!("Pushed {} tasks", task_client.tasks.len());
+            *pushed = Some(task_client.tasks.clone());
+            true
+        }
+        Ok(Sent::Queued(e)) => {
+            warn!("Could not push tasks, queued them and retrying: {}", e);
+            false
+        }
+        Err(e) => {
+            warn!("Could not push tasks, retrying: {}", e);
+            false
+        }
+    }
+}
+
+/// Taskwarrior 3's database, and where its writes land before they are
+/// checkpointed into it. The `-shm` file also changes on reads, so it doesn't
+/// count.
+const TASKCHAMPION_FILES: &[&str] = &[
+    "taskchampion.sqlite3",
+    "taskchampion.sqlite3-wal",
+    "taskchampion.sqlite3-journal",
+];
+
+/// Whether `event` is a change to taskwarrior 2's `.data` files or to
+/// taskwarrior 3's database, rather than to a lock file or an early notice of
+/// a write still in progress.
+fn is_change(event: &DebouncedEvent) -> bool {
+    let path = match event {
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Remove(path)
+        | DebouncedEvent::Rename(_, path) => path,
+        // events were dropped, so anything could have changed
+        DebouncedEvent::Rescan => return true,
+        DebouncedEvent::Error(e, _) => {
+            error!("Error watching taskwarrior data: {}", e);
+            return false;
+        }
+        DebouncedEvent::NoticeWrite(_)
+        | DebouncedEvent::NoticeRemove(_)
+        | DebouncedEvent::Chmod(_) => return false,
+    };
+
+    path.extension() == Some(OsStr::new("data"))
+        || path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| TASKCHAMPION_FILES.contains(&name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn only_data_files_count() {
+        let data = PathBuf::from("/home/me/.task/pending.data");
+        let lock = PathBuf::from("/home/me/.task/pending.data.lock");
+
+        assert!(is_change(&DebouncedEvent::Write(data.clone())));
+        assert!(is_change(&DebouncedEvent::Rename(
+            lock.clone(),
+            data.clone()
+        )));
+        assert!(is_change(&DebouncedEvent::Rescan));
+        assert!(!is_change(&DebouncedEvent::Write(lock)));
+        assert!(!is_change(&DebouncedEvent::NoticeWrite(data)));
+    }
+
+    #[test]
+    fn taskchampion_databases_count() {
+        let dir = PathBuf::from("/home/me/.task");
+
+        assert!(is_change(&DebouncedEvent::Write(
+            dir.join("taskchampion.sqlite3")
+        )));
+        assert!(is_change(&DebouncedEvent::Write(
+            dir.join("taskchampion.sqlite3-wal")
+        )));
+        assert!(!is_change(&DebouncedEvent::Write(
+            dir.join("taskchampion.sqlite3-shm")
+        )));
+    }
+}
@@ -1,9 +1,22 @@
 use task_hookrs::task::Task;
-use task_streamer::schema::Topic;
+use task_streamer::schema::{
+    DisplayTask, Envelope, Pushed, Segment, TaskDelta, TaskView, Topic, UrgencyLevel, UrgencyLevels, SCHEMA_V1,
+    SCHEMA_V2,
+};
+use task_streamer::encoding::Encoding;
 use task_streamer::app::{AppState, app_config};
-use actix_web::{rt as actix_rt, test, web, App};
+use task_streamer::frontend::{self, StaticDir};
+use task_streamer::limits::{Limits, RateLimit};
+use task_streamer::redact::Redaction;
+use actix_web::{http, rt as actix_rt, test, web, App};
+use actix_web::middleware::Compress;
+use actix_web_actors::ws::{Frame, Message};
+use actix_http::ws::Item;
+use bytes::BytesMut;
+use futures::{SinkExt, Stream, StreamExt};
 
 use serde_json;
+use std::fs;
 
 fn fake_tasks() -> Vec<Task> {
     let task_json = r#"
@@ -28,10 +41,7 @@ async fn listsing_tasks() {
     let tasks: Vec<Task> = test::read_body_json(resp).await;
     assert!(tasks.is_empty());
 
-    {
-        let mut t = state.tasks.lock().unwrap();
-        *t = fake_tasks();
-    }
+    state.set_tasks(fake_tasks()).unwrap();
 
     let req = test::TestRequest::with_header("content-type", "application/json").uri("/api/v1/tasks").to_request();
     let resp = test::call_service(&mut app, req).await;
@@ -51,9 +61,7 @@ async fn setting_tasks() {
             .configure(app_config)
     ).await;
 
-    {
-        assert_eq!(*state.tasks.lock().unwrap(), vec![]);
-    }
+    assert_eq!(state.tasks.load().value, vec![]);
 
     let req = test::TestRequest::post()
         .header("content-type", "application/json")
@@ -64,10 +72,74 @@ async fn setting_tasks() {
 
     let resp = test::call_service(&mut app, req).await;
     assert!(resp.status().is_success());
+    assert!(test::read_body(resp).await.is_empty());
 
-    {
-        assert_eq!(*state.tasks.lock().unwrap(), fake_tasks());
-    }
+    assert_eq!(state.tasks.load().value, fake_tasks());
+}
+
+#[actix_rt::test]
+async fn setting_task_deltas() {
+    let state = web::Data::new(AppState::new("Foo bar baz".to_string()));
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(app_config)
+    ).await;
+
+    let old = fake_tasks()[..4].to_vec();
+    let req = test::TestRequest::post()
+        .header("Authorization", "Bearer Foo bar baz")
+        .uri("/api/v2/tasks")
+        .set_json(&old)
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert!(resp.status().is_success());
+    let base: Pushed = test::read_body_json(resp).await;
+    assert_eq!(base, state.pushed());
+
+    let mut new = fake_tasks()[1..].to_vec();
+    *new[0].description_mut() = "add more tests".to_string();
+    let delta = TaskDelta::between(base, &old, &new);
+
+    let req = test::TestRequest::post()
+        .uri("/api/v2/tasks/delta")
+        .set_json(&delta)
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+    // v1 is as it was
+    let req = test::TestRequest::post()
+        .header("Authorization", "Bearer Foo bar baz")
+        .uri("/api/v1/tasks/delta")
+        .set_json(&delta)
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+
+    let req = test::TestRequest::post()
+        .header("Authorization", "Bearer Foo bar baz")
+        .uri("/api/v2/tasks/delta")
+        .set_json(&delta)
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    let pushed: Pushed = test::read_body_json(resp).await;
+    assert_eq!(pushed.revision, base.revision + 1);
+    assert_eq!(state.tasks.load().value, new);
+
+    // made against a revision that is gone now
+    let req = test::TestRequest::post()
+        .header("Authorization", "Bearer Foo bar baz")
+        .uri("/api/v2/tasks/delta")
+        .set_json(&delta)
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::CONFLICT);
+    let current: Pushed = test::read_body_json(resp).await;
+    assert_eq!(current, pushed);
+    assert_eq!(state.tasks.load().value, new);
 }
 
 #[actix_rt::test]
@@ -89,10 +161,7 @@ async fn getting_topic() {
 
     let expected = Topic::new("herp".to_string(), "derp".to_string());
 
-    {
-        let mut t = state.topic.lock().unwrap();
-        *t = expected.clone();
-    }
+    state.set_topic(expected.clone()).unwrap();
 
     let req = test::TestRequest::with_header("content-type", "application/json").uri("/api/v1/topic").to_request();
     let resp = test::call_service(&mut app, req).await;
@@ -124,7 +193,946 @@ async fn setting_topic() {
     let resp = test::call_service(&mut app, req).await;
     assert!(resp.status().is_success());
 
-    {
-        assert_eq!(*state.topic.lock().unwrap(), expected);
+    assert_eq!(state.topic.load().value, expected);
+}
+
+#[actix_rt::test]
+async fn both_api_versions() {
+    let state = web::Data::new(AppState::new("Foo bar baz".to_string()));
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(app_config)
+    ).await;
+
+    let req = test::TestRequest::post()
+        .header("Authorization", "Bearer Foo bar baz")
+        .uri(&format!("/api/v{}/tasks", SCHEMA_V2))
+        .set_json(&fake_tasks())
+        .to_request();
+    assert!(test::call_service(&mut app, req).await.status().is_success());
+
+    // v1 is unchanged: the tasks as they were set
+    let req = test::TestRequest::get().uri(&format!("/api/v{}/tasks", SCHEMA_V1)).to_request();
+    let resp = test::call_service(&mut app, req).await;
+    let v1_etag = resp.headers().get(http::header::ETAG).unwrap().clone();
+    let tasks: Vec<Task> = test::read_body_json(resp).await;
+    assert_eq!(tasks, fake_tasks());
+
+    let req = test::TestRequest::get().uri(&format!("/api/v{}/tasks", SCHEMA_V2)).to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert!(resp.status().is_success());
+    assert_ne!(resp.headers().get(http::header::ETAG).unwrap(), &v1_etag);
+
+    let envelope: Envelope<Vec<DisplayTask>> = test::read_body_json(resp).await;
+    assert_eq!(envelope.revision, 1);
+    assert_eq!(envelope.updated_by.as_deref(), Some("api"));
+    assert_eq!(envelope.data.len(), fake_tasks().len());
+    assert_eq!(envelope.data[7].description, "display controls on hover");
+    assert_eq!(envelope.data[7].urgency, 17.0);
+    assert_eq!(envelope.data[7].entry.to_rfc3339(), "2021-01-03T06:01:10+00:00");
+
+    let req = test::TestRequest::get().uri(&format!("/api/v{}/topic", SCHEMA_V1)).to_request();
+    let topic: Topic = test::read_response_json(&mut app, req).await;
+    assert_eq!(topic, Topic::default());
+
+    let req = test::TestRequest::get().uri(&format!("/api/v{}/topic", SCHEMA_V2)).to_request();
+    let envelope: Envelope<Topic> = test::read_response_json(&mut app, req).await;
+    assert_eq!(envelope.revision, 0);
+    assert_eq!(envelope.updated_by, None);
+    assert_eq!(envelope.data, Topic::default());
+}
+
+#[actix_rt::test]
+async fn completing_topic_segments() {
+    let state = web::Data::new(AppState::new("Foo bar baz".to_string()));
+    state.set_topic(Topic {
+        segments: vec![Segment::new("intro".to_string()), Segment::new("code".to_string())],
+        ..Topic::new("herp".to_string(), "derp".to_string())
+    }).unwrap();
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(app_config)
+    ).await;
+
+    let req = test::TestRequest::post().uri("/api/v1/topic/segments/next").to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+    for done in &[[true, false], [true, true]] {
+        let req = test::TestRequest::post()
+            .header("Authorization", "Bearer Foo bar baz")
+            .uri("/api/v1/topic/segments/next")
+            .to_request();
+        let topic: Topic = test::read_response_json(&mut app, req).await;
+        let segments: Vec<bool> = topic.segments.iter().map(|segment| segment.done).collect();
+        assert_eq!(segments, done.to_vec());
+    }
+
+    let req = test::TestRequest::post()
+        .header("Authorization", "Bearer Foo bar baz")
+        .uri("/api/v1/topic/segments/next")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::CONFLICT);
+    assert_eq!(state.topic.load().revision, 3);
+}
+
+#[actix_rt::test]
+async fn conditional_gets() {
+    let state = web::Data::new(AppState::new("Foo bar baz".to_string()));
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(app_config)
+    ).await;
+
+    let req = test::TestRequest::get().uri("/api/v1/tasks").to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    assert!(resp.headers().contains_key(http::header::LAST_MODIFIED));
+    let etag = resp.headers().get(http::header::ETAG).unwrap().clone();
+    // the same tag goes out with gzipped bodies
+    assert!(etag.to_str().unwrap().starts_with("W/"));
+    let last_modified = resp.headers().get(http::header::LAST_MODIFIED).unwrap().clone();
+
+    let req = test::TestRequest::get()
+        .header(http::header::IF_NONE_MATCH, etag.clone())
+        .uri("/api/v1/tasks")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::NOT_MODIFIED);
+
+    state.set_tasks(fake_tasks()).unwrap();
+    assert_eq!(state.tasks.load().revision, 1);
+
+    // caught even when the update came within the same second
+    let req = test::TestRequest::get()
+        .header(http::header::IF_MODIFIED_SINCE, last_modified.clone())
+        .uri("/api/v1/tasks")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .header(http::header::IF_NONE_MATCH, etag.clone())
+        .header(http::header::IF_MODIFIED_SINCE, "Fri, 01 Jan 2100 00:00:00 GMT")
+        .uri("/api/v1/tasks")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    assert_ne!(resp.headers().get(http::header::ETAG).unwrap(), &etag);
+
+    let tasks: Vec<Task> = test::read_body_json(resp).await;
+    assert_eq!(tasks, fake_tasks());
+
+    // the topic shares the revision counter with the tasks
+    state.set_topic(Topic::new("herp".to_string(), "derp".to_string())).unwrap();
+    assert_eq!(state.topic.load().revision, 2);
+}
+
+#[actix_rt::test]
+async fn compressed_responses() {
+    let state = web::Data::new(AppState::new("Foo bar baz".to_string()));
+    state.set_tasks(fake_tasks()).unwrap();
+
+    let mut app = test::init_service(
+        App::new()
+            .wrap(Compress::default())
+            .app_data(state.clone())
+            .configure(app_config)
+    ).await;
+
+    for encoding in &["gzip", "br"] {
+        let req = test::TestRequest::get()
+            .header(http::header::ACCEPT_ENCODING, *encoding)
+            .uri("/api/v1/tasks")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(resp.headers().get(http::header::CONTENT_ENCODING).unwrap(), encoding);
+    }
+
+    let req = test::TestRequest::get().uri("/api/v1/tasks").to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert!(!resp.headers().contains_key(http::header::CONTENT_ENCODING));
+}
+
+#[actix_rt::test]
+async fn serving_static_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("index.html"), "<html></html>").unwrap();
+    fs::write(dir.path().join("app.js"), "console.log('hi');").unwrap();
+    fs::write(dir.path().join(".env"), "SECRET=1").unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    fs::write(outside.path().join("secret.txt"), "SECRET=1").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(outside.path().join("secret.txt"), dir.path().join("leak.txt")).unwrap();
+
+    let state = web::Data::new(AppState::new("Foo bar baz".to_string()));
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(app_config)
+            .data(StaticDir(dir.path().to_path_buf()))
+            .default_service(web::route().to(frontend::serve))
+    ).await;
+
+    let req = test::TestRequest::get().uri("/app.js").to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    assert_eq!(resp.headers().get(http::header::CONTENT_TYPE).unwrap(), "application/javascript");
+    assert_eq!(resp.headers().get(http::header::CACHE_CONTROL).unwrap(), "public, max-age=3600");
+
+    for uri in &["/", "/overlay/left"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(resp.headers().get(http::header::CONTENT_TYPE).unwrap(), "text/html");
+        assert_eq!(resp.headers().get(http::header::CACHE_CONTROL).unwrap(), "no-cache");
+        assert_eq!(test::read_body(resp).await, "<html></html>");
+    }
+
+    // api routes win over the frontend
+    let req = test::TestRequest::get().uri("/api/v1/tasks").to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.headers().get(http::header::CONTENT_TYPE).unwrap(), "application/json");
+
+    for uri in &["/api/v1/nope", "/missing.js", "/.env", "/../Cargo.toml", "/%2e%2e/Cargo.toml", "/leak.txt"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND, "{}", uri);
+    }
+}
+
+#[actix_rt::test]
+async fn rate_limiting() {
+    let limits = Limits {
+        writes: Some(RateLimit { burst: 2, per_second: 0.5 }),
+        reads: Some(RateLimit { burst: 3, per_second: 0.5 }),
+        ..Limits::default()
+    };
+    let state = web::Data::new(AppState::new("Foo bar baz".to_string()).with_limits(&limits));
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(app_config)
+    ).await;
+
+    let post = |peer: &str| {
+        test::TestRequest::post()
+            .header("Authorization", "Bearer Foo bar baz")
+            .peer_addr(peer.parse().unwrap())
+            .uri("/api/v1/topic")
+            .set_json(&Topic::default())
+            .to_request()
+    };
+
+    for _ in 0..2 {
+        let resp = test::call_service(&mut app, post("10.0.0.1:5000")).await;
+        assert!(resp.status().is_success());
+    }
+
+    let resp = test::call_service(&mut app, post("10.0.0.1:5000")).await;
+    assert_eq!(resp.status(), http::StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get(http::header::RETRY_AFTER).unwrap(), "2");
+
+    // other clients and reads have their own buckets
+    let resp = test::call_service(&mut app, post("10.0.0.2:5000")).await;
+    assert!(resp.status().is_success());
+
+    for expected in &[200, 200, 200, 429] {
+        let req = test::TestRequest::get()
+            .peer_addr("10.0.0.1:5000".parse().unwrap())
+            .uri("/api/v1/tasks")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status().as_u16(), *expected);
+    }
+
+    // GraphQL queries are POSTs, but count as reads
+    for _ in 0..2 {
+        let resp = test::call_service(&mut app, post("10.0.0.3:5000")).await;
+        assert!(resp.status().is_success());
+    }
+
+    for expected in &[200, 200, 200, 429] {
+        let req = test::TestRequest::post()
+            .peer_addr("10.0.0.3:5000".parse().unwrap())
+            .uri("/graphql")
+            .set_json(&serde_json::json!({"query": "{ topic { title } }"}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status().as_u16(), *expected);
+    }
+}
+
+#[actix_rt::test]
+async fn limiting_websocket_sessions() {
+    let limits = Limits {
+        max_sessions: Some(2),
+        max_sessions_per_ip: Some(1),
+        ..Limits::default()
+    };
+    let state = web::Data::new(AppState::new("Foo bar baz".to_string()).with_limits(&limits));
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(app_config)
+    ).await;
+
+    let connect = |peer: &str| {
+        test::TestRequest::get()
+            .header(http::header::UPGRADE, "websocket")
+            .header(http::header::CONNECTION, "upgrade")
+            .header(http::header::SEC_WEBSOCKET_VERSION, "13")
+            .header(http::header::SEC_WEBSOCKET_KEY, "13")
+            .peer_addr(peer.parse().unwrap())
+            .uri("/ws/")
+            .to_request()
+    };
+
+    // the session, and its permit, live as long as the response body
+    let first = test::call_service(&mut app, connect("10.0.0.1:5000")).await;
+    assert_eq!(first.status(), http::StatusCode::SWITCHING_PROTOCOLS);
+
+    let resp = test::call_service(&mut app, connect("10.0.0.1:5001")).await;
+    assert_eq!(resp.status(), http::StatusCode::TOO_MANY_REQUESTS);
+    assert!(resp.headers().contains_key(http::header::RETRY_AFTER));
+
+    let second = test::call_service(&mut app, connect("10.0.0.2:5000")).await;
+    assert_eq!(second.status(), http::StatusCode::SWITCHING_PROTOCOLS);
+
+    let resp = test::call_service(&mut app, connect("10.0.0.3:5000")).await;
+    assert_eq!(resp.status(), http::StatusCode::TOO_MANY_REQUESTS);
+
+    drop(first);
+    let resp = test::call_service(&mut app, connect("10.0.0.1:5000")).await;
+    assert_eq!(resp.status(), http::StatusCode::SWITCHING_PROTOCOLS);
+    drop(second);
+}
+
+fn stream_redaction() -> Redaction {
+    Redaction {
+        redact_tags: vec!["next".to_string()],
+        ..Redaction::default()
+    }
+}
+
+#[actix_rt::test]
+async fn public_and_private_task_views() {
+    let state = web::Data::new(
+        AppState::new("Foo bar baz".to_string()).with_redaction(stream_redaction())
+    );
+    state.set_tasks(fake_tasks()).unwrap();
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(app_config)
+    ).await;
+
+    let req = test::TestRequest::get().uri("/api/v1/tasks").to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert!(resp.status().is_success());
+    let public_etag = resp.headers().get(http::header::ETAG).unwrap().clone();
+
+    let tasks: Vec<Task> = test::read_body_json(resp).await;
+    assert_eq!(tasks, stream_redaction().apply(fake_tasks()));
+    assert_eq!(tasks.last().unwrap().description(), "[redacted]");
+
+    let req = test::TestRequest::get()
+        .header("Authorization", "Bearer Foo bar baz")
+        .header(http::header::IF_NONE_MATCH, public_etag)
+        .uri("/api/v1/tasks")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    // a cached public view is no good to an authenticated reader
+    assert_eq!(resp.status(), http::StatusCode::OK);
+
+    let tasks: Vec<Task> = test::read_body_json(resp).await;
+    assert_eq!(tasks, fake_tasks());
+}
+
+/// Reads the next text message, reassembling continuation frames.
+async fn next_text<S, E>(socket: &mut S) -> String
+where
+    S: Stream<Item = Result<Frame, E>> + Unpin,
+    E: std::fmt::Debug,
+{
+    let mut text = BytesMut::new();
+
+    loop {
+        match socket.next().await.unwrap().unwrap() {
+            Frame::Text(data) => return String::from_utf8(data.to_vec()).unwrap(),
+            Frame::Continuation(Item::FirstText(data)) | Frame::Continuation(Item::Continue(data)) => {
+                text.extend_from_slice(&data)
+            }
+            Frame::Continuation(Item::Last(data)) => {
+                text.extend_from_slice(&data);
+                return String::from_utf8(text.to_vec()).unwrap();
+            }
+            _ => (),
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn authenticated_websocket_sessions() {
+    let state = web::Data::new(
+        AppState::new("secret".to_string()).with_redaction(stream_redaction())
+    );
+
+    let srv_state = state.clone();
+    let srv = test::start(move || {
+        App::new()
+            .app_data(srv_state.clone())
+            .configure(app_config)
+    });
+    let url = format!("ws://{}/ws/", srv.addr());
+    let client = awc::Client::new();
+
+    let (_, mut anonymous) = client.ws(url.as_str()).protocols(["task-streamer"]).connect().await.unwrap();
+
+    let (_, mut by_query) = client
+        .ws(format!("{}?token=secret", url))
+        .protocols(["task-streamer"])
+        .connect()
+        .await
+        .unwrap();
+
+    let (resp, mut by_protocol) = client
+        .ws(url.as_str())
+        .protocols(["task-streamer", "bearer.secret"])
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers().get(http::header::SEC_WEBSOCKET_PROTOCOL).unwrap(), "task-streamer");
+
+    let refused = client.ws(url.as_str()).protocols(["task-streamer", "bearer.wrong"]).connect().await;
+    assert!(refused.is_err());
+
+    // sessions register with the manager asynchronously
+    actix_rt::time::delay_for(std::time::Duration::from_millis(100)).await;
+    state.set_tasks(fake_tasks()).unwrap();
+
+    let public: serde_json::Value = serde_json::from_str(&next_text(&mut anonymous).await).unwrap();
+    assert_eq!(public["data"], serde_json::to_value(stream_redaction().apply(fake_tasks())).unwrap());
+
+    for socket in &mut [&mut by_query, &mut by_protocol] {
+        let private: serde_json::Value = serde_json::from_str(&next_text(socket).await).unwrap();
+        assert_eq!(private["data"], serde_json::to_value(fake_tasks()).unwrap());
+    }
+}
+
+#[actix_rt::test]
+async fn legacy_websocket_sessions() {
+    let state = web::Data::new(AppState::new("secret".to_string()));
+
+    let srv_state = state.clone();
+    let srv = test::start(move || {
+        App::new()
+            .app_data(srv_state.clone())
+            .configure(app_config)
+    });
+    let client = awc::Client::new();
+
+    // clients offering no subprotocol get the bare notifications they always did
+    let (resp, mut socket) = client.ws(format!("ws://{}/ws/", srv.addr())).connect().await.unwrap();
+    assert!(resp.headers().get(http::header::SEC_WEBSOCKET_PROTOCOL).is_none());
+
+    // sessions register with the manager asynchronously
+    actix_rt::time::delay_for(std::time::Duration::from_millis(100)).await;
+    state.set_topic(Topic::new("legacy".to_string(), "".to_string())).unwrap();
+    state.set_tasks(fake_tasks()).unwrap();
+
+    for event in &["topic_updated", "tasks-updated"] {
+        match socket.next().await.unwrap().unwrap() {
+            Frame::Text(data) => assert_eq!(data, event),
+            frame => panic!("expected a single text frame, got {:?}", frame),
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn private_websocket_stream() {
+    let state = web::Data::new(
+        AppState::new("Foo bar baz".to_string()).with_private_stream(true)
+    );
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(app_config)
+    ).await;
+
+    let connect = |uri: &str, protocols: &str| {
+        test::TestRequest::get()
+            .header(http::header::UPGRADE, "websocket")
+            .header(http::header::CONNECTION, "upgrade")
+            .header(http::header::SEC_WEBSOCKET_VERSION, "13")
+            .header(http::header::SEC_WEBSOCKET_KEY, "13")
+            .header(http::header::SEC_WEBSOCKET_PROTOCOL, protocols)
+            .uri(uri)
+            .to_request()
+    };
+
+    let resp = test::call_service(&mut app, connect("/ws/", "task-streamer")).await;
+    assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+    let resp = test::call_service(&mut app, connect("/ws/", "task-streamer, bearer.nope")).await;
+    assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+    let resp = test::call_service(&mut app, connect("/ws/?token=nope", "task-streamer")).await;
+    assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+    let resp = test::call_service(&mut app, connect("/ws/?token=Foo%20bar%20baz", "task-streamer")).await;
+    assert_eq!(resp.status(), http::StatusCode::SWITCHING_PROTOCOLS);
+
+    let resp = test::call_service(&mut app, connect("/ws/", "task-streamer, bearer.Foo bar baz")).await;
+    assert_eq!(resp.status(), http::StatusCode::SWITCHING_PROTOCOLS);
+}
+
+/// Sends a command and waits for its answer, skipping any updates.
+async fn command<S>(socket: &mut S, command: serde_json::Value) -> serde_json::Value
+where
+    S: Stream<Item = Result<Frame, awc::error::WsProtocolError>>
+        + futures::Sink<Message, Error = awc::error::WsProtocolError>
+        + Unpin,
+{
+    socket.send(Message::Text(command.to_string())).await.unwrap();
+
+    loop {
+        let frame: serde_json::Value = serde_json::from_str(&next_text(socket).await).unwrap();
+        if frame["id"] == command["id"] {
+            return frame;
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn websocket_commands() {
+    let state = web::Data::new(AppState::new("secret".to_string()));
+    state.set_tasks(fake_tasks()).unwrap();
+
+    let srv_state = state.clone();
+    let srv = test::start(move || {
+        App::new()
+            .app_data(srv_state.clone())
+            .configure(app_config)
+    });
+    let url = format!("ws://{}/ws/", srv.addr());
+    let client = awc::Client::new();
+
+    let (_, mut anonymous) = client.ws(url.as_str()).protocols(["task-streamer"]).connect().await.unwrap();
+
+    let reply = command(&mut anonymous, serde_json::json!({"id": 1, "command": "hello", "client": "overlay", "version": "1.0"})).await;
+    assert_eq!(reply["event"], "reply");
+    assert_eq!(reply["id"], 1);
+    assert_eq!(reply["data"]["authenticated"], false);
+
+    let reply = command(&mut anonymous, serde_json::json!({"id": 2, "command": "ping"})).await;
+    assert_eq!(reply["command"], "ping");
+
+    let reply = command(&mut anonymous, serde_json::json!({"id": 3, "command": "snapshot"})).await;
+    assert_eq!(reply["data"]["tasks"], serde_json::to_value(fake_tasks()).unwrap());
+    assert_eq!(reply["data"]["topic"], serde_json::to_value(Topic::default()).unwrap());
+
+    let reply = command(&mut anonymous, serde_json::json!({"id": 4, "command": "launch-missiles"})).await;
+    assert_eq!(reply["event"], "error");
+    assert_eq!(reply["id"], 4);
+
+    let reply = command(&mut anonymous, serde_json::json!({"id": 5, "command": "set-topic", "data": {"title": "pwned"}})).await;
+    assert_eq!(reply["event"], "error");
+    assert_eq!(reply["error"], "unauthorized");
+    assert_eq!(state.topic.load().value, Topic::default());
+
+    let reply = command(&mut anonymous, serde_json::json!({"id": 6, "command": "unsubscribe", "events": ["tasks-updated"]})).await;
+    assert_eq!(reply["data"]["events"], serde_json::json!(["topic_updated"]));
+
+    let (_, mut authenticated) = client
+        .ws(url.as_str())
+        .protocols(["task-streamer", "bearer.secret"])
+        .connect()
+        .await
+        .unwrap();
+
+    let reply = command(&mut authenticated, serde_json::json!({"id": 7, "command": "set-tasks", "data": []})).await;
+    assert_eq!(reply["event"], "reply");
+    assert!(state.tasks.load().value.is_empty());
+
+    let topic = Topic::new("herp".to_string(), "derp".to_string());
+    let reply = command(&mut authenticated, serde_json::json!({"id": 8, "command": "set-topic", "data": topic})).await;
+    assert_eq!(reply["event"], "reply");
+
+    // the anonymous session was not subscribed to the tasks update
+    let update: serde_json::Value = serde_json::from_str(&next_text(&mut anonymous).await).unwrap();
+    assert_eq!(update["event"], "topic_updated");
+    assert_eq!(update["data"], serde_json::to_value(topic).unwrap());
+}
+
+#[actix_rt::test]
+async fn filtered_websocket_sessions() {
+    let state = web::Data::new(AppState::new("secret".to_string()));
+
+    let srv_state = state.clone();
+    let srv = test::start(move || {
+        App::new()
+            .app_data(srv_state.clone())
+            .configure(app_config)
+    });
+    let url = format!("ws://{}/ws/", srv.addr());
+    let client = awc::Client::new();
+
+    assert!(client.ws(format!("{}?sort=vibes", url)).connect().await.is_err());
+
+    let (_, mut ticker) = client
+        .ws(format!("{}?tags=next&limit=1", url))
+        .protocols(["task-streamer"])
+        .connect()
+        .await
+        .unwrap();
+    let (_, mut backlog) = client.ws(url.as_str()).protocols(["task-streamer"]).connect().await.unwrap();
+
+    let reply = command(&mut backlog, serde_json::json!({
+        "id": 1,
+        "command": "subscribe",
+        "filter": {"projects": ["twitch"], "sort": "urgency", "limit": 2},
+    })).await;
+    assert_eq!(reply["data"]["events"], serde_json::json!(["tasks-updated", "topic_updated"]));
+    assert_eq!(reply["data"]["filter"]["limit"], 2);
+
+    state.set_tasks(fake_tasks()).unwrap();
+
+    let update: serde_json::Value = serde_json::from_str(&next_text(&mut ticker).await).unwrap();
+    let descriptions: Vec<_> = update["data"].as_array().unwrap().iter().map(|t| t["description"].clone()).collect();
+    assert_eq!(descriptions, vec!["display controls on hover"]);
+
+    let update: serde_json::Value = serde_json::from_str(&next_text(&mut backlog).await).unwrap();
+    let descriptions: Vec<_> = update["data"].as_array().unwrap().iter().map(|t| t["description"].clone()).collect();
+    assert_eq!(descriptions, vec!["display controls on hover", "figure out frontend static asset storage/serving"]);
+
+    let reply = command(&mut ticker, serde_json::json!({"id": 2, "command": "snapshot"})).await;
+    assert_eq!(reply["data"]["tasks"].as_array().unwrap().len(), 1);
+
+    // the same tasks again change no one's slice, so only the topic arrives
+    state.set_tasks(fake_tasks()).unwrap();
+    state.set_topic(Topic::new("herp".to_string(), "derp".to_string())).unwrap();
+
+    let update: serde_json::Value = serde_json::from_str(&next_text(&mut ticker).await).unwrap();
+    assert_eq!(update["event"], "topic_updated");
+}
+
+#[actix_rt::test]
+async fn task_views() {
+    let state = web::Data::new(
+        AppState::new("secret".to_string())
+            .with_urgency_levels(UrgencyLevels { medium: 2.1, high: 10.0 })
+    );
+
+    let srv_state = state.clone();
+    let srv = test::start(move || {
+        App::new()
+            .app_data(srv_state.clone())
+            .configure(app_config)
+    });
+    let client = awc::Client::new();
+
+    let (_, mut socket) = client
+        .ws(format!("ws://{}/ws/", srv.addr()))
+        .protocols(["task-streamer"])
+        .connect()
+        .await
+        .unwrap();
+    let reply = command(&mut socket, serde_json::json!({"id": 1, "command": "subscribe", "events": ["task-views-updated"]})).await;
+    assert_eq!(reply["data"]["events"], serde_json::json!(["task-views-updated", "tasks-updated", "topic_updated"]));
+    command(&mut socket, serde_json::json!({"id": 2, "command": "unsubscribe", "events": ["tasks-updated"]})).await;
+
+    state.set_tasks(fake_tasks()).unwrap();
+
+    let update: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+    assert_eq!(update["event"], "task-views-updated");
+    assert_eq!(update["revision"], 1);
+    let levels: Vec<_> = update["data"].as_array().unwrap().iter().map(|t| t["urgency_level"].clone()).collect();
+    assert_eq!(levels, vec!["medium", "medium", "medium", "low", "low", "low", "low", "high"]);
+
+    let mut resp = client.get(format!("http://{}/api/v2/tasks/views", srv.addr())).send().await.unwrap();
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get(http::header::CACHE_CONTROL).unwrap(), "no-cache");
+
+    let envelope: Envelope<Vec<TaskView>> = resp.json().await.unwrap();
+    assert_eq!(envelope.revision, 1);
+    let view = &envelope.data[7];
+    assert_eq!(view.task.description, "display controls on hover");
+    assert_eq!(view.urgency_level, UrgencyLevel::High);
+    assert_eq!(view.project_path, vec!["twitch", "task-display"]);
+    assert!(!view.is_active);
+    assert!(!view.is_overdue);
+    assert!(view.age_seconds > 0);
+    assert_eq!(view.active_for_seconds, None);
+    assert_eq!(view.due_in_seconds, None);
+}
+
+#[actix_rt::test]
+async fn binary_encodings() {
+    let state = web::Data::new(AppState::new("Foo bar baz".to_string()));
+    let mut app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(app_config)
+    ).await;
+
+    for encoding in Encoding::ALL.iter().filter(|e| e.is_binary()) {
+        let req = test::TestRequest::post()
+            .header("content-type", encoding.mime())
+            .header("Authorization", "Bearer Foo bar baz")
+            .uri("/api/v1/tasks")
+            .set_payload(encoding.encode(&fake_tasks()).unwrap())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(state.tasks.load().value, fake_tasks());
+
+        let req = test::TestRequest::get()
+            .header("accept", encoding.mime())
+            .uri("/api/v1/tasks")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get(http::header::CONTENT_TYPE).unwrap(), encoding.mime());
+        let etag = resp.headers().get(http::header::ETAG).unwrap().to_str().unwrap().to_string();
+        assert!(etag.ends_with(&format!("-{}\"", encoding.name())));
+
+        let body = test::read_body(resp).await;
+        let tasks: Vec<Task> = encoding.decode(&body).unwrap();
+        assert_eq!(tasks, fake_tasks());
+    }
+
+    // v1 still falls back to JSON, where v2 refuses
+    let req = test::TestRequest::get()
+        .header("accept", "text/html")
+        .uri("/api/v1/topic")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    assert_eq!(resp.headers().get(http::header::CONTENT_TYPE).unwrap(), "application/json");
+
+    let req = test::TestRequest::get()
+        .header("accept", "text/html")
+        .uri("/api/v2/topic")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::NOT_ACCEPTABLE);
+
+    // v1 bodies keep the limit they had as JSON
+    let mut tasks = fake_tasks();
+    *tasks[0].description_mut() = "x".repeat(40 * 1024);
+    for (version, status) in &[("v1", http::StatusCode::PAYLOAD_TOO_LARGE), ("v2", http::StatusCode::OK)] {
+        let req = test::TestRequest::post()
+            .header("content-type", "application/json")
+            .header("Authorization", "Bearer Foo bar baz")
+            .uri(&format!("/api/{}/tasks", version))
+            .set_json(&tasks)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), *status);
+    }
+
+    let req = test::TestRequest::post()
+        .header("content-type", "text/plain")
+        .header("Authorization", "Bearer Foo bar baz")
+        .uri("/api/v1/topic")
+        .set_payload("nope")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+/// Waits for the next binary frame.
+async fn next_binary<S, E>(socket: &mut S) -> bytes::Bytes
+where
+    S: Stream<Item = Result<Frame, E>> + Unpin,
+    E: std::fmt::Debug,
+{
+    loop {
+        if let Frame::Binary(bytes) = socket.next().await.unwrap().unwrap() {
+            return bytes;
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn binary_websocket_sessions() {
+    let state = web::Data::new(AppState::new("secret".to_string()));
+    state.set_tasks(fake_tasks()).unwrap();
+
+    let srv_state = state.clone();
+    let srv = test::start(move || {
+        App::new()
+            .app_data(srv_state.clone())
+            .configure(app_config)
+    });
+    let url = format!("ws://{}/ws/", srv.addr());
+    let client = awc::Client::new();
+
+    let (resp, mut socket) = client
+        .ws(url.as_str())
+        .protocols(["task-streamer.msgpack"])
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers().get(http::header::SEC_WEBSOCKET_PROTOCOL).unwrap(), "task-streamer.msgpack");
+
+    let snapshot = Encoding::MessagePack.encode(&serde_json::json!({"id": 1, "command": "snapshot"})).unwrap();
+    socket.send(Message::Binary(snapshot.into())).await.unwrap();
+
+    let reply: serde_json::Value = Encoding::MessagePack.decode(&next_binary(&mut socket).await).unwrap();
+    assert_eq!(reply["id"], 1);
+    let tasks: Vec<Task> = serde_json::from_value(reply["data"]["tasks"].clone()).unwrap();
+    assert_eq!(tasks, fake_tasks());
+
+    state.set_topic(Topic::new("binary".to_string(), "frames".to_string())).unwrap();
+
+    let update: serde_json::Value = Encoding::MessagePack.decode(&next_binary(&mut socket).await).unwrap();
+    assert_eq!(update["event"], "topic_updated");
+    assert_eq!(update["data"]["title"], "binary");
+
+    // JSON sessions answer binary frames with an error
+    let (_, mut json) = client.ws(url.as_str()).protocols(["task-streamer"]).connect().await.unwrap();
+    json.send(Message::Binary(bytes::Bytes::from_static(b"\x81"))).await.unwrap();
+    let error: serde_json::Value = serde_json::from_str(&next_text(&mut json).await).unwrap();
+    assert_eq!(error["event"], "error");
+}
+
+#[actix_rt::test]
+async fn graphql_queries() {
+    let state = web::Data::new(
+        AppState::new("Foo bar baz".to_string()).with_redaction(stream_redaction())
+    );
+    state.set_tasks(fake_tasks()).unwrap();
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(app_config)
+    ).await;
+
+    let query = serde_json::json!({
+        "query": "{ topic { title } tasks(filter: {sort: URGENCY, limit: 2}) { description project tags urgency isActive age } }"
+    });
+
+    let req = test::TestRequest::post()
+        .header("Authorization", "Bearer Foo bar baz")
+        .uri("/graphql")
+        .set_json(&query)
+        .to_request();
+    let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert!(resp.get("errors").is_none(), "{}", resp);
+
+    let tasks = resp["data"]["tasks"].as_array().unwrap();
+    assert_eq!(tasks.len(), 2);
+    assert_eq!(tasks[0]["description"], "display controls on hover");
+    assert_eq!(tasks[0]["project"], "twitch.task-display");
+    assert_eq!(tasks[0]["urgency"], 17.0);
+    assert_eq!(tasks[0]["isActive"], false);
+    assert!(tasks[0]["age"].as_i64().unwrap() > 0);
+
+    // anonymous readers get the public view
+    let req = test::TestRequest::post()
+        .uri("/graphql")
+        .set_json(&query)
+        .to_request();
+    let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(resp["data"]["tasks"][0]["description"], "[redacted]");
+
+    // every sort the client has
+    for sort in &["ACTIVE", "PROJECT"] {
+        let query = serde_json::json!({
+            "query": format!("{{ tasks(filter: {{sort: {}}}) {{ description }} }}", sort)
+        });
+        let req = test::TestRequest::post()
+            .uri("/graphql")
+            .set_json(&query)
+            .to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert!(resp.get("errors").is_none(), "{}", resp);
+    }
+}
+
+#[actix_rt::test]
+async fn graphql_subscriptions() {
+    let state = web::Data::new(AppState::new("secret".to_string()));
+
+    let srv_state = state.clone();
+    let srv = test::start(move || {
+        App::new()
+            .app_data(srv_state.clone())
+            .configure(app_config)
+    });
+    let url = format!("ws://{}/graphql", srv.addr());
+    let client = awc::Client::new();
+
+    let (resp, mut socket) = client
+        .ws(url.as_str())
+        .protocols(["graphql-transport-ws"])
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers().get(http::header::SEC_WEBSOCKET_PROTOCOL).unwrap(), "graphql-transport-ws");
+
+    let send = |message: serde_json::Value| Message::Text(message.to_string());
+
+    socket.send(send(serde_json::json!({"type": "connection_init", "payload": {"token": "secret"}}))).await.unwrap();
+    let ack: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+    assert_eq!(ack["type"], "connection_ack");
+
+    socket.send(send(serde_json::json!({
+        "id": "tasks",
+        "type": "subscribe",
+        "payload": {"query": "subscription { tasksUpdated(filter: {projects: [\"twitch\"], limit: 1}) { description } }"}
+    }))).await.unwrap();
+    socket.send(send(serde_json::json!({
+        "id": "topic",
+        "type": "subscribe",
+        "payload": {"query": "subscription { topicUpdated { title } }"}
+    }))).await.unwrap();
+
+    // subscriptions register with the manager asynchronously
+    actix_rt::time::delay_for(std::time::Duration::from_millis(100)).await;
+    state.set_tasks(fake_tasks()).unwrap();
+    state.set_topic(Topic::new("graphql".to_string(), "".to_string())).unwrap();
+
+    let next: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+    assert_eq!(next["type"], "next");
+    assert_eq!(next["id"], "tasks");
+    assert_eq!(
+        next["payload"]["data"]["tasksUpdated"],
+        serde_json::json!([{"description": "figure out frontend static asset storage/serving"}])
+    );
+
+    let next: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+    assert_eq!(next["id"], "topic");
+    assert_eq!(next["payload"]["data"]["topicUpdated"]["title"], "graphql");
+
+    // a wrong token in `connection_init` ends the session
+    let (_, mut refused) = client
+        .ws(url.as_str())
+        .protocols(["graphql-transport-ws"])
+        .connect()
+        .await
+        .unwrap();
+    refused.send(send(serde_json::json!({"type": "connection_init", "payload": {"token": "wrong"}}))).await.unwrap();
+    loop {
+        match refused.next().await {
+            Some(Ok(Frame::Close(_))) | None => break,
+            _ => (),
+        }
     }
 }